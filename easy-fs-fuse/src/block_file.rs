@@ -0,0 +1,105 @@
+use easy_fs::{BlockDevice, BLOCK_SZ};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Block device backed by an image file on the host.
+///
+/// Blocks are read and written with positional I/O, so the device is shared
+/// between threads without a lock and without seeking.
+pub struct BlockFile(File);
+
+impl BlockFile {
+    /// Open an existing image for reading and writing
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self(file))
+    }
+    /// Create an image of `blocks` blocks at `path`, replacing any file there.
+    /// The blocks are holes of a sparse file, which read as zeros
+    pub fn create(path: impl AsRef<Path>, blocks: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let block_file = Self(file);
+        block_file.set_blocks(blocks)?;
+        Ok(block_file)
+    }
+    /// Get the number of blocks of the image, a partial last block included
+    pub fn blocks(&self) -> std::io::Result<usize> {
+        let len = self.0.metadata()?.len() as usize;
+        Ok((len + BLOCK_SZ - 1) / BLOCK_SZ)
+    }
+    /// Set the number of blocks of the image, extending it with holes or truncating it
+    pub fn set_blocks(&self, blocks: usize) -> std::io::Result<()> {
+        self.0.set_len((blocks * BLOCK_SZ) as u64)
+    }
+    /// Read a block, reading zeros past the end of an image whose
+    /// last block is partial. Reading a block past the end is an error
+    pub fn try_read_block(&self, block_id: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        check_buf_len(buf.len())?;
+        let offset = (block_id * BLOCK_SZ) as u64;
+        let mut read = 0;
+        while read < BLOCK_SZ {
+            match self.0.read_at(&mut buf[read..], offset + read as u64) {
+                Ok(0) if read == 0 => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!("block {} is past the end of the image", block_id),
+                    ))
+                }
+                Ok(0) => {
+                    buf[read..].fill(0);
+                    break;
+                }
+                Ok(len) => read += len,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+    /// Write a block, extending the image if it is past the end
+    pub fn try_write_block(&self, block_id: usize, buf: &[u8]) -> std::io::Result<()> {
+        check_buf_len(buf.len())?;
+        self.0.write_all_at(buf, (block_id * BLOCK_SZ) as u64)
+    }
+    /// Flush the written blocks to the disk of the host
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.0.sync_data()
+    }
+}
+
+/// Check that a buffer holds exactly a block
+fn check_buf_len(len: usize) -> std::io::Result<()> {
+    if len != BLOCK_SZ {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("buffer of {} bytes instead of a block", len),
+        ));
+    }
+    Ok(())
+}
+
+impl BlockDevice for BlockFile {
+    /// Read a block from file
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        if let Err(err) = self.try_read_block(block_id, buf) {
+            panic!("Error when reading block {}: {}", block_id, err);
+        }
+    }
+    /// Write a block into file
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        if let Err(err) = self.try_write_block(block_id, buf) {
+            panic!("Error when writing block {}: {}", block_id, err);
+        }
+    }
+    /// Get the number of blocks of the image
+    fn num_blocks(&self) -> Option<usize> {
+        self.blocks().ok()
+    }
+}
@@ -0,0 +1,5 @@
+//! Host side of easy-fs, shared by the easy-fs-fuse tool and tests
+
+mod block_file;
+
+pub use block_file::BlockFile;
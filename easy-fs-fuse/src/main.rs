@@ -1,40 +1,76 @@
-use clap::{App, Arg};
-use easy_fs::{BlockDevice, EasyFileSystem};
-use std::fs::{read_dir, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+mod manifest;
+mod sha256;
+#[cfg(test)]
+mod tests;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use easy_fs::{
+    BlockDevice, DiskInodeType, EasyFileSystem, Fragmentation, Inode, SuperBlock, KEY_SZ, ROOT_UID,
+};
+use easy_fs_fuse::BlockFile;
+use manifest::EntryKind;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{create_dir_all, hard_link, read_dir, File};
+use std::io::{stdin, stdout, BufRead, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::Mutex;
 
 /// Use a block size of 512 bytes
 const BLOCK_SZ: usize = 512;
 const BLOCK_NUM: usize = 131072; //64*2048
+/// Number of inodes covered by a block of inode bitmap
+const BLOCK_BITS: u32 = BLOCK_SZ as u32 * 8;
 
-/// Wrapper for turning a File into a BlockDevice
-struct BlockFile(Mutex<File>);
-
-impl BlockDevice for BlockFile {
-    /// Read a block from file
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
-        let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.read(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
-    }
-    /// Write a block into file
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
-        let mut file = self.0.lock().unwrap();
-        file.seek(SeekFrom::Start((block_id * BLOCK_SZ) as u64))
-            .expect("Error when seeking!");
-        assert_eq!(file.write(buf).unwrap(), BLOCK_SZ, "Not a complete block!");
-    }
-}
+/// Commands inspecting or editing an existing image, available both as subcommands
+/// and inside the shell: name, arguments after the image (optional ones in brackets)
+/// and description
+const COMMANDS: &[(&str, &[&str], &str)] = &[
+    ("ls", &[], "List the files of the image"),
+    ("cat", &["name"], "Print a file of the image"),
+    (
+        "put",
+        &["host", "[name]"],
+        "Copy a host file into the image",
+    ),
+    (
+        "get",
+        &["name", "[host]"],
+        "Copy a file of the image to the host",
+    ),
+    ("rm", &["name"], "Remove a file from the image"),
+    (
+        "ln",
+        &["target", "link"],
+        "Add another name for a file of the image",
+    ),
+    (
+        "stat",
+        &["name"],
+        "Show the metadata of a file of the image",
+    ),
+    (
+        "df",
+        &[],
+        "Show how many blocks and inodes of the image are in use",
+    ),
+];
 
 fn main() {
-    easy_fs_pack().expect("Error when packing easy-fs!");
-}
-
-/// Pack a directory into a easy-fs disk image
-fn easy_fs_pack() -> std::io::Result<()> {
+    let commands = COMMANDS.iter().map(|(name, args, about)| {
+        args.iter().enumerate().fold(
+            SubCommand::with_name(name)
+                .about(*about)
+                .arg(Arg::with_name("image").required(true).index(1)),
+            |sub_command, (i, arg)| {
+                sub_command.arg(
+                    Arg::with_name(arg.trim_matches(|c| c == '[' || c == ']'))
+                        .required(!arg.starts_with('['))
+                        .index(i as u64 + 2),
+                )
+            },
+        )
+    });
     let matches = App::new("EasyFileSystem packer")
         .arg(
             Arg::with_name("source")
@@ -50,106 +86,1178 @@ fn easy_fs_pack() -> std::io::Result<()> {
                 .takes_value(true)
                 .help("Executable target dir(with backslash)"),
         )
+        .arg(
+            Arg::with_name("tree")
+                .short("r")
+                .long("tree")
+                .help("Mirror the source dir tree with exact names instead of packing apps"),
+        )
+        .arg(
+            Arg::with_name("manifest")
+                .short("m")
+                .long("manifest")
+                .takes_value(true)
+                .conflicts_with_all(&["source", "tree", "sync"])
+                .help("Manifest listing the entries to create instead of a source dir"),
+        )
+        .arg(
+            Arg::with_name("sync")
+                .long("sync")
+                .help("Update an existing image, only writing the files that changed"),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .conflicts_with("sync")
+                .help("Build the image twice and check both are identical, printing its SHA-256"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .help("Path of the image, fs.img in the target dir by default"),
+        )
+        .arg(
+            Arg::with_name("blocks")
+                .short("b")
+                .long("blocks")
+                .takes_value(true)
+                .conflicts_with("size")
+                .help("Total number of blocks of the image, 131072 by default"),
+        )
+        .arg(
+            Arg::with_name("size")
+                .long("size")
+                .takes_value(true)
+                .help("Total size of the image in bytes, with an optional K, M or G suffix"),
+        )
+        .arg(
+            Arg::with_name("inodes")
+                .short("i")
+                .long("inodes")
+                .takes_value(true)
+                .help("Number of inodes of the inode area, rounded up to a multiple of 4096"),
+        )
+        .arg(
+            Arg::with_name("block-size")
+                .long("block-size")
+                .takes_value(true)
+                .help("Size of a block in bytes, easy-fs only supports 512"),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .short("c")
+                .long("compress")
+                .help("Store files compressed when that saves space"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .short("k")
+                .long("key")
+                .takes_value(true)
+                .help("File holding a 64-byte AES-256-XTS key to encrypt files with"),
+        )
+        .arg(
+            Arg::with_name("encrypt")
+                .short("e")
+                .long("encrypt")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("key")
+                .help("Name of a file to encrypt, or its path in a tree, may be repeated"),
+        )
+        .subcommand(
+            SubCommand::with_name("cp")
+                .about("Copy a file inside an existing image")
+                .arg(
+                    Arg::with_name("reflink")
+                        .long("reflink")
+                        .help("Share data blocks with the source, copying them on write"),
+                )
+                .arg(Arg::with_name("image").required(true).index(1))
+                .arg(Arg::with_name("src").required(true).index(2))
+                .arg(Arg::with_name("dst").required(true).index(3)),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Manage snapshots of an existing image")
+                .arg(
+                    Arg::with_name("action")
+                        .required(true)
+                        .index(1)
+                        .possible_values(&["create", "list", "ls", "delete", "revert"])
+                        .help("ls lists the files inside a snapshot"),
+                )
+                .arg(Arg::with_name("image").required(true).index(2))
+                .arg(Arg::with_name("name").index(3).required_ifs(&[
+                    ("action", "create"),
+                    ("action", "ls"),
+                    ("action", "delete"),
+                    ("action", "revert"),
+                ])),
+        )
+        .subcommand(
+            SubCommand::with_name("resize")
+                .about("Grow an existing image onto more blocks")
+                .arg(Arg::with_name("image").required(true).index(1))
+                .arg(
+                    Arg::with_name("blocks")
+                        .required(true)
+                        .index(2)
+                        .help("New number of blocks of the image"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Report how the files and the layout of an image differ from another one")
+                .arg(Arg::with_name("old").required(true).index(1))
+                .arg(Arg::with_name("new").required(true).index(2)),
+        )
+        .subcommand(
+            SubCommand::with_name("defrag")
+                .about("Relocate the blocks of each file of an existing image into contiguous runs")
+                .arg(Arg::with_name("image").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("unpack")
+                .about("Extract the files of an existing image into a host directory")
+                .arg(
+                    Arg::with_name("key")
+                        .short("k")
+                        .long("key")
+                        .takes_value(true)
+                        .help("File holding the key to decrypt encrypted files with"),
+                )
+                .arg(Arg::with_name("image").required(true).index(1))
+                .arg(Arg::with_name("target").required(true).index(2)),
+        )
+        .subcommands(commands)
+        .subcommand(
+            SubCommand::with_name("shell")
+                .about("Run the commands inspecting or editing an existing image interactively")
+                .arg(Arg::with_name("image").required(true).index(1)),
+        )
         .get_matches();
-    let src_path = matches.value_of("source").unwrap();
-    let target_path = matches.value_of("target").unwrap();
-    println!("src_path = {}\ntarget_path = {}", src_path, target_path);
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(format!("{}{}", target_path, "fs.img"))?;
-        f.set_len((BLOCK_NUM * BLOCK_SZ) as u64).unwrap();
-        f
-    })));
-    let efs = EasyFileSystem::create(block_file.clone(), BLOCK_NUM as u32, 1);
-    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
-    let apps: Vec<_> = read_dir(src_path)
+    match matches.subcommand() {
+        ("cp", Some(sub_matches)) => easy_fs_cp(sub_matches).expect("Error when copying file!"),
+        ("snapshot", Some(sub_matches)) => {
+            easy_fs_snapshot(sub_matches).expect("Error when managing snapshots!")
+        }
+        ("resize", Some(sub_matches)) => {
+            easy_fs_resize(sub_matches).expect("Error when resizing easy-fs!")
+        }
+        ("diff", Some(sub_matches)) => {
+            easy_fs_diff(sub_matches).expect("Error when comparing easy-fs images!")
+        }
+        ("defrag", Some(sub_matches)) => {
+            easy_fs_defrag(sub_matches).expect("Error when defragmenting easy-fs!")
+        }
+        ("unpack", Some(sub_matches)) => {
+            easy_fs_unpack(sub_matches).expect("Error when unpacking easy-fs!")
+        }
+        ("shell", Some(sub_matches)) => {
+            easy_fs_shell(sub_matches).expect("Error when running the shell!")
+        }
+        (name, Some(sub_matches)) => easy_fs_command(name, sub_matches).unwrap_or_else(|err| {
+            eprintln!("{}: {}", name, err);
+            std::process::exit(1)
+        }),
+        _ => easy_fs_pack(&matches).expect("Error when packing easy-fs!"),
+    }
+}
+
+/// Open an existing easy-fs disk image
+fn open_image(image_path: &str) -> std::io::Result<Arc<BlockFile>> {
+    Ok(Arc::new(BlockFile::open(image_path)?))
+}
+
+/// Copy a file inside an existing easy-fs disk image
+fn easy_fs_cp(matches: &ArgMatches) -> std::io::Result<()> {
+    let src_name = matches.value_of("src").unwrap();
+    let dst_name = matches.value_of("dst").unwrap();
+    let efs = EasyFileSystem::open(open_image(matches.value_of("image").unwrap())?);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let src = root_inode
+        .find(src_name)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, src_name))?;
+    let dst = match root_inode.find(dst_name) {
+        Some(dst) => dst,
+        None => root_inode
+            .create(dst_name)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "cannot create file"))?,
+    };
+    if dst.inode_type() != DiskInodeType::File {
+        return Err(Error::new(ErrorKind::InvalidInput, "not a regular file"));
+    }
+    if matches.is_present("reflink") {
+        Inode::clone_from(&dst, &src)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "cannot reflink"))?;
+    } else {
+        dst.clear();
+        let mut buffer = [0u8; BLOCK_SZ];
+        let mut offset = 0usize;
+        loop {
+            let len = src.read_at(offset, &mut buffer);
+            if len == 0 {
+                break;
+            }
+            if dst.write_at(offset, &buffer[..len]) < len {
+                return Err(Error::new(ErrorKind::Other, "no space left"));
+            }
+            offset += len;
+        }
+    }
+    Ok(())
+}
+
+/// Create, list, inspect, delete or revert to snapshots of an existing easy-fs disk image
+fn easy_fs_snapshot(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = EasyFileSystem::open(open_image(matches.value_of("image").unwrap())?);
+    let name = matches.value_of("name").unwrap_or_default();
+    let not_found = || Error::new(ErrorKind::NotFound, name);
+    match matches.value_of("action").unwrap() {
+        "create" => EasyFileSystem::create_snapshot(&efs, name)
+            .ok_or_else(|| Error::new(ErrorKind::AlreadyExists, name))?,
+        "list" => {
+            for snapshot in efs.lock().list_snapshots() {
+                println!("{}", snapshot);
+            }
+        }
+        "ls" => {
+            let snapshot_root = EasyFileSystem::snapshot_root(&efs, name).ok_or_else(not_found)?;
+            for file in snapshot_root.ls() {
+                println!("{}", file);
+            }
+        }
+        "delete" => efs.lock().delete_snapshot(name).ok_or_else(not_found)?,
+        "revert" => EasyFileSystem::revert_to_snapshot(&efs, name).ok_or_else(not_found)?,
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Grow an existing easy-fs disk image, extending the image file as needed
+fn easy_fs_resize(matches: &ArgMatches) -> std::io::Result<()> {
+    let blocks: u32 = matches
+        .value_of("blocks")
         .unwrap()
-        .into_iter()
-        .map(|dir_entry| {
-            let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
-            name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
-            name_with_ext
-        })
+        .parse()
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid number of blocks"))?;
+    let block_file = open_image(matches.value_of("image").unwrap())?;
+    if block_file.blocks()? < blocks as usize {
+        block_file.set_blocks(blocks as usize)?;
+    }
+    let efs = EasyFileSystem::open(block_file);
+    let mut fs = efs.lock();
+    let old_blocks = fs.total_blocks();
+    fs.grow(blocks)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "cannot grow to that size"))?;
+    println!("grown from {} to {} blocks", old_blocks, fs.total_blocks());
+    Ok(())
+}
+
+/// Compare two existing easy-fs disk images
+fn easy_fs_diff(matches: &ArgMatches) -> std::io::Result<()> {
+    let old_file = open_image(matches.value_of("old").unwrap())?;
+    let new_file = open_image(matches.value_of("new").unwrap())?;
+    diff_images(&old_file, &new_file, &mut stdout())
+}
+
+/// Report to `out` the differences of the super blocks and bitmaps of two images,
+/// then the files added (+), removed (-), modified (M) or of another type (T)
+fn diff_images(
+    old_file: &Arc<BlockFile>,
+    new_file: &Arc<BlockFile>,
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    let old_efs = EasyFileSystem::open(old_file.clone());
+    let new_efs = EasyFileSystem::open(new_file.clone());
+    let (old_super, new_super) = (old_efs.lock().super_block(), new_efs.lock().super_block());
+    let fields = |super_block: &SuperBlock| {
+        [
+            ("total_blocks", super_block.total_blocks),
+            ("inode_bitmap_blocks", super_block.inode_bitmap_blocks),
+            ("inode_area_blocks", super_block.inode_area_blocks),
+            ("data_bitmap_blocks", super_block.data_bitmap_blocks),
+            ("data_area_blocks", super_block.data_area_blocks),
+            ("data_regions", super_block.data_regions().len() as u32),
+        ]
+    };
+    let mut same_layout = true;
+    for ((name, old), (_, new)) in fields(&old_super).iter().zip(fields(&new_super).iter()) {
+        if old != new {
+            writeln!(out, "superblock {}: {} -> {}", name, old, new)?;
+            same_layout = false;
+        }
+    }
+    let (old_usage, new_usage) = (old_efs.lock().usage(), new_efs.lock().usage());
+    writeln!(
+        out,
+        "data blocks used: {} -> {}, inodes used: {} -> {}",
+        old_usage.data_blocks - old_usage.free_blocks,
+        new_usage.data_blocks - new_usage.free_blocks,
+        old_usage.used_inodes,
+        new_usage.used_inodes
+    )?;
+    if same_layout {
+        // bitmaps are compared bit by bit when they are at the same place
+        let inode_bitmap = (1, old_super.inode_bitmap_blocks);
+        let data_bitmaps = old_super
+            .data_regions()
+            .iter()
+            .map(|region| (region.bitmap_start, region.bitmap_blocks))
+            .collect::<Vec<_>>();
+        for (what, bitmaps) in [("inode", vec![inode_bitmap]), ("data block", data_bitmaps)] {
+            let (allocated, freed) = diff_bitmaps(old_file, new_file, &bitmaps);
+            writeln!(
+                out,
+                "{} bitmap: {} allocated, {} freed",
+                what, allocated, freed
+            )?;
+        }
+    }
+    let mut old_files = BTreeMap::new();
+    let mut new_files = BTreeMap::new();
+    collect_files(&EasyFileSystem::root_inode(&old_efs), "", &mut old_files);
+    collect_files(&EasyFileSystem::root_inode(&new_efs), "", &mut new_files);
+    for (path, old) in old_files.iter() {
+        if !new_files.contains_key(path) {
+            writeln!(out, "- {} ({} bytes)", path, old.stat().size)?;
+        }
+    }
+    for (path, new) in new_files.iter() {
+        let new_stat = new.stat();
+        let old = match old_files.get(path) {
+            Some(old) => old,
+            None => {
+                writeln!(out, "+ {} ({} bytes)", path, new_stat.size)?;
+                continue;
+            }
+        };
+        let old_stat = old.stat();
+        if old_stat.inode_type != new_stat.inode_type {
+            writeln!(
+                out,
+                "T {}: {:?} -> {:?}",
+                path, old_stat.inode_type, new_stat.inode_type
+            )?;
+            continue;
+        }
+        let mut changes = Vec::new();
+        if old_stat.size != new_stat.size {
+            changes.push(format!("size {} -> {}", old_stat.size, new_stat.size));
+        }
+        if old_stat.uid != new_stat.uid {
+            changes.push(format!("owner {} -> {}", old_stat.uid, new_stat.uid));
+        }
+        if old_stat.device != new_stat.device {
+            changes.push(format!(
+                "device {:?} -> {:?}",
+                old_stat.device, new_stat.device
+            ));
+        }
+        if new_stat.inode_type == DiskInodeType::File {
+            let (mut old_data, mut new_data) = (Vec::new(), Vec::new());
+            copy_out(old, &mut old_data)?;
+            copy_out(new, &mut new_data)?;
+            let ranges = diff_block_ranges(&old_data, &new_data);
+            if !ranges.is_empty() {
+                let ranges: Vec<String> = ranges
+                    .iter()
+                    .map(|&(start, end)| match end - start {
+                        1 => format!("{}", start),
+                        _ => format!("{}-{}", start, end - 1),
+                    })
+                    .collect();
+                changes.push(format!("blocks {}", ranges.join(", ")));
+            }
+        }
+        if !changes.is_empty() {
+            writeln!(out, "M {}: {}", path, changes.join(", "))?;
+        }
+    }
+    Ok(())
+}
+
+/// Count the bits set in the bitmaps of the new image only and of the old one only,
+/// each bitmap is given by its first block and number of blocks
+fn diff_bitmaps(
+    old_file: &Arc<BlockFile>,
+    new_file: &Arc<BlockFile>,
+    bitmaps: &[(u32, u32)],
+) -> (u32, u32) {
+    let (mut allocated, mut freed) = (0, 0);
+    let (mut old_block, mut new_block) = ([0u8; BLOCK_SZ], [0u8; BLOCK_SZ]);
+    for &(start, blocks) in bitmaps {
+        for block_id in start..start + blocks {
+            old_file.read_block(block_id as usize, &mut old_block);
+            new_file.read_block(block_id as usize, &mut new_block);
+            for (old, new) in old_block.iter().zip(new_block.iter()) {
+                allocated += (new & !old).count_ones();
+                freed += (old & !new).count_ones();
+            }
+        }
+    }
+    (allocated, freed)
+}
+
+/// Collect the entries below an easy-fs directory by their path
+fn collect_files(dir: &Inode, prefix: &str, files: &mut BTreeMap<String, Arc<Inode>>) {
+    let mut offset = 0;
+    while let Some((entry, next_offset)) = dir.readdir(offset) {
+        offset = next_offset;
+        let path = format!("{}{}", prefix, entry.name);
+        let inode = dir.find(&entry.name).unwrap();
+        if entry.inode_type == DiskInodeType::Directory {
+            collect_files(&inode, &format!("{}/", path), files);
+        }
+        files.insert(path, inode);
+    }
+}
+
+/// Get the ranges `start..end` of the blocks of two contents that differ,
+/// blocks beyond the end of the shorter one differ
+fn diff_block_ranges(old: &[u8], new: &[u8]) -> Vec<(usize, usize)> {
+    let blocks = (old.len().max(new.len()) + BLOCK_SZ - 1) / BLOCK_SZ;
+    let block_of = |data: &[u8], i: usize| {
+        let end = data.len().min((i + 1) * BLOCK_SZ);
+        data.get(i * BLOCK_SZ..end).unwrap_or(&[]).to_vec()
+    };
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for i in (0..blocks).filter(|&i| block_of(old, i) != block_of(new, i)) {
+        match ranges.last_mut() {
+            Some(range) if range.1 == i => range.1 = i + 1,
+            _ => ranges.push((i, i + 1)),
+        }
+    }
+    ranges
+}
+
+/// Defragment an existing easy-fs disk image, reporting fragmentation before and after
+fn easy_fs_defrag(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = EasyFileSystem::open(open_image(matches.value_of("image").unwrap())?);
+    let report = |when: &str, fragmentation: Fragmentation| {
+        println!(
+            "{}: {}% fragmented, {} extents over {} blocks of {} files",
+            when,
+            fragmentation.score(),
+            fragmentation.extents,
+            fragmentation.blocks,
+            fragmentation.files
+        )
+    };
+    report("before", efs.lock().fragmentation());
+    let relocated = EasyFileSystem::defragment(&efs);
+    report("after", efs.lock().fragmentation());
+    println!("{} files relocated", relocated);
+    Ok(())
+}
+
+/// Run one of `COMMANDS` on an existing easy-fs disk image
+fn easy_fs_command(name: &str, matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = EasyFileSystem::open(open_image(matches.value_of("image").unwrap())?);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let (_, arg_names, _) = COMMANDS.iter().find(|command| command.0 == name).unwrap();
+    let args: Vec<&str> = arg_names
+        .iter()
+        .filter_map(|arg| matches.value_of(arg.trim_matches(|c| c == '[' || c == ']')))
         .collect();
-    for app in apps {
-        // load app data (elf) from host file system
-        let mut host_file = File::open(format!("{}{}", target_path, app)).unwrap();
-        let mut all_data: Vec<u8> = Vec::new();
-        host_file.read_to_end(&mut all_data).unwrap();
-        // create a file in easy-fs
-        let inode = root_inode.create(app.as_str()).unwrap();
-        // write data to easy-fs
-        inode.write_at(0, all_data.as_slice());
+    run_command(&root_inode, name, &args, &mut stdout())
+}
+
+/// Read commands from stdin and run them on an existing easy-fs disk image until
+/// `exit` or the end of input, reporting failed commands without stopping
+fn easy_fs_shell(matches: &ArgMatches) -> std::io::Result<()> {
+    let image_path = matches.value_of("image").unwrap();
+    let efs = EasyFileSystem::open(open_image(image_path)?);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut lines = stdin().lock().lines();
+    loop {
+        print!("{}> ", image_path);
+        stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.split_first() {
+            None => continue,
+            Some((&"exit", _)) | Some((&"quit", _)) => break,
+            Some((&"help", _)) => {
+                for (name, args, about) in COMMANDS {
+                    println!("{:<24}{}", format!("{} {}", name, args.join(" ")), about);
+                }
+                println!("{:<24}Leave the shell", "exit");
+            }
+            Some((name, args)) => {
+                if let Err(err) = run_command(&root_inode, name, args, &mut stdout()) {
+                    println!("{}: {}", name, err);
+                }
+            }
+        }
+    }
+    println!();
+    Ok(())
+}
+
+/// Run the command `name` of `COMMANDS` with its arguments, printing to `out`
+fn run_command(
+    root_inode: &Inode,
+    name: &str,
+    args: &[&str],
+    out: &mut dyn Write,
+) -> std::io::Result<()> {
+    let (_, arg_names, _) = COMMANDS
+        .iter()
+        .find(|command| command.0 == name)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "unknown command, try help"))?;
+    let required = arg_names.iter().filter(|arg| !arg.starts_with('[')).count();
+    if args.len() < required || args.len() > arg_names.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("usage: {} {}", name, arg_names.join(" ")),
+        ));
+    }
+    let find = |name: &str| {
+        root_inode
+            .find(name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} not found", name)))
+    };
+    match name {
+        "ls" => {
+            for name in root_inode.ls() {
+                writeln!(out, "{}", name)?;
+            }
+        }
+        "cat" => {
+            copy_out(&*find(args[0])?, out)?;
+        }
+        "put" => {
+            let host_path = args[0];
+            let name = match args.get(1) {
+                Some(name) => *name,
+                None => host_path.rsplit('/').next().unwrap(),
+            };
+            let mut data = Vec::new();
+            File::open(host_path)?.read_to_end(&mut data)?;
+            let inode = match root_inode.find(name) {
+                Some(inode) => inode,
+                None => root_inode
+                    .create(name)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "cannot create file"))?,
+            };
+            if inode.inode_type() != DiskInodeType::File {
+                return Err(Error::new(ErrorKind::InvalidInput, "not a regular file"));
+            }
+            inode.clear();
+            if inode.write_at(0, &data) < data.len() {
+                return Err(Error::new(ErrorKind::Other, "no space left"));
+            }
+        }
+        "get" => {
+            let inode = find(args[0])?;
+            copy_out(&inode, &mut File::create(args.get(1).unwrap_or(&args[0]))?)?;
+        }
+        "rm" => {
+            find(args[0])?;
+            root_inode
+                .unlink(args[0])
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "cannot remove"))?;
+        }
+        "ln" => {
+            if root_inode.find(args[1]).is_some() {
+                return Err(Error::new(ErrorKind::AlreadyExists, args[1]));
+            }
+            find(args[0])?;
+            root_inode
+                .link(args[1], args[0])
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "cannot link"))?;
+        }
+        "stat" => {
+            let stat = find(args[0])?.stat();
+            writeln!(out, "name: {}", args[0])?;
+            writeln!(out, "inode: {}", stat.inode_id)?;
+            writeln!(out, "type: {:?}", stat.inode_type)?;
+            writeln!(out, "size: {}", stat.size)?;
+            writeln!(out, "blocks: {}", stat.blocks)?;
+            writeln!(out, "links: {}", stat.nlink)?;
+            writeln!(out, "uid: {}", stat.uid)?;
+            if let Some((major, minor)) = stat.device {
+                writeln!(out, "device: {},{}", major, minor)?;
+            }
+        }
+        "df" => {
+            let usage = root_inode.fs().lock().usage();
+            writeln!(
+                out,
+                "blocks: {} total, {} for data, {} used, {} free",
+                usage.total_blocks,
+                usage.data_blocks,
+                usage.data_blocks - usage.free_blocks,
+                usage.free_blocks
+            )?;
+            writeln!(
+                out,
+                "inodes: {} used, {} in the inode area",
+                usage.used_inodes, usage.area_inodes
+            )?;
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Write the data of an easy-fs file to `out`
+fn copy_out(inode: &Inode, out: &mut dyn Write) -> std::io::Result<()> {
+    let mut buffer = [0u8; BLOCK_SZ];
+    let mut offset = 0usize;
+    loop {
+        let len = inode.read_at(offset, &mut buffer);
+        if len == 0 {
+            return Ok(());
+        }
+        out.write_all(&buffer[..len])?;
+        offset += len;
+    }
+}
+
+/// Extract the files of an existing easy-fs disk image into a host directory
+fn easy_fs_unpack(matches: &ArgMatches) -> std::io::Result<()> {
+    let efs = EasyFileSystem::open(open_image(matches.value_of("image").unwrap())?);
+    if let Some(key_path) = matches.value_of("key") {
+        efs.lock()
+            .add_key(&read_key(key_path)?, ROOT_UID)
+            .ok_or_else(|| Error::new(ErrorKind::AlreadyExists, "key id in use"))?;
+    }
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut unpacked = BTreeMap::new();
+    unpack_dir(
+        &root_inode,
+        Path::new(matches.value_of("target").unwrap()),
+        &mut unpacked,
+    )?;
+    println!("{} files unpacked", unpacked.len());
+    Ok(())
+}
+
+/// Extract the entries of an easy-fs directory into `host_dir`, recursing into subdirectories.
+/// `unpacked` maps the inodes extracted so far to their host paths, further names
+/// of a file become hard links to its first one
+fn unpack_dir(
+    dir: &Inode,
+    host_dir: &Path,
+    unpacked: &mut BTreeMap<u32, PathBuf>,
+) -> std::io::Result<()> {
+    create_dir_all(host_dir)?;
+    let mut offset = 0;
+    while let Some((entry, next_offset)) = dir.readdir(offset) {
+        offset = next_offset;
+        let host_path = host_dir.join(&entry.name);
+        if let Some(first_path) = unpacked.get(&entry.inode_id) {
+            if entry.inode_type == DiskInodeType::File {
+                hard_link(first_path, &host_path)?;
+            } else {
+                println!("skip {}: already unpacked", host_path.display());
+            }
+            continue;
+        }
+        let inode = dir.find(&entry.name).unwrap();
+        match entry.inode_type {
+            DiskInodeType::File => copy_out(&inode, &mut File::create(&host_path)?)?,
+            DiskInodeType::Directory => {
+                unpacked.insert(entry.inode_id, host_path.clone());
+                unpack_dir(&inode, &host_path, unpacked)?;
+            }
+            inode_type => {
+                println!(
+                    "skip {}: {:?} is not supported",
+                    host_path.display(),
+                    inode_type
+                );
+                continue;
+            }
+        }
+        for name in inode.list_xattr() {
+            let value = inode.get_xattr(&name).unwrap();
+            // the host filesystem may not support xattrs at all
+            if xattr::set(&host_path, &name, &value).is_err() {
+                println!("skip xattr {} of {}", name, host_path.display());
+            }
+        }
+        unpacked.insert(entry.inode_id, host_path);
     }
+    Ok(())
+}
+
+/// Pack a directory into a easy-fs disk image
+fn easy_fs_pack(matches: &ArgMatches) -> std::io::Result<()> {
+    let src_path = matches.value_of("source").unwrap_or_default();
+    // the apps of the source dir are loaded from the target dir, a tree is loaded as is
+    let target_path = matches.value_of("target").unwrap_or(src_path);
+    let image_path = match matches.value_of("output") {
+        Some(image_path) => image_path.to_string(),
+        None => format!("{}{}", target_path, "fs.img"),
+    };
+    println!(
+        "src_path = {}\ntarget_path = {}\nimage_path = {}",
+        src_path, target_path, image_path
+    );
+    pack_image(matches, &image_path, matches.is_present("sync"))?;
+    if matches.is_present("verify") {
+        let rebuilt_path = format!("{}.verify", image_path);
+        pack_image(matches, &rebuilt_path, false)?;
+        let digest = sha256::digest(File::open(&image_path)?)?;
+        let rebuilt_digest = sha256::digest(File::open(&rebuilt_path)?)?;
+        std::fs::remove_file(&rebuilt_path)?;
+        if digest != rebuilt_digest {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("rebuilt image differs: {} != {}", digest, rebuilt_digest),
+            ));
+        }
+        println!("{}  {}", digest, image_path);
+    }
+    Ok(())
+}
+
+/// Pack the image at `image_path` from the source given by the options, updating
+/// the image if `sync` is set and it exists. A new image only depends on the options
+/// and the contents of the source, entries are created in the order of their names
+fn pack_image(matches: &ArgMatches, image_path: &str, sync: bool) -> std::io::Result<()> {
+    let src_path = matches.value_of("source").unwrap_or_default();
+    let target_path = matches.value_of("target").unwrap_or(src_path);
+    let (total_blocks, inode_bitmap_blocks) = image_geometry(matches)?;
+    let block_file = Arc::new(match BlockFile::open(image_path) {
+        Ok(block_file) => block_file,
+        Err(err) if err.kind() == ErrorKind::NotFound => BlockFile::create(image_path, 0)?,
+        Err(err) => return Err(err),
+    });
+    let block_device: Arc<dyn BlockDevice> = block_file.clone();
+    let efs = if sync && block_file.blocks()? > 0 && EasyFileSystem::is_formatted(&block_device) {
+        println!("updating the existing image, its geometry is kept");
+        EasyFileSystem::open(block_file)
+    } else {
+        block_file.set_blocks(total_blocks as usize)?;
+        EasyFileSystem::create(block_file, total_blocks, inode_bitmap_blocks)
+    };
+    println!("{:#?}", efs.lock().super_block());
+    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+    let options = PackOptions {
+        key_id: match matches.value_of("key") {
+            Some(key_path) => Some(
+                efs.lock()
+                    .add_key(&read_key(key_path)?, ROOT_UID)
+                    .ok_or_else(|| Error::new(ErrorKind::AlreadyExists, "key id in use"))?,
+            ),
+            None => None,
+        },
+        encrypted: matches.values_of("encrypt").into_iter().flatten().collect(),
+        compress: matches.is_present("compress"),
+    };
+    let mut stats = PackStats::default();
+    if let Some(manifest_path) = matches.value_of("manifest") {
+        pack_manifest(&root_inode, Path::new(manifest_path), &options, &mut stats)?;
+    } else if matches.is_present("tree") {
+        pack_tree(&root_inode, Path::new(src_path), "", &options, &mut stats)?;
+    } else {
+        let mut apps: Vec<_> = read_dir(src_path)
+            .unwrap()
+            .into_iter()
+            .map(|dir_entry| {
+                let mut name_with_ext = dir_entry.unwrap().file_name().into_string().unwrap();
+                name_with_ext.drain(name_with_ext.find('.').unwrap()..name_with_ext.len());
+                name_with_ext
+            })
+            .collect();
+        apps.sort();
+        for app in apps.iter() {
+            // load app data (elf) from host file system
+            let host_path = format!("{}{}", target_path, app);
+            let key_id = options
+                .key_id
+                .filter(|_| options.encrypted.contains(&app.as_str()));
+            pack_file(
+                &root_inode,
+                app,
+                Path::new(&host_path),
+                key_id,
+                ROOT_UID,
+                &options,
+                &mut stats,
+            )?;
+        }
+        remove_stale(&root_inode, &apps, &mut stats);
+    }
+    efs.lock().sync();
     // list apps
     for app in root_inode.ls() {
         println!("{}", app);
     }
+    println!(
+        "{} written, {} unchanged, {} removed",
+        stats.written, stats.unchanged, stats.removed
+    );
     Ok(())
 }
 
-#[test]
-fn efs_test() -> std::io::Result<()> {
-    let block_file = Arc::new(BlockFile(Mutex::new({
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open("target/fs.img")?;
-        f.set_len(BLOCK_NUM * BLOCK_SZ).unwrap();
-        f
-    })));
-    EasyFileSystem::create(block_file.clone(), 4096, 1);
-    let efs = EasyFileSystem::open(block_file.clone());
-    let root_inode = EasyFileSystem::root_inode(&efs);
-    root_inode.create("filea");
-    root_inode.create("fileb");
-    for name in root_inode.ls() {
-        println!("{}", name);
-    }
-    let filea = root_inode.find("filea").unwrap();
-    let greet_str = "Hello, world!";
-    filea.write_at(0, greet_str.as_bytes());
-    //let mut buffer = [0u8; BLOCK_SZ];
-    let mut buffer = [0u8; 233];
-    let len = filea.read_at(0, &mut buffer);
-    assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap(),);
-
-    let mut random_str_test = |len: usize| {
-        filea.clear();
-        assert_eq!(filea.read_at(0, &mut buffer), 0,);
-        let mut str = String::new();
-        use rand;
-        // random digit
-        for _ in 0..len {
-            str.push(char::from('0' as u8 + rand::random::<u8>() % 10));
-        }
-        filea.write_at(0, str.as_bytes());
-        let mut read_buffer = [0u8; 127];
-        let mut offset = 0usize;
-        let mut read_str = String::new();
-        loop {
-            let len = filea.read_at(offset, &mut read_buffer);
-            if len == 0 {
-                break;
+/// How files are stored by packing
+struct PackOptions<'a> {
+    /// Key to encrypt the files and directories listed in `encrypted` with
+    key_id: Option<u32>,
+    /// Names of the files to encrypt, or their paths in a tree
+    encrypted: Vec<&'a str>,
+    compress: bool,
+}
+
+/// Number of entries of an image packing has written, kept as they were and removed
+#[derive(Default)]
+struct PackStats {
+    written: u32,
+    unchanged: u32,
+    removed: u32,
+}
+
+/// Mirror the host directory `host_dir` into the easy-fs directory `dir` recursively,
+/// keeping exact names. `rel_path` is the path of `dir` inside the image.
+/// Entries of `dir` which are not on the host are removed
+fn pack_tree(
+    dir: &Inode,
+    host_dir: &Path,
+    rel_path: &str,
+    options: &PackOptions,
+    stats: &mut PackStats,
+) -> std::io::Result<()> {
+    let mut entries = read_dir(host_dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut names = Vec::new();
+    for entry in entries {
+        let host_path = entry.path();
+        let name = entry.file_name().into_string().map_err(|name| {
+            Error::new(ErrorKind::InvalidData, format!("{:?} is not UTF-8", name))
+        })?;
+        let path = if rel_path.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", rel_path, name)
+        };
+        let key_id = options
+            .key_id
+            .filter(|_| options.encrypted.contains(&path.as_str()));
+        // symbolic links are followed
+        let metadata = std::fs::metadata(&host_path)?;
+        if metadata.is_dir() {
+            let sub_dir = match dir.find(&name) {
+                Some(inode)
+                    if inode.is_dir() && (key_id.is_none() || inode.encryption_key() == key_id) =>
+                {
+                    inode
+                }
+                existing => {
+                    if existing.is_some() {
+                        remove_entry(dir, &name);
+                    }
+                    let sub_dir = dir
+                        .mkdir(&name)
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, path.clone()))?;
+                    // entries of an encrypted directory are encrypted already
+                    if let Some(key_id) = key_id.filter(|_| sub_dir.encryption_key().is_none()) {
+                        sub_dir.set_encryption(key_id).unwrap();
+                    }
+                    sub_dir
+                }
+            };
+            copy_xattrs(&host_path, &sub_dir)?;
+            pack_tree(&sub_dir, &host_path, &path, options, stats)?;
+        } else if metadata.is_file() {
+            pack_file(dir, &name, &host_path, key_id, ROOT_UID, options, stats)?;
+        } else {
+            println!(
+                "skip {}: not a regular file or directory",
+                host_path.display()
+            );
+            continue;
+        }
+        names.push(name);
+    }
+    remove_stale(dir, &names, stats);
+    Ok(())
+}
+
+/// Create the entries listed by a manifest, host files are found relative to it
+fn pack_manifest(
+    root_inode: &Arc<Inode>,
+    manifest_path: &Path,
+    options: &PackOptions,
+    stats: &mut PackStats,
+) -> std::io::Result<()> {
+    let with_path =
+        |err: Error| Error::new(err.kind(), format!("{}: {}", manifest_path.display(), err));
+    let entries = manifest::parse(&std::fs::read_to_string(manifest_path)?).map_err(with_path)?;
+    let base = manifest_path.parent().unwrap_or_else(|| Path::new(""));
+    for entry in entries {
+        let invalid = |message: &str| {
+            with_path(Error::new(
+                ErrorKind::InvalidInput,
+                format!("line {}: {}", entry.line, message),
+            ))
+        };
+        let path = entry.path.trim_matches('/');
+        let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let dir = make_dirs(root_inode, parent_path)
+            .ok_or_else(|| invalid("cannot create the parent directories"))?;
+        let uid = entry.owner.unwrap_or(ROOT_UID);
+        if entry.mode.is_some() {
+            println!("skip mode of {}: easy-fs has no modes", path);
+        }
+        let existing = dir.find(name);
+        if existing.is_some() && entry.kind != EntryKind::Dir {
+            return Err(invalid("already exists"));
+        }
+        let inode_type = match entry.kind {
+            EntryKind::File => {
+                let key_id = options.key_id.filter(|_| options.encrypted.contains(&path));
+                let host_path = base.join(entry.source.unwrap());
+                pack_file(&dir, name, &host_path, key_id, uid, options, stats)?;
+                continue;
             }
-            offset += len;
-            read_str.push_str(core::str::from_utf8(&read_buffer[..len]).unwrap());
+            EntryKind::Dir => {
+                match existing {
+                    // a directory created for earlier entries is owned by root
+                    Some(inode) if inode.is_dir() && inode.owner() == uid => {}
+                    Some(_) => {
+                        return Err(invalid(
+                            "already exists, list directories before their entries",
+                        ))
+                    }
+                    None => {
+                        dir.mkdir_as(name, uid)
+                            .ok_or_else(|| invalid("cannot create"))?;
+                    }
+                }
+                stats.written += 1;
+                continue;
+            }
+            EntryKind::HardLink => {
+                let target = find_path(root_inode, entry.target.as_deref().unwrap())
+                    .ok_or_else(|| invalid("target not found"))?;
+                dir.link_inode(name, &target)
+                    .ok_or_else(|| invalid("cannot link to the target"))?;
+                stats.written += 1;
+                continue;
+            }
+            EntryKind::Symlink => return Err(invalid("easy-fs has no symbolic links")),
+            EntryKind::Fifo => DiskInodeType::Fifo,
+            EntryKind::CharDevice => DiskInodeType::CharDevice,
+            EntryKind::BlockDevice => DiskInodeType::BlockDevice,
+        };
+        let (major, minor) = entry.device;
+        dir.mknod_as(name, uid, inode_type, major, minor)
+            .ok_or_else(|| invalid("cannot create"))?;
+        stats.written += 1;
+    }
+    Ok(())
+}
+
+/// Find an entry of an image by its path from the root directory
+fn find_path(root_inode: &Arc<Inode>, path: &str) -> Option<Arc<Inode>> {
+    path.split('/')
+        .filter(|name| !name.is_empty())
+        .try_fold(root_inode.clone(), |dir, name| dir.find(name))
+}
+
+/// Get the directory of an image at a path from the root directory,
+/// creating the missing directories on the way
+fn make_dirs(root_inode: &Arc<Inode>, path: &str) -> Option<Arc<Inode>> {
+    path.split('/')
+        .filter(|name| !name.is_empty())
+        .try_fold(root_inode.clone(), |dir, name| match dir.find(name) {
+            Some(inode) if inode.is_dir() => Some(inode),
+            Some(_) => None,
+            None => dir.mkdir(name),
+        })
+}
+
+/// Make the file `name` in the easy-fs directory `dir` hold the data and extended
+/// attributes of a host file, owned by `uid` and encrypted with the key `key_id` if any.
+/// A file already holding the same data in the same way is left as it is,
+/// one holding other data without other links is rewritten in place
+fn pack_file(
+    dir: &Inode,
+    name: &str,
+    host_path: &Path,
+    key_id: Option<u32>,
+    uid: u32,
+    options: &PackOptions,
+    stats: &mut PackStats,
+) -> std::io::Result<()> {
+    let mut all_data: Vec<u8> = Vec::new();
+    File::open(host_path)?.read_to_end(&mut all_data)?;
+    let mut inode = None;
+    if let Some(old) = dir.find(name) {
+        let stat = old.stat();
+        if old.inode_type() == DiskInodeType::File
+            && old.owner() == uid
+            && (key_id.is_none() || old.encryption_key() == key_id)
+        {
+            if stat.size as usize == all_data.len() {
+                let mut data = Vec::new();
+                copy_out(&old, &mut data)?;
+                if data == all_data {
+                    if options.compress && !old.is_compressed() {
+                        old.compress();
+                    }
+                    copy_xattrs(host_path, &old)?;
+                    stats.unchanged += 1;
+                    return Ok(());
+                }
+            }
+            if stat.nlink == 1 {
+                old.clear();
+                for xattr in old.list_xattr() {
+                    old.remove_xattr(&xattr);
+                }
+                inode = Some(old);
+            }
+        }
+        if inode.is_none() {
+            remove_entry(dir, name);
+        }
+    }
+    let inode = match inode {
+        Some(inode) => inode,
+        // create a file in easy-fs
+        None => {
+            let (_, inode) = dir.create_inode_as(name, uid).ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, format!("cannot create {}", name))
+            })?;
+            if let Some(key_id) = key_id.filter(|_| inode.encryption_key().is_none()) {
+                inode.set_encryption(key_id).unwrap();
+            }
+            inode
         }
-        assert_eq!(str, read_str);
     };
+    // write data to easy-fs
+    if inode.write_at(0, all_data.as_slice()) < all_data.len() {
+        return Err(Error::new(ErrorKind::Other, "no space left"));
+    }
+    if options.compress {
+        inode.compress();
+    }
+    copy_xattrs(host_path, &inode)?;
+    stats.written += 1;
+    Ok(())
+}
+
+/// Remove the entries of the easy-fs directory `dir` whose name is not in `names`
+fn remove_stale(dir: &Inode, names: &[String], stats: &mut PackStats) {
+    for name in dir.ls() {
+        if !names.contains(&name) {
+            remove_entry(dir, &name);
+            stats.removed += 1;
+        }
+    }
+}
 
-    random_str_test(4 * BLOCK_SZ);
-    random_str_test(8 * BLOCK_SZ + BLOCK_SZ / 2);
-    random_str_test(100 * BLOCK_SZ);
-    random_str_test(70 * BLOCK_SZ + BLOCK_SZ / 7);
-    random_str_test((12 + 128) * BLOCK_SZ);
-    random_str_test(400 * BLOCK_SZ);
-    random_str_test(1000 * BLOCK_SZ);
-    random_str_test(2000 * BLOCK_SZ);
+/// Remove the entry `name` of the easy-fs directory `dir` with the entries below it.
+/// An inode without other links is released with its data by `unlink`
+fn remove_entry(dir: &Inode, name: &str) {
+    let inode = dir.find(name).unwrap();
+    if inode.is_dir() {
+        for child in inode.ls() {
+            remove_entry(&inode, &child);
+        }
+    }
+    dir.unlink(name).unwrap();
+}
 
+/// Get the number of blocks and of inode bitmap blocks of a new image from the options
+fn image_geometry(matches: &ArgMatches) -> std::io::Result<(u32, u32)> {
+    let invalid = |what: &str| Error::new(ErrorKind::InvalidInput, format!("invalid {}", what));
+    if let Some(block_size) = matches.value_of("block-size") {
+        if block_size.parse() != Ok(BLOCK_SZ) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("block size must be {}", BLOCK_SZ),
+            ));
+        }
+    }
+    let total_blocks = match (matches.value_of("blocks"), matches.value_of("size")) {
+        (Some(blocks), _) => blocks.parse().map_err(|_| invalid("number of blocks"))?,
+        (None, Some(size)) => {
+            let (digits, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+                Some(pos) => size.split_at(pos),
+                None => (size, ""),
+            };
+            let shift = match unit {
+                "" => 0,
+                "K" | "k" => 10,
+                "M" | "m" => 20,
+                "G" | "g" => 30,
+                _ => return Err(invalid("size")),
+            };
+            let bytes = digits.parse::<u64>().map_err(|_| invalid("size"))? << shift;
+            if bytes % BLOCK_SZ as u64 != 0 {
+                return Err(invalid("size, not a multiple of the block size"));
+            }
+            u32::try_from(bytes / BLOCK_SZ as u64).map_err(|_| invalid("size"))?
+        }
+        (None, None) => BLOCK_NUM as u32,
+    };
+    let inode_bitmap_blocks = match matches.value_of("inodes") {
+        Some(inodes) => {
+            let inodes: u32 = inodes.parse().map_err(|_| invalid("number of inodes"))?;
+            ((inodes + BLOCK_BITS - 1) / BLOCK_BITS).max(1)
+        }
+        None => 1,
+    };
+    let min_blocks = EasyFileSystem::min_total_blocks(inode_bitmap_blocks);
+    if total_blocks < min_blocks {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("at least {} blocks are needed for these inodes", min_blocks),
+        ));
+    }
+    Ok((total_blocks, inode_bitmap_blocks))
+}
+
+/// Read an encryption key from a host file
+fn read_key(key_path: &str) -> std::io::Result<[u8; KEY_SZ]> {
+    let mut key = [0u8; KEY_SZ];
+    File::open(key_path)?.read_exact(&mut key)?;
+    Ok(key)
+}
+
+/// Copy the `user.*` extended attributes of a host file into an easy-fs inode
+fn copy_xattrs(host_path: &Path, inode: &Inode) -> std::io::Result<()> {
+    let mut names: Vec<_> = match xattr::list(host_path) {
+        Ok(names) => names.collect(),
+        // the host filesystem may not support xattrs at all
+        Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    names.sort();
+    for name in names {
+        let name = name.into_string().map_err(|name| {
+            Error::new(ErrorKind::InvalidData, format!("{:?} is not UTF-8", name))
+        })?;
+        // other namespaces belong to the host, e.g. security labels
+        if !name.starts_with("user.") {
+            continue;
+        }
+        if let Some(value) = xattr::get(host_path, &name)? {
+            if inode.get_xattr(&name).as_ref() == Some(&value) {
+                continue;
+            }
+            if inode.set_xattr(&name, &value).is_none() {
+                println!(
+                    "skip xattr {} of {}: no space left",
+                    name,
+                    host_path.display()
+                );
+            }
+        }
+    }
     Ok(())
 }
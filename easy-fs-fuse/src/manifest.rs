@@ -0,0 +1,214 @@
+//! Manifests describing the entries of an image, in a subset of TOML:
+//!
+//! ```toml
+//! [[entry]]
+//! path = "bin/hello"        # path inside the image
+//! source = "build/hello"    # host file, relative to the manifest
+//! owner = 1000
+//!
+//! [[entry]]
+//! path = "bin/hi"
+//! type = "hardlink"         # file (default), dir, hardlink, symlink, fifo, char or block
+//! target = "bin/hello"      # entry linked to, a path inside the image
+//! ```
+//!
+//! Values are basic strings or integers, which may be written in octal with `0o`.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+
+/// Type of an entry of a manifest
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntryKind {
+    File,
+    Dir,
+    HardLink,
+    Symlink,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+}
+
+/// An entry of a manifest
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Line of the manifest the entry starts at
+    pub line: usize,
+    /// Path inside the image
+    pub path: String,
+    pub kind: EntryKind,
+    /// Host file holding the data of a file
+    pub source: Option<String>,
+    /// Path linked to by a link
+    pub target: Option<String>,
+    pub owner: Option<u32>,
+    pub mode: Option<u32>,
+    /// (major, minor) number of a device
+    pub device: (u32, u32),
+}
+
+/// A value of a manifest
+enum Value {
+    Str(String),
+    Int(u64),
+}
+
+/// Keys of a table with their line and value
+type Table = BTreeMap<String, (usize, Value)>;
+
+/// Error at a line of a manifest
+fn error(line: usize, message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("line {}: {}", line, message),
+    )
+}
+
+/// Parse the entries of a manifest
+pub fn parse(text: &str) -> std::io::Result<Vec<Entry>> {
+    let mut tables: Vec<(usize, Table)> = Vec::new();
+    for (index, raw_line) in text.lines().enumerate() {
+        let line = index + 1;
+        let (key, rest) = match raw_line.trim_start() {
+            "" => continue,
+            content if content.starts_with('#') => continue,
+            content if content.starts_with("[[") => {
+                if strip_comment(line, content)? != "[[entry]]" {
+                    return Err(error(line, "only [[entry]] tables are supported"));
+                }
+                tables.push((line, BTreeMap::new()));
+                continue;
+            }
+            content => match content.split_once('=') {
+                Some((key, rest)) => (key.trim(), rest.trim_start()),
+                None => return Err(error(line, "expected key = value")),
+            },
+        };
+        let (value, rest) = parse_value(line, rest)?;
+        if !strip_comment(line, rest)?.is_empty() {
+            return Err(error(line, "unexpected text after the value"));
+        }
+        let (_, table) = tables
+            .last_mut()
+            .ok_or_else(|| error(line, "key outside of an [[entry]] table"))?;
+        if table.insert(key.to_string(), (line, value)).is_some() {
+            return Err(error(line, &format!("duplicate key {}", key)));
+        }
+    }
+    tables
+        .into_iter()
+        .map(|(line, table)| parse_entry(line, table))
+        .collect()
+}
+
+/// Check that only a comment follows on a line, return what precedes it
+fn strip_comment(line: usize, text: &str) -> std::io::Result<&str> {
+    let text = match text.find('#') {
+        Some(pos) => &text[..pos],
+        None => text,
+    };
+    if text.contains('"') {
+        return Err(error(line, "unexpected string"));
+    }
+    Ok(text.trim())
+}
+
+/// Parse a value at the beginning of `text`, return it with the text after it
+fn parse_value(line: usize, text: &str) -> std::io::Result<(Value, &str)> {
+    if let Some(text) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = text.char_indices();
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::Str(value), &text[pos + 1..])),
+                '\\' => value.push(match chars.next() {
+                    Some((_, '"')) => '"',
+                    Some((_, '\\')) => '\\',
+                    Some((_, 'n')) => '\n',
+                    Some((_, 't')) => '\t',
+                    _ => return Err(error(line, "unsupported escape")),
+                }),
+                c => value.push(c),
+            }
+        }
+        return Err(error(line, "unterminated string"));
+    }
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == '#')
+        .unwrap_or(text.len());
+    let digits = text[..end].replace('_', "");
+    let value = match digits.strip_prefix("0o") {
+        Some(octal) => u64::from_str_radix(octal, 8),
+        None => digits.parse(),
+    }
+    .map_err(|_| error(line, "expected a string or an integer"))?;
+    Ok((Value::Int(value), &text[end..]))
+}
+
+/// Build an entry from the keys of its table
+fn parse_entry(line: usize, mut table: Table) -> std::io::Result<Entry> {
+    let mut string = |key: &str| match table.remove(key) {
+        Some((_, Value::Str(value))) => Ok(Some(value)),
+        Some((line, Value::Int(_))) => Err(error(line, &format!("{} must be a string", key))),
+        None => Ok(None),
+    };
+    let path = string("path")?.ok_or_else(|| error(line, "missing path"))?;
+    let kind = match string("type")?.as_deref() {
+        None | Some("file") => EntryKind::File,
+        Some("dir") => EntryKind::Dir,
+        Some("hardlink") => EntryKind::HardLink,
+        Some("symlink") => EntryKind::Symlink,
+        Some("fifo") => EntryKind::Fifo,
+        Some("char") => EntryKind::CharDevice,
+        Some("block") => EntryKind::BlockDevice,
+        Some(kind) => return Err(error(line, &format!("unknown type {}", kind))),
+    };
+    let source = string("source")?;
+    let target = string("target")?;
+    let mut integer = |key: &str| match table.remove(key) {
+        Some((line, Value::Int(value))) => u32::try_from(value)
+            .map(Some)
+            .map_err(|_| error(line, &format!("{} is too large", key))),
+        Some((line, Value::Str(_))) => Err(error(line, &format!("{} must be an integer", key))),
+        None => Ok(None),
+    };
+    let owner = integer("owner")?;
+    let mode = integer("mode")?;
+    let major = integer("major")?;
+    let minor = integer("minor")?;
+    if let Some((key, (line, _))) = table.into_iter().next() {
+        return Err(error(line, &format!("unknown key {}", key)));
+    }
+    let is_device = matches!(kind, EntryKind::CharDevice | EntryKind::BlockDevice);
+    if (kind == EntryKind::File) != source.is_some() {
+        return Err(error(
+            line,
+            "source is required by files and only allowed for them",
+        ));
+    }
+    if matches!(kind, EntryKind::HardLink | EntryKind::Symlink) != target.is_some() {
+        return Err(error(
+            line,
+            "target is required by links and only allowed for them",
+        ));
+    }
+    if is_device != (major.is_some() && minor.is_some())
+        || (!is_device && (major.is_some() || minor.is_some()))
+    {
+        return Err(error(
+            line,
+            "major and minor are required by devices and only allowed for them",
+        ));
+    }
+    Ok(Entry {
+        line,
+        path,
+        kind,
+        source,
+        target,
+        owner,
+        mode,
+        device: (major.unwrap_or(0), minor.unwrap_or(0)),
+    })
+}
@@ -0,0 +1,975 @@
+//! Tests of easy-fs and of the commands of the tool, one feature each on its own
+//! in-memory image. Only the tests of host image files use files under `target/`
+
+use super::*;
+use easy_fs::MemBlockDevice;
+
+/// Number of blocks of the test images
+const BLOCKS: u32 = 4096;
+
+/// Create a zeroed in-memory device large enough for a test image
+fn mem_device() -> Arc<MemBlockDevice> {
+    Arc::new(MemBlockDevice::new(BLOCKS as usize))
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Remove what a previous run left at the host paths of a test
+fn remove_host_dirs(dirs: &[&str]) -> std::io::Result<()> {
+    for dir in dirs {
+        if Path::new(dir).exists() {
+            std::fs::remove_dir_all(dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write `count` files of 40 blocks in turns, so that their blocks are interleaved
+fn churn_files(root_inode: &Inode, count: usize) -> Vec<Arc<Inode>> {
+    let files: Vec<Arc<Inode>> = (0..count)
+        .map(|i| root_inode.create(&format!("churn{}", i)).unwrap())
+        .collect();
+    for chunk in 0..40 {
+        for (i, file) in files.iter().enumerate() {
+            let block = [(chunk * count + i) as u8; BLOCK_SZ];
+            file.write_at(chunk * BLOCK_SZ, &block);
+        }
+    }
+    files
+}
+
+#[test]
+fn files_are_read_back_with_their_xattrs() {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("filea");
+    root_inode.create("fileb");
+    assert_eq!(root_inode.ls(), ["filea", "fileb"]);
+    let filea = root_inode.find("filea").unwrap();
+    let greet_str = "Hello, world!";
+    filea.write_at(0, greet_str.as_bytes());
+    let mut buffer = [0u8; 233];
+    let len = filea.read_at(0, &mut buffer);
+    assert_eq!(greet_str, core::str::from_utf8(&buffer[..len]).unwrap(),);
+
+    assert!(filea.get_xattr("user.checksum").is_none());
+    filea.set_xattr("user.checksum", b"1234").unwrap();
+    filea.set_xattr("user.label", b"fixture").unwrap();
+    filea.set_xattr("user.checksum", b"5678").unwrap();
+    assert_eq!(filea.get_xattr("user.checksum").unwrap(), b"5678");
+    assert_eq!(filea.list_xattr(), vec!["user.checksum", "user.label"]);
+    assert!(filea.set_xattr("user.big", &[0u8; BLOCK_SZ]).is_none());
+    filea.remove_xattr("user.checksum").unwrap();
+    assert!(filea.remove_xattr("user.checksum").is_none());
+    assert_eq!(filea.list_xattr(), vec!["user.label"]);
+}
+
+#[test]
+fn random_data_is_read_back() {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+    let mut buffer = [0u8; 233];
+    let mut random_str_test = |len: usize| {
+        filea.clear();
+        assert_eq!(filea.read_at(0, &mut buffer), 0,);
+        let mut str = String::new();
+        use rand;
+        // random digit
+        for _ in 0..len {
+            str.push(char::from(b'0' + rand::random::<u8>() % 10));
+        }
+        filea.write_at(0, str.as_bytes());
+        let mut read_buffer = [0u8; 127];
+        let mut offset = 0usize;
+        let mut read_str = String::new();
+        loop {
+            let len = filea.read_at(offset, &mut read_buffer);
+            if len == 0 {
+                break;
+            }
+            offset += len;
+            read_str.push_str(core::str::from_utf8(&read_buffer[..len]).unwrap());
+        }
+        assert_eq!(str, read_str);
+    };
+
+    random_str_test(4 * BLOCK_SZ);
+    random_str_test(8 * BLOCK_SZ + BLOCK_SZ / 2);
+    random_str_test(100 * BLOCK_SZ);
+    random_str_test(70 * BLOCK_SZ + BLOCK_SZ / 7);
+    random_str_test((12 + 128) * BLOCK_SZ);
+    random_str_test(400 * BLOCK_SZ);
+    random_str_test(1000 * BLOCK_SZ);
+    random_str_test(2000 * BLOCK_SZ);
+}
+
+#[test]
+fn clones_copy_blocks_on_write() {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let fileb = root_inode.create("fileb").unwrap();
+    let fixture = [b'f'; 40 * BLOCK_SZ];
+    fileb.write_at(0, &fixture);
+    let filec = root_inode.create("filec").unwrap();
+    Inode::clone_from(&filec, &fileb).unwrap();
+    let mut read_buffer = [0u8; 40 * BLOCK_SZ];
+    assert_eq!(filec.read_at(0, &mut read_buffer), fixture.len());
+    assert_eq!(read_buffer, fixture);
+    // the first write to either file copies the block
+    filec.write_at(BLOCK_SZ * 30, b"changed");
+    fileb.write_at(0, b"changed");
+    fileb.read_at(0, &mut read_buffer);
+    assert_eq!(&read_buffer[..7], b"changed");
+    assert_eq!(read_buffer[BLOCK_SZ * 30], b'f');
+    filec.read_at(0, &mut read_buffer);
+    assert_eq!(read_buffer[0], b'f');
+    assert_eq!(&read_buffer[BLOCK_SZ * 30..BLOCK_SZ * 30 + 7], b"changed");
+    fileb.clear();
+    filec.read_at(0, &mut read_buffer);
+    assert_eq!(read_buffer[BLOCK_SZ], b'f');
+    filec.clear();
+}
+
+#[test]
+fn snapshots_keep_the_files_as_they_were() {
+    let device = mem_device();
+    let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filea = root_inode.create("filea").unwrap();
+    filea.set_xattr("user.label", b"fixture").unwrap();
+    let fileb = root_inode.create("fileb").unwrap();
+    fileb.write_at(0, b"pristine");
+    EasyFileSystem::create_snapshot(&efs, "clean").unwrap();
+    assert!(EasyFileSystem::create_snapshot(&efs, "clean").is_none());
+    fileb.write_at(0, b"modified");
+    root_inode.create("filed");
+    let snapshot_root = EasyFileSystem::snapshot_root(&efs, "clean").unwrap();
+    assert!(snapshot_root.find("filed").is_none());
+    let snapshot_fileb = snapshot_root.find("fileb").unwrap();
+    let mut read_buffer = [0u8; BLOCK_SZ];
+    snapshot_fileb.read_at(0, &mut read_buffer);
+    assert_eq!(&read_buffer[..8], b"pristine");
+    assert_eq!(snapshot_fileb.write_at(0, b"x"), 0);
+    assert!(snapshot_root.create("filee").is_none());
+    assert_eq!(efs.lock().list_snapshots(), vec!["clean"]);
+    let snapshots_dir = EasyFileSystem::snapshots_dir(&efs);
+    assert_eq!(snapshots_dir.ls(), vec!["clean"]);
+    // neither the live filesystem nor a snapshot with open files is released
+    assert!(EasyFileSystem::revert_to_snapshot(&efs, "clean").is_none());
+    assert!(efs.lock().delete_snapshot("clean").is_none());
+    drop((filea, fileb, snapshot_fileb, snapshot_root, snapshots_dir));
+    EasyFileSystem::revert_to_snapshot(&efs, "clean").unwrap();
+    // handles taken after the revert see the files of the snapshot
+    assert!(root_inode.find("filed").is_none());
+    let fileb = root_inode.find("fileb").unwrap();
+    fileb.read_at(0, &mut read_buffer);
+    assert_eq!(&read_buffer[..8], b"pristine");
+    efs.lock().delete_snapshot("clean").unwrap();
+    assert!(efs.lock().list_snapshots().is_empty());
+    let filea = root_inode.find("filea").unwrap();
+    assert_eq!(filea.list_xattr(), vec!["user.label"]);
+    // and so does the image opened again
+    drop((filea, fileb, root_inode));
+    efs.lock().sync();
+    let efs = EasyFileSystem::open(device);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    assert_eq!(root_inode.ls(), ["filea", "fileb"]);
+    root_inode
+        .find("fileb")
+        .unwrap()
+        .read_at(0, &mut read_buffer);
+    assert_eq!(&read_buffer[..8], b"pristine");
+    assert!(efs.lock().list_snapshots().is_empty());
+}
+
+#[test]
+fn compressed_files_are_read_back() {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let filec = root_inode.create("filec").unwrap();
+    let mut compressible = vec![0u8; 30 * BLOCK_SZ];
+    for (i, byte) in compressible.iter_mut().enumerate().step_by(97) {
+        *byte = i as u8;
+    }
+    filec.write_at(0, &compressible);
+    filec.compress().unwrap();
+    assert!(filec.is_compressed());
+    assert!(filec.compress().is_none());
+    let mut read_buffer = vec![0u8; compressible.len() + 1];
+    assert_eq!(filec.read_at(0, &mut read_buffer), compressible.len());
+    assert_eq!(&read_buffer[..compressible.len()], compressible.as_slice());
+    assert_eq!(filec.read_at(5000, &mut read_buffer[..3000]), 3000);
+    assert_eq!(&read_buffer[..3000], &compressible[5000..8000]);
+    // writes store the data as is again
+    filec.write_at(BLOCK_SZ * 10, b"changed");
+    assert!(!filec.is_compressed());
+    compressible[BLOCK_SZ * 10..BLOCK_SZ * 10 + 7].copy_from_slice(b"changed");
+    assert_eq!(filec.read_at(0, &mut read_buffer), compressible.len());
+    assert_eq!(&read_buffer[..compressible.len()], compressible.as_slice());
+    filec.write_at(0, &[b'x'; 7]);
+    assert!(filec.compress().is_some());
+    filec.clear();
+    assert!(!filec.is_compressed());
+}
+
+#[test]
+fn quotas_limit_the_blocks_and_inodes_of_a_user() {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    efs.lock().set_quota(1, 4, 2).unwrap();
+    let (_, user_file) = root_inode.create_inode_as("user_file", 1).unwrap();
+    assert_eq!(user_file.owner(), 1);
+    assert_eq!(user_file.write_at(0, &[b'u'; 4 * BLOCK_SZ]), 4 * BLOCK_SZ);
+    assert_eq!(user_file.write_at(4 * BLOCK_SZ, b"over quota"), 0);
+    assert!(user_file.set_xattr("user.label", b"over quota").is_none());
+    assert_eq!(efs.lock().quota(1).blocks_used, 4);
+    root_inode.create_inode_as("user_file2", 1).unwrap();
+    assert!(root_inode.create_inode_as("user_file3", 1).is_none());
+    assert_eq!(efs.lock().quota(1).inodes_used, 2);
+    user_file.clear();
+    assert_eq!(efs.lock().quota(1).blocks_used, 0);
+    efs.lock().recount_quota();
+    assert_eq!(efs.lock().quota(1).inodes_used, 2);
+    assert_eq!(efs.lock().quota(1).blocks_used, 0);
+    // uids beyond the table have no quota and are not accounted
+    assert!(efs.lock().set_quota(u32::MAX, 1, 1).is_none());
+    let (_, other_file) = root_inode.create_inode_as("other_file", u32::MAX).unwrap();
+    assert_eq!(other_file.write_at(0, &[b'u'; 2 * BLOCK_SZ]), 2 * BLOCK_SZ);
+    assert_eq!(efs.lock().quota(u32::MAX).blocks_used, 0);
+}
+
+#[test]
+fn encrypted_files_need_their_key() {
+    let device = mem_device();
+    let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let key = [0x5au8; KEY_SZ];
+    let key_id = efs.lock().add_key(&key, 1000).unwrap();
+    // the id only depends on the data half, a different key with the same id is refused
+    let mut other_key = key;
+    other_key[KEY_SZ / 2..].fill(0xa5);
+    assert!(efs.lock().add_key(&other_key, 1000).is_none());
+    let secret = root_inode.create("secret").unwrap();
+    secret.set_encryption(key_id).unwrap();
+    assert_eq!(secret.encryption_key(), Some(key_id));
+    let marker = b"grading data, not for students";
+    secret.write_at(0, marker);
+    // the gap before a write past the end reads as zeros
+    secret.write_at(3 * BLOCK_SZ + 100, marker);
+    let mut read_buffer = vec![0u8; 4 * BLOCK_SZ];
+    assert_eq!(
+        secret.read_at(0, &mut read_buffer),
+        3 * BLOCK_SZ + 100 + marker.len()
+    );
+    assert_eq!(&read_buffer[..marker.len()], marker);
+    assert!(read_buffer[marker.len()..3 * BLOCK_SZ + 100]
+        .iter()
+        .all(|byte| *byte == 0));
+    assert_eq!(
+        &read_buffer[3 * BLOCK_SZ + 100..3 * BLOCK_SZ + 100 + marker.len()],
+        marker
+    );
+    assert!(secret.compress().is_none());
+    // a key is only removed by the user who loaded it or by root
+    assert_eq!(efs.lock().add_key(&key, 1001), Some(key_id));
+    assert!(efs.lock().remove_key(key_id, 1001).is_none());
+    efs.lock().remove_key(key_id, 1000).unwrap();
+    assert_eq!(secret.read_at(0, &mut read_buffer), 0);
+    assert_eq!(secret.write_at(0, b"x"), 0);
+    efs.lock().sync();
+    assert!(!contains(&device.to_bytes(), marker));
+    assert_eq!(efs.lock().add_key(&key, 1001), Some(key_id));
+    assert_eq!(
+        secret.read_at(0, &mut read_buffer[..marker.len()]),
+        marker.len()
+    );
+    assert_eq!(&read_buffer[..marker.len()], marker);
+}
+
+#[test]
+fn encrypted_directories_hide_the_names_of_their_children() {
+    let device = mem_device();
+    let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let key = [0x5au8; KEY_SZ];
+    let marker = b"grading data, not for students";
+    let key_id = efs.lock().add_key(&key, 1000).unwrap();
+    root_inode.set_encryption(key_id).unwrap();
+    let secret = root_inode.create("grading_secret").unwrap();
+    assert_eq!(secret.encryption_key(), Some(key_id));
+    secret.write_at(0, marker);
+    assert_eq!(root_inode.ls(), vec!["grading_secret"]);
+    assert!(root_inode.find("grading_secret").is_some());
+    efs.lock().remove_key(key_id, ROOT_UID).unwrap();
+    assert!(root_inode.find("grading_secret").is_none());
+    assert_ne!(root_inode.ls(), vec!["grading_secret"]);
+    assert!(root_inode.create("plain").is_none());
+    efs.lock().sync();
+    let image = device.to_bytes();
+    assert!(!contains(&image, marker) && !contains(&image, b"grading_secret"));
+}
+
+#[test]
+fn inodes_overflow_into_the_data_area() {
+    let device = mem_device();
+    let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    while efs.lock().alloc_inode().unwrap() < 4095 {}
+    for i in 0..6 {
+        let (inode_id, inode) = root_inode.create_inode(&format!("extra{}", i)).unwrap();
+        assert_eq!(inode_id, 4096 + i);
+        inode.write_at(0, format!("extra file {}", i).as_bytes());
+    }
+    let efs = EasyFileSystem::open(device);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let mut read_buffer = [0u8; BLOCK_SZ];
+    for i in 0..6 {
+        let extra = root_inode.find(&format!("extra{}", i)).unwrap();
+        let len = extra.read_at(0, &mut read_buffer);
+        assert_eq!(&read_buffer[..len], format!("extra file {}", i).as_bytes());
+    }
+    // a block of inodes is released with its last inode and then reused
+    let mut fs = efs.lock();
+    for inode_id in 4096..4102 {
+        fs.dealloc_inode(inode_id);
+    }
+    assert_eq!(fs.alloc_inode(), Some(4096));
+}
+
+#[test]
+fn grown_images_hold_more_data() {
+    let device = Arc::new(MemBlockDevice::new(2 * BLOCKS as usize));
+    let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let data: Vec<u8> = (0..2600 * BLOCK_SZ).map(|i| (i % 251) as u8).collect();
+    let before = root_inode.create("before").unwrap();
+    assert_eq!(before.write_at(0, &data), data.len());
+    assert!(efs.lock().grow(BLOCKS).is_none());
+    efs.lock().grow(2 * BLOCKS).unwrap();
+    let after = root_inode.create("after").unwrap();
+    assert_eq!(after.write_at(0, &data), data.len());
+    let efs = EasyFileSystem::open(device);
+    assert_eq!(efs.lock().total_blocks(), 2 * BLOCKS);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    for name in ["before", "after"] {
+        let mut read_back = vec![0u8; data.len()];
+        let inode = root_inode.find(name).unwrap();
+        assert_eq!(inode.read_at(0, &mut read_back), data.len());
+        assert!(read_back == data);
+    }
+}
+
+#[test]
+fn reserved_blocks_are_contiguous_and_charged_up_front() {
+    let device = mem_device();
+    let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let log = root_inode.create("log").unwrap();
+    let used = efs.lock().quota(0).blocks_used;
+    log.allocate(0, 40 * BLOCK_SZ, true).unwrap();
+    let stat = log.stat();
+    assert_eq!((stat.size, stat.blocks), (0, 41));
+    assert_eq!(efs.lock().quota(0).blocks_used, used + 41);
+    assert_eq!(efs.lock().fragmentation().score(), 0);
+    let other = root_inode.create("other").unwrap();
+    other.write_at(0, &[1u8; BLOCK_SZ]);
+    for chunk in 0..40 {
+        assert_eq!(
+            log.write_at(chunk * BLOCK_SZ, &[chunk as u8; BLOCK_SZ]),
+            BLOCK_SZ
+        );
+    }
+    assert_eq!(log.stat().blocks, 41);
+    assert_eq!(efs.lock().fragmentation().score(), 0);
+    assert!(log.allocate(0, easy_fs::MAX_FILE_SIZE, true).is_none());
+    // a range whose end overflows is rejected rather than wrapped around
+    assert!(log.allocate(usize::MAX, 2, false).is_none());
+    assert_eq!(log.stat().blocks, 41);
+    log.allocate(40 * BLOCK_SZ, 100, false).unwrap();
+    let mut tail = [1u8; 100];
+    assert_eq!(log.read_at(40 * BLOCK_SZ, &mut tail), 100);
+    assert_eq!(tail, [0u8; 100]);
+    assert_eq!(log.stat().size as usize, 40 * BLOCK_SZ + 100);
+    // reserved blocks survive reopening and are released with the data
+    log.allocate(0, 60 * BLOCK_SZ, true).unwrap();
+    let efs = EasyFileSystem::open(device);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let log = root_inode.find("log").unwrap();
+    assert_eq!(log.stat().blocks, 61);
+    let used = efs.lock().quota(0).blocks_used;
+    efs.lock().recount_quota();
+    assert_eq!(efs.lock().quota(0).blocks_used, used);
+    log.clear();
+    assert_eq!(log.stat().blocks, 0);
+    assert_eq!(efs.lock().quota(0).blocks_used, used - 61);
+}
+
+#[test]
+fn fifos_and_devices_have_no_data() {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let (_, fifo) = root_inode
+        .mknod_as("fifo", 0, DiskInodeType::Fifo, 0, 0)
+        .unwrap();
+    let (_, null) = root_inode
+        .mknod_as("null", 0, DiskInodeType::CharDevice, 1, 3)
+        .unwrap();
+    assert!(root_inode
+        .mknod_as("dir", 0, DiskInodeType::Directory, 0, 0)
+        .is_none());
+    assert!(root_inode
+        .mknod_as("big", 0, DiskInodeType::BlockDevice, 1 << 12, 0)
+        .is_none());
+    assert_eq!(fifo.device(), None);
+    assert_eq!(null.device(), Some((1, 3)));
+    assert_eq!(null.write_at(0, &[1u8; 16]), 0);
+    assert!(fifo.allocate(0, BLOCK_SZ, false).is_none());
+    let stat = null.stat();
+    assert_eq!((stat.size, stat.blocks, stat.device), (0, 0, Some((1, 3))));
+    let types: Vec<_> = (0..2)
+        .map(|i| root_inode.readdir(i * 32).unwrap().0.inode_type)
+        .collect();
+    assert_eq!(types, [DiskInodeType::Fifo, DiskInodeType::CharDevice]);
+    // devices keep their numbers in snapshots
+    EasyFileSystem::create_snapshot(&efs, "before").unwrap();
+    let snapshot_root = EasyFileSystem::snapshot_root(&efs, "before").unwrap();
+    assert_eq!(snapshot_root.find("null").unwrap().device(), Some((1, 3)));
+}
+
+#[test]
+fn the_smallest_image_holds_a_single_data_block() {
+    let min_blocks = EasyFileSystem::min_total_blocks(2);
+    let device = Arc::new(MemBlockDevice::new(min_blocks as usize));
+    EasyFileSystem::create(device.clone(), min_blocks, 2);
+    let efs = EasyFileSystem::open(device);
+    let super_block = efs.lock().super_block();
+    assert_eq!(
+        (super_block.total_blocks, super_block.inode_bitmap_blocks),
+        (min_blocks, 2)
+    );
+    assert_eq!(super_block.inode_area_blocks, 2 * BLOCK_BITS / 4);
+    assert_eq!(super_block.data_area_blocks, 1);
+}
+
+#[test]
+fn shell_commands_inspect_and_edit_an_image() -> std::io::Result<()> {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let run = |name: &str, args: &[&str]| {
+        let mut out = Vec::new();
+        run_command(&root_inode, name, args, &mut out).map(|_| String::from_utf8(out).unwrap())
+    };
+    let host_path = "target/shell_host";
+    std::fs::write(host_path, "shell data")?;
+    // the root directory and the hidden inodes of the tables of the filesystem
+    assert!(run("df", &[])?.contains(" 0 used, 3069 free\ninodes: 5 used"));
+    run("put", &[host_path, "data"])?;
+    run("ln", &["data", "alias"])?;
+    assert_eq!(run("ls", &[])?, "data\nalias\n");
+    assert_eq!(run("cat", &["alias"])?, "shell data");
+    assert!(run("stat", &["data"])?.contains("size: 10\nblocks: 1\nlinks: 2\n"));
+    assert!(run("df", &[])?.contains(" 3 used, 3066 free\ninodes: 6 used"));
+    run("rm", &["data"])?;
+    assert_eq!(run("ls", &[])?, "alias\n");
+    run("get", &["alias", host_path])?;
+    assert_eq!(std::fs::read(host_path)?, b"shell data");
+    assert_eq!(
+        run("cat", &["data"]).unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+    assert_eq!(
+        run("ln", &["alias"]).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(run("mv", &[]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    // removing the last link releases the inode and its block
+    run("rm", &["alias"])?;
+    assert!(run("df", &[])?.contains(" 2 used, 3067 free\ninodes: 5 used"));
+    Ok(())
+}
+
+#[test]
+fn unpacking_keeps_sizes_and_hard_links() -> std::io::Result<()> {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let big_data: Vec<u8> = (0..3 * BLOCK_SZ + 7).map(|i| i as u8).collect();
+    root_inode.create("big").unwrap().write_at(0, &big_data);
+    root_inode.create("empty").unwrap();
+    root_inode.link("also_big", "big").unwrap();
+    root_inode
+        .mknod_as("pipe", 0, DiskInodeType::Fifo, 0, 0)
+        .unwrap();
+    let host_dir = Path::new("target/unpack");
+    remove_host_dirs(&["target/unpack"])?;
+    let mut unpacked = BTreeMap::new();
+    unpack_dir(&root_inode, host_dir, &mut unpacked)?;
+    assert_eq!(unpacked.len(), 2);
+    assert_eq!(std::fs::read(host_dir.join("big"))?, big_data);
+    assert_eq!(std::fs::read(host_dir.join("empty"))?, b"");
+    assert!(!host_dir.join("pipe").exists());
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(
+            std::fs::metadata(host_dir.join("big"))?.ino(),
+            std::fs::metadata(host_dir.join("also_big"))?.ino()
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn packing_mirrors_a_host_tree() -> std::io::Result<()> {
+    // a host tree is packed with exact names and unpacked back unchanged
+    let host_tree = Path::new("target/tree");
+    remove_host_dirs(&["target/tree", "target/tree_out"])?;
+    create_dir_all(host_tree.join("apps/nested"))?;
+    create_dir_all(host_tree.join("empty"))?;
+    let big_data: Vec<u8> = (0..3 * BLOCK_SZ + 7).map(|i| i as u8).collect();
+    std::fs::write(host_tree.join("config.v1.toml"), "level = 3")?;
+    std::fs::write(host_tree.join("apps/nested/input.bin"), &big_data)?;
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let options = PackOptions {
+        key_id: None,
+        encrypted: Vec::new(),
+        compress: false,
+    };
+    let mut stats = PackStats::default();
+    pack_tree(&root_inode, host_tree, "", &options, &mut stats)?;
+    assert_eq!((stats.written, stats.unchanged, stats.removed), (2, 0, 0));
+    assert_eq!(root_inode.ls(), ["apps", "config.v1.toml", "empty"]);
+    let apps = root_inode.find("apps").unwrap();
+    assert!(apps.is_dir());
+    assert!(apps.mkdir("nested").is_none());
+    let input = apps.find("nested").unwrap().find("input.bin").unwrap();
+    assert_eq!(input.stat().size as usize, big_data.len());
+    let input_id = input.inode_id();
+    assert_eq!(efs.lock().link_count(input_id), 1);
+    let mut unpacked = BTreeMap::new();
+    unpack_dir(&root_inode, Path::new("target/tree_out"), &mut unpacked)?;
+    assert_eq!(
+        std::fs::read("target/tree_out/apps/nested/input.bin")?,
+        big_data
+    );
+    assert_eq!(
+        std::fs::read("target/tree_out/config.v1.toml")?,
+        b"level = 3"
+    );
+    assert!(Path::new("target/tree_out/empty").is_dir());
+    drop((apps, input));
+
+    // packing again only writes what changed and removes what is gone
+    let config_id = root_inode.find("config.v1.toml").unwrap().inode_id();
+    let used_blocks = |root_inode: &Inode| {
+        let usage = root_inode.fs().lock().usage();
+        usage.data_blocks - usage.free_blocks
+    };
+    let used = used_blocks(&root_inode);
+    std::fs::remove_dir_all(host_tree.join("apps"))?;
+    std::fs::write(host_tree.join("empty/new"), "new")?;
+    let mut stats = PackStats::default();
+    pack_tree(&root_inode, host_tree, "", &options, &mut stats)?;
+    assert_eq!((stats.written, stats.unchanged, stats.removed), (1, 1, 1));
+    assert_eq!(root_inode.ls(), ["config.v1.toml", "empty"]);
+    assert_eq!(
+        root_inode.find("config.v1.toml").unwrap().inode_id(),
+        config_id
+    );
+    // the 4 blocks of input.bin and the blocks of the two directories are released,
+    // a block is taken by new and another by the entries of empty
+    assert_eq!(used_blocks(&root_inode), used - 6 + 2);
+    // a changed file is rewritten in place, taking no other inode
+    let used_inodes = efs.lock().usage().used_inodes;
+    std::fs::write(host_tree.join("config.v1.toml"), "level = 4")?;
+    let mut stats = PackStats::default();
+    pack_tree(&root_inode, host_tree, "", &options, &mut stats)?;
+    assert_eq!((stats.written, stats.unchanged, stats.removed), (1, 1, 0));
+    assert_eq!(
+        root_inode.find("config.v1.toml").unwrap().inode_id(),
+        config_id
+    );
+    assert_eq!(efs.lock().usage().used_inodes, used_inodes);
+    let mut data = Vec::new();
+    copy_out(&root_inode.find("config.v1.toml").unwrap(), &mut data)?;
+    assert_eq!(data, b"level = 4");
+    Ok(())
+}
+
+#[test]
+fn manifests_describe_every_entry_of_an_image() -> std::io::Result<()> {
+    create_dir_all("target/manifest")?;
+    std::fs::write("target/manifest/hello", "hello")?;
+    std::fs::write(
+        "target/manifest/fs.toml",
+        r#"
+# apps of user 1000
+[[entry]]
+path = "home/user"
+type = "dir"
+owner = 1000
+
+[[entry]]
+path = "home/user/hello"
+source = "hello"
+owner = 1000
+mode = 0o755
+
+[[entry]]
+path = "bin/hi" # linked from another directory
+type = "hardlink"
+target = "home/user/hello"
+
+[[entry]]
+path = "dev/null"
+type = "char"
+major = 1
+minor = 3
+"#,
+    )?;
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+    let options = PackOptions {
+        key_id: None,
+        encrypted: Vec::new(),
+        compress: false,
+    };
+    let mut stats = PackStats::default();
+    pack_manifest(
+        &root_inode,
+        Path::new("target/manifest/fs.toml"),
+        &options,
+        &mut stats,
+    )?;
+    assert_eq!(stats.written, 4);
+    assert_eq!(root_inode.ls(), ["home", "bin", "dev"]);
+    let hello = find_path(&root_inode, "home/user/hello").unwrap();
+    assert_eq!((hello.owner(), hello.stat().nlink), (1000, 2));
+    assert_eq!(find_path(&root_inode, "home/user").unwrap().owner(), 1000);
+    assert_eq!(find_path(&root_inode, "home").unwrap().owner(), ROOT_UID);
+    let mut data = Vec::new();
+    copy_out(&find_path(&root_inode, "bin/hi").unwrap(), &mut data)?;
+    assert_eq!(data, b"hello");
+    assert_eq!(
+        find_path(&root_inode, "dev/null").unwrap().device(),
+        Some((1, 3))
+    );
+    for (manifest, error) in [
+        (
+            "[[entry]]\npath = \"x\"\ntype = \"symlink\"\ntarget = \"y\"",
+            "no symbolic links",
+        ),
+        (
+            "[[entry]]\npath = \"x\"\nsize = 1",
+            "line 3: unknown key size",
+        ),
+        ("[[entry]]\npath = \"x\"", "line 1: source is required"),
+        ("path = \"x\"", "line 1: key outside"),
+        ("[[entry]]\npath = \"x\" y", "line 2: unexpected text"),
+    ] {
+        std::fs::write("target/manifest/bad.toml", manifest)?;
+        let err = pack_manifest(
+            &root_inode,
+            Path::new("target/manifest/bad.toml"),
+            &options,
+            &mut stats,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains(error), "{}", err);
+    }
+    Ok(())
+}
+
+#[test]
+fn digests_are_those_of_sha256sum() -> std::io::Result<()> {
+    assert_eq!(
+        sha256::digest(&b"abc"[..])?,
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+    assert_eq!(
+        sha256::digest(&[b'a'; 1000][..])?,
+        "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+    );
+    Ok(())
+}
+
+#[test]
+fn images_of_a_tree_are_reproducible_and_diffed() -> std::io::Result<()> {
+    let host_tree = Path::new("target/repro_tree");
+    remove_host_dirs(&["target/repro_tree"])?;
+    create_dir_all(host_tree.join("empty"))?;
+    std::fs::write(host_tree.join("config.v1.toml"), "level = 4")?;
+    std::fs::write(host_tree.join("empty/new"), "new")?;
+    let options = PackOptions {
+        key_id: None,
+        encrypted: Vec::new(),
+        compress: false,
+    };
+    // images packed from the same tree are identical, even when built by one process
+    let digests = (0..2)
+        .map(|i| {
+            let image_path = format!("target/repro{}.img", i);
+            let image_file = Arc::new(BlockFile::create(&image_path, 2048)?);
+            let efs = EasyFileSystem::create(image_file, 2048, 1);
+            let root_inode = EasyFileSystem::root_inode(&efs);
+            pack_tree(
+                &root_inode,
+                host_tree,
+                "",
+                &options,
+                &mut PackStats::default(),
+            )?;
+            efs.lock().sync();
+            sha256::digest(File::open(&image_path)?)
+        })
+        .collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(digests[0], digests[1]);
+
+    // diff reports the files added, removed and modified, and the blocks of the bitmaps
+    {
+        let efs = EasyFileSystem::open(open_image("target/repro1.img")?);
+        let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+        let new = find_path(&root_inode, "empty/new").unwrap();
+        new.write_at(BLOCK_SZ, b"longer");
+        remove_entry(&root_inode, "config.v1.toml");
+        root_inode.create("added").unwrap().write_at(0, b"added");
+        efs.lock().sync();
+    }
+    let mut report = Vec::new();
+    diff_images(
+        &open_image("target/repro0.img")?,
+        &open_image("target/repro1.img")?,
+        &mut report,
+    )?;
+    // the inode and the blocks of config.v1.toml are reused by added
+    assert_eq!(
+        String::from_utf8(report).unwrap(),
+        "data blocks used: 5 -> 6, inodes used: 8 -> 8\n\
+         inode bitmap: 0 allocated, 0 freed\n\
+         data block bitmap: 1 allocated, 0 freed\n\
+         - config.v1.toml (9 bytes)\n\
+         + added (5 bytes)\n\
+         M empty/new: size 3 -> 518, blocks 0-1\n"
+    );
+    assert_eq!(diff_block_ranges(b"ab", b"ab"), []);
+    assert_eq!(
+        diff_block_ranges(&[0; 4 * BLOCK_SZ], &[1; 6 * BLOCK_SZ][..]),
+        [(0, 6)]
+    );
+    Ok(())
+}
+
+#[test]
+fn image_files_are_sparse_and_bounded() -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    // images are created sparse, a partial last block reads as zeros
+    // and a block past the end is an error instead of a panic
+    let image_file = BlockFile::create("target/short.img", 1024)?;
+    assert_eq!(image_file.blocks()?, 1024);
+    assert!(std::fs::metadata("target/short.img")?.blocks() < 1024);
+    image_file.try_write_block(1, &[7; BLOCK_SZ])?;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open("target/short.img")?
+        .set_len((BLOCK_SZ + 100) as u64)?;
+    assert_eq!(image_file.blocks()?, 2);
+    let mut block = [1u8; BLOCK_SZ];
+    image_file.try_read_block(1, &mut block)?;
+    assert_eq!(block[..100], [7; 100]);
+    assert_eq!(block[100..], [0; BLOCK_SZ - 100]);
+    let err = image_file.try_read_block(2, &mut block).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    let err = image_file.try_write_block(0, &block[1..]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    Ok(())
+}
+
+#[test]
+fn defragmented_files_are_contiguous() {
+    let device = mem_device();
+    let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    // files written in turns are scattered
+    let files = churn_files(&root_inode, 3);
+    let shared = root_inode.create("shared").unwrap();
+    Inode::clone_from(&shared, &files[0]).unwrap();
+    assert_eq!(efs.lock().fragmentation().score(), 100);
+    // the blocks shared by churn0 and its clone stay in place
+    assert_eq!(EasyFileSystem::defragment(&efs), 2);
+    let fragmentation = efs.lock().fragmentation();
+    assert_eq!(fragmentation.extents - fragmentation.files, 2 * 39);
+    assert_eq!(fragmentation.score(), 50);
+    assert_eq!(EasyFileSystem::defragment(&efs), 0);
+    let efs = EasyFileSystem::open(device);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    for i in 0..3 {
+        let file = root_inode.find(&format!("churn{}", i)).unwrap();
+        for chunk in 0..40 {
+            let mut block = [0u8; BLOCK_SZ];
+            file.read_at(chunk * BLOCK_SZ, &mut block);
+            assert_eq!(block, [(chunk * 3 + i) as u8; BLOCK_SZ]);
+        }
+    }
+}
+
+#[test]
+fn defragmentation_without_room_for_indirect_blocks_keeps_files() {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let files = churn_files(&root_inode, 2);
+    let gap = root_inode.create("gap").unwrap();
+    gap.write_at(0, &[0; 40 * BLOCK_SZ]);
+    let small = root_inode.create("small").unwrap();
+    let spares: Vec<_> = (0..3)
+        .map(|i| root_inode.create(&format!("spare{}", i)).unwrap())
+        .collect();
+    let fill = root_inode.create("fill").unwrap();
+    let mut offset = 0;
+    for len in [64 * BLOCK_SZ, BLOCK_SZ] {
+        loop {
+            let written = fill.write_at(offset, &vec![0; len]);
+            if written == 0 {
+                break;
+            }
+            offset += written;
+        }
+    }
+    // a block left over by the filler cannot serve as an indirect block
+    for spare in spares.iter() {
+        if efs.lock().usage().free_blocks > 0 {
+            assert_eq!(spare.write_at(0, &[0; BLOCK_SZ]), BLOCK_SZ);
+        }
+    }
+    assert_eq!(efs.lock().usage().free_blocks, 0);
+    // a free run as long as the data of a churned file, with no block for its indirect block
+    gap.clear();
+    assert_eq!(small.write_at(0, &[0; BLOCK_SZ]), BLOCK_SZ);
+    assert_eq!(efs.lock().usage().free_blocks, 40);
+    let fragmentation = efs.lock().fragmentation();
+    assert_eq!(EasyFileSystem::defragment(&efs), 0);
+    assert_eq!(efs.lock().fragmentation().extents, fragmentation.extents);
+    assert_eq!(efs.lock().usage().free_blocks, 40);
+    for (i, file) in files.iter().enumerate() {
+        for chunk in 0..40 {
+            let mut block = [0u8; BLOCK_SZ];
+            file.read_at(chunk * BLOCK_SZ, &mut block);
+            assert_eq!(block, [(chunk * 2 + i) as u8; BLOCK_SZ]);
+        }
+    }
+}
+
+#[test]
+fn readdir_skips_unlinked_entries() {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let files = churn_files(&root_inode, 3);
+    let shared = root_inode.create("shared").unwrap();
+    Inode::clone_from(&shared, &files[0]).unwrap();
+    drop(files);
+    // readdir walks the entries one at a time, skipping unlinked ones
+    root_inode.unlink("churn1").unwrap();
+    let mut offset = 0;
+    let mut entries = Vec::new();
+    while let Some((entry, next_offset)) = root_inode.readdir(offset) {
+        assert!(next_offset > offset);
+        entries.push(entry);
+        offset = next_offset;
+    }
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, vec!["churn0", "churn2", "shared"]);
+    assert_eq!(names, root_inode.ls());
+    let churn2 = root_inode.find_node("churn2").unwrap().0;
+    assert_eq!(entries[1].inode_id, churn2);
+    assert!(entries
+        .iter()
+        .all(|entry| entry.inode_type == DiskInodeType::File));
+    assert!(root_inode.readdir(offset).is_none());
+}
+
+#[test]
+fn stat_reports_size_blocks_and_links() {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("file").unwrap();
+    file.write_at(0, &[1u8; 40 * BLOCK_SZ]);
+    // stat reports the logical size, allocated blocks and links of an inode
+    root_inode.link("alias", "file").unwrap();
+    let stat = root_inode.find("alias").unwrap().stat();
+    assert_eq!(stat.inode_id, file.inode_id());
+    assert_eq!(stat.size as usize, 40 * BLOCK_SZ);
+    // 40 data blocks and an indirect block
+    assert_eq!(stat.blocks, 41);
+    assert_eq!(stat.nlink, 2);
+    let stat = root_inode.stat();
+    assert_eq!((stat.inode_id, stat.nlink), (0, 1));
+    assert_eq!(stat.inode_type, DiskInodeType::Directory);
+}
+
+#[test]
+fn concurrent_writers_share_a_handle_and_keep_their_blocks() {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode.create("file").unwrap();
+    root_inode.link("alias", "file").unwrap();
+    // users of one inode share a handle, and concurrent writers do not lose blocks
+    let handle = root_inode.find("file").unwrap();
+    assert!(Arc::ptr_eq(&handle, &root_inode.find("alias").unwrap()));
+    let mut writers = Vec::new();
+    for i in 0..4 {
+        let writer = root_inode.find("file").unwrap();
+        writers.push(std::thread::spawn(move || {
+            for chunk in 0..10 {
+                let offset = (chunk * 4 + i) * BLOCK_SZ;
+                assert_eq!(writer.write_at(offset, &[i as u8 + 1; BLOCK_SZ]), BLOCK_SZ);
+            }
+        }));
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert_eq!(handle.stat().size as usize, 40 * BLOCK_SZ);
+    for chunk in 0..40 {
+        let mut block = [0u8; BLOCK_SZ];
+        handle.read_at(chunk * BLOCK_SZ, &mut block);
+        assert_eq!(block, [(chunk % 4) as u8 + 1; BLOCK_SZ]);
+    }
+}
+
+#[test]
+fn independent_files_are_written_in_parallel() {
+    let efs = EasyFileSystem::create(mem_device(), BLOCKS, 1);
+    let root_inode = Arc::new(EasyFileSystem::root_inode(&efs));
+    let handle = root_inode.create("shared").unwrap();
+    for chunk in 0..40 {
+        handle.write_at(chunk * BLOCK_SZ, &[(chunk % 4) as u8 + 1; BLOCK_SZ]);
+    }
+    // independent files are written and read in parallel with a shared reader
+    let mut workers = Vec::new();
+    for i in 0..4u8 {
+        let root_inode = Arc::clone(&root_inode);
+        let reader = Arc::clone(&handle);
+        workers.push(std::thread::spawn(move || {
+            let file = root_inode.create(&format!("parallel{}", i)).unwrap();
+            let data: Vec<u8> = (0..6000).map(|j| (j as u8).wrapping_mul(i + 1)).collect();
+            for round in 0..10 {
+                assert_eq!(file.write_at(round * data.len(), &data), data.len());
+                let mut block = [0u8; BLOCK_SZ];
+                reader.read_at(round * BLOCK_SZ, &mut block);
+                assert_eq!(block, [(round % 4) as u8 + 1; BLOCK_SZ]);
+            }
+            let mut read_back = vec![0u8; data.len()];
+            for round in 0..10 {
+                assert_eq!(file.read_at(round * data.len(), &mut read_back), data.len());
+                assert_eq!(read_back, data);
+            }
+        }));
+    }
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    assert_eq!(root_inode.find("parallel3").unwrap().stat().size, 60000);
+}
@@ -16,7 +16,7 @@ pub struct Bitmap {
 /// Decompose bits into (block_pos, bits64_pos, inner_pos)
 fn decomposition(mut bit: usize) -> (usize, usize, usize) {
     let block_pos = bit / BLOCK_BITS;
-    bit = bit % BLOCK_BITS;
+    bit %= BLOCK_BITS;
     (block_pos, bit / 64, bit % 64)
 }
 
@@ -56,6 +56,45 @@ impl Bitmap {
         }
         None
     }
+    /// Allocate `count` contiguous blocks among the first `limit` ones,
+    /// return the first of them
+    pub fn alloc_contiguous(
+        &self,
+        block_device: &Arc<dyn BlockDevice>,
+        count: usize,
+        limit: usize,
+    ) -> Option<usize> {
+        let mut run_start = 0;
+        for block_pos in 0..self.blocks {
+            let bitmap_block =
+                get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |bitmap_block: &BitmapBlock| *bitmap_block);
+            for inner_bit in 0..BLOCK_BITS {
+                let bit = block_pos * BLOCK_BITS + inner_bit;
+                if bit >= limit {
+                    return None;
+                }
+                if bitmap_block[inner_bit / 64] & (1u64 << (inner_bit % 64)) != 0 {
+                    run_start = bit + 1;
+                } else if bit + 1 - run_start == count {
+                    (run_start..=bit).for_each(|bit| self.set(block_device, bit));
+                    return Some(run_start);
+                }
+            }
+        }
+        None
+    }
+    /// Mark a free block as allocated
+    fn set(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .modify(0, |bitmap_block: &mut BitmapBlock| {
+                assert!(bitmap_block[bits64_pos] & (1u64 << inner_pos) == 0);
+                bitmap_block[bits64_pos] |= 1u64 << inner_pos;
+            });
+    }
     /// Deallocate a block
     pub fn dealloc(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) {
         let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
@@ -66,6 +105,30 @@ impl Bitmap {
                 bitmap_block[bits64_pos] -= 1u64 << inner_pos;
             });
     }
+    /// Whether a block is allocated
+    pub fn is_allocated(&self, block_device: &Arc<dyn BlockDevice>, bit: usize) -> bool {
+        let (block_pos, bits64_pos, inner_pos) = decomposition(bit);
+        get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+            .lock()
+            .read(0, |bitmap_block: &BitmapBlock| {
+                bitmap_block[bits64_pos] & (1u64 << inner_pos) != 0
+            })
+    }
+    /// Count the allocated blocks
+    pub fn count_allocated(&self, block_device: &Arc<dyn BlockDevice>) -> usize {
+        (0..self.blocks)
+            .map(|block_pos| {
+                get_block_cache(block_pos + self.start_block_id, Arc::clone(block_device))
+                    .lock()
+                    .read(0, |bitmap_block: &BitmapBlock| {
+                        bitmap_block
+                            .iter()
+                            .map(|bits64| bits64.count_ones() as usize)
+                            .sum::<usize>()
+                    })
+            })
+            .sum()
+    }
     /// Get the max number of allocatable blocks
     pub fn maximum(&self) -> usize {
         self.blocks * BLOCK_BITS
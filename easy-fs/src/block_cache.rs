@@ -1,6 +1,7 @@
 use super::{BlockDevice, BLOCK_SZ};
 use alloc::collections::VecDeque;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use lazy_static::*;
 use spin::Mutex;
 
@@ -79,8 +80,17 @@ impl Drop for BlockCache {
 /// Use a block cache of 16 blocks
 const BLOCK_CACHE_SIZE: usize = 16;
 
+/// Identify a cached block by its id and the address of its device,
+/// so that blocks of different devices are cached apart
+type BlockKey = (usize, usize);
+
+/// Get the key of a block of a device
+fn block_key(block_id: usize, block_device: &Arc<dyn BlockDevice>) -> BlockKey {
+    (block_id, Arc::as_ptr(block_device) as *const () as usize)
+}
+
 pub struct BlockCacheManager {
-    queue: VecDeque<(usize, Arc<Mutex<BlockCache>>)>,
+    queue: VecDeque<(BlockKey, Arc<Mutex<BlockCache>>)>,
 }
 
 impl BlockCacheManager {
@@ -95,12 +105,14 @@ impl BlockCacheManager {
         block_id: usize,
         block_device: Arc<dyn BlockDevice>,
     ) -> Arc<Mutex<BlockCache>> {
-        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == block_id) {
+        let key = block_key(block_id, &block_device);
+        if let Some(pair) = self.queue.iter().find(|pair| pair.0 == key) {
             Arc::clone(&pair.1)
         } else {
             // substitute
-            if self.queue.len() == BLOCK_CACHE_SIZE {
-                // from front to tail
+            if self.queue.len() >= BLOCK_CACHE_SIZE {
+                // from front to tail, when every block is held by a concurrent user
+                // the cache grows beyond its size until some are released
                 if let Some((idx, _)) = self
                     .queue
                     .iter()
@@ -108,8 +120,6 @@ impl BlockCacheManager {
                     .find(|(_, pair)| Arc::strong_count(&pair.1) == 1)
                 {
                     self.queue.drain(idx..=idx);
-                } else {
-                    panic!("Run out of BlockCache!");
                 }
             }
             // load block into mem and push back
@@ -117,7 +127,7 @@ impl BlockCacheManager {
                 block_id,
                 Arc::clone(&block_device),
             )));
-            self.queue.push_back((block_id, Arc::clone(&block_cache)));
+            self.queue.push_back((key, Arc::clone(&block_cache)));
             block_cache
         }
     }
@@ -142,8 +152,14 @@ pub fn get_block_cache(
 
 /// Sync all block cache to block device
 pub fn block_cache_sync_all() {
-    let manager = BLOCK_CACHE_MANAGER.lock();
-    for (_, cache) in manager.queue.iter() {
+    // the manager is released first, as a holder of a block may be waiting for it
+    let caches: Vec<Arc<Mutex<BlockCache>>> = BLOCK_CACHE_MANAGER
+        .lock()
+        .queue
+        .iter()
+        .map(|(_, cache)| Arc::clone(cache))
+        .collect();
+    for cache in caches {
         cache.lock().sync();
     }
 }
@@ -0,0 +1,181 @@
+//! LZ4-style compression of file data in clusters of blocks
+//!
+//! Compressed file data starts with the uncompressed size, followed by the end offset of
+//! every cluster relative to the end of this table, and then the clusters themselves.
+//! A cluster that does not shrink is stored as is, which is told by its stored length
+//! being equal to its uncompressed length.
+
+use super::BLOCK_SZ;
+use alloc::vec::Vec;
+
+/// Number of uncompressed bytes in a cluster
+pub const CLUSTER_SZ: usize = 4 * BLOCK_SZ;
+/// Shortest match worth encoding
+const MIN_MATCH: usize = 4;
+/// No match may start within the last bytes of the input
+const MF_LIMIT: usize = 12;
+/// The input always ends with some literals
+const LAST_LITERALS: usize = 5;
+/// Farthest distance a match may refer back to
+const MAX_OFFSET: usize = u16::MAX as usize;
+/// log2 of the number of entries of the match finder hash table
+const HASH_LOG: usize = 12;
+
+/// Get the number of clusters of data of the given size
+pub fn cluster_count(size: usize) -> usize {
+    (size + CLUSTER_SZ - 1) / CLUSTER_SZ
+}
+
+/// Get the uncompressed length of a cluster
+pub fn cluster_len(cluster: usize, size: usize) -> usize {
+    CLUSTER_SZ.min(size - cluster * CLUSTER_SZ)
+}
+
+/// Get the offset of the first cluster in compressed data of the given size
+pub fn clusters_start(size: usize) -> usize {
+    4 * (1 + cluster_count(size))
+}
+
+/// Compress file data cluster by cluster
+pub fn compress_clusters(data: &[u8]) -> Vec<u8> {
+    let mut table: Vec<u8> = Vec::new();
+    let mut clusters: Vec<u8> = Vec::new();
+    table.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    for cluster in data.chunks(CLUSTER_SZ) {
+        let compressed = compress(cluster);
+        if compressed.len() < cluster.len() {
+            clusters.extend_from_slice(&compressed);
+        } else {
+            clusters.extend_from_slice(cluster);
+        }
+        table.extend_from_slice(&(clusters.len() as u32).to_le_bytes());
+    }
+    table.extend_from_slice(&clusters);
+    table
+}
+
+/// Restore a cluster from its stored bytes, return None if they are corrupted
+pub fn decompress_cluster(stored: &[u8], len: usize) -> Option<Vec<u8>> {
+    if stored.len() == len {
+        return Some(stored.to_vec());
+    }
+    let mut cluster = Vec::with_capacity(len);
+    decompress(stored, &mut cluster)?;
+    if cluster.len() == len {
+        Some(cluster)
+    } else {
+        None
+    }
+}
+
+fn read_u32(src: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([src[i], src[i + 1], src[i + 2], src[i + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+fn push_len(dst: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        dst.push(255);
+        len -= 255;
+    }
+    dst.push(len as u8);
+}
+
+/// Emit a sequence of literals followed by a match, or only literals if `match_len` is 0
+fn push_sequence(dst: &mut Vec<u8>, literals: &[u8], offset: usize, match_len: usize) {
+    let match_len = match_len.saturating_sub(MIN_MATCH);
+    let token = (literals.len().min(15) << 4) | match_len.min(15);
+    dst.push(token as u8);
+    if literals.len() >= 15 {
+        push_len(dst, literals.len() - 15);
+    }
+    dst.extend_from_slice(literals);
+    if offset == 0 {
+        return;
+    }
+    dst.extend_from_slice(&(offset as u16).to_le_bytes());
+    if match_len >= 15 {
+        push_len(dst, match_len - 15);
+    }
+}
+
+/// Compress a buffer into the LZ4 block format
+pub fn compress(src: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::new();
+    // position + 1 of the last occurrence of a hash, 0 if there is none
+    let mut table = alloc::vec![0u32; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut i = 0;
+    while i + MF_LIMIT <= src.len() {
+        let sequence = read_u32(src, i);
+        let h = hash(sequence);
+        let candidate = table[h] as usize;
+        table[h] = i as u32 + 1;
+        if candidate == 0
+            || i - (candidate - 1) > MAX_OFFSET
+            || read_u32(src, candidate - 1) != sequence
+        {
+            i += 1;
+            continue;
+        }
+        let start = candidate - 1;
+        let mut len = MIN_MATCH;
+        while i + len < src.len() - LAST_LITERALS && src[start + len] == src[i + len] {
+            len += 1;
+        }
+        push_sequence(&mut dst, &src[anchor..i], i - start, len);
+        i += len;
+        anchor = i;
+    }
+    push_sequence(&mut dst, &src[anchor..], 0, 0);
+    dst
+}
+
+fn read_len(src: &[u8], i: &mut usize) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let byte = *src.get(*i)?;
+        *i += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+/// Decompress a buffer in the LZ4 block format, return None if it is corrupted
+pub fn decompress(src: &[u8], dst: &mut Vec<u8>) -> Option<()> {
+    let mut i = 0;
+    while i < src.len() {
+        let token = src[i];
+        i += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_len(src, &mut i)?;
+        }
+        dst.extend_from_slice(src.get(i..i + literals)?);
+        i += literals;
+        // the last sequence has no match
+        if i == src.len() {
+            break;
+        }
+        let offset = u16::from_le_bytes([*src.get(i)?, *src.get(i + 1)?]) as usize;
+        i += 2;
+        if offset == 0 || offset > dst.len() {
+            return None;
+        }
+        let mut match_len = (token & 0xf) as usize;
+        if match_len == 15 {
+            match_len += read_len(src, &mut i)?;
+        }
+        // a match may overlap the bytes it produces
+        let start = dst.len() - offset;
+        for k in 0..match_len + MIN_MATCH {
+            dst.push(dst[start + k]);
+        }
+    }
+    Some(())
+}
@@ -0,0 +1,152 @@
+//! AES-256-XTS encryption of file data and names
+//!
+//! Every data block is encrypted on its own, with a tweak made of the nonce of its inode
+//! and its index in the inode. Names are padded to the full name field and encrypted with
+//! the nonce of their directory, so encrypting the same name again finds its entry.
+
+use super::{RawName, BLOCK_SZ};
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::{Aes256, Block};
+
+/// Size of a key, two AES-256 keys for data and tweaks
+pub const KEY_SZ: usize = 64;
+/// Size of an AES block
+const AES_BLOCK_SZ: usize = 16;
+/// Index used in the tweak of names, which no data block reaches
+const NAME_INDEX: u32 = u32::MAX;
+
+/// A loaded AES-256-XTS key
+pub struct XtsKey {
+    data: Aes256,
+    tweak: Aes256,
+}
+
+impl XtsKey {
+    /// Load a key
+    pub fn new(key: &[u8; KEY_SZ]) -> Self {
+        Self {
+            data: Aes256::new(GenericArray::from_slice(&key[..KEY_SZ / 2])),
+            tweak: Aes256::new(GenericArray::from_slice(&key[KEY_SZ / 2..])),
+        }
+    }
+    /// Get the id of the key, derived from the key itself
+    /// so that data is never decrypted with a different key
+    pub fn id(&self) -> u32 {
+        let mut block = Block::default();
+        self.data.encrypt_block(&mut block);
+        u32::from_le_bytes([block[0], block[1], block[2], block[3]])
+    }
+    /// Whether this is the same key as `other`, told by encrypting a zero block
+    /// with both of its AES keys
+    pub fn same_key(&self, other: &Self) -> bool {
+        let fingerprint = |key: &Self| {
+            let (mut data, mut tweak) = (Block::default(), Block::default());
+            key.data.encrypt_block(&mut data);
+            key.tweak.encrypt_block(&mut tweak);
+            (data, tweak)
+        };
+        fingerprint(self) == fingerprint(other)
+    }
+    /// Encrypt a data block
+    pub fn encrypt_block(&self, block: &mut [u8; BLOCK_SZ], nonce: u32, index: u32) {
+        self.encrypt(block, nonce, index);
+    }
+    /// Decrypt a data block
+    pub fn decrypt_block(&self, block: &mut [u8; BLOCK_SZ], nonce: u32, index: u32) {
+        self.decrypt(block, nonce, index);
+    }
+    /// Encrypt a name padded with zeros
+    pub fn encrypt_name(&self, name: &mut RawName, nonce: u32) {
+        self.encrypt(name, nonce, NAME_INDEX);
+    }
+    /// Decrypt a name, which is then padded with zeros
+    pub fn decrypt_name(&self, name: &mut RawName, nonce: u32) {
+        self.decrypt(name, nonce, NAME_INDEX);
+    }
+    fn initial_tweak(&self, nonce: u32, index: u32) -> Block {
+        let mut tweak = Block::default();
+        tweak[..4].copy_from_slice(&nonce.to_le_bytes());
+        tweak[4..8].copy_from_slice(&index.to_le_bytes());
+        self.tweak.encrypt_block(&mut tweak);
+        tweak
+    }
+    fn encrypt_aes_block(&self, chunk: &mut [u8], tweak: &Block) {
+        xor(chunk, tweak);
+        self.data.encrypt_block(GenericArray::from_mut_slice(chunk));
+        xor(chunk, tweak);
+    }
+    fn decrypt_aes_block(&self, chunk: &mut [u8], tweak: &Block) {
+        xor(chunk, tweak);
+        self.data.decrypt_block(GenericArray::from_mut_slice(chunk));
+        xor(chunk, tweak);
+    }
+    /// Encrypt data of at least one AES block, stealing ciphertext for a partial last block
+    fn encrypt(&self, data: &mut [u8], nonce: u32, index: u32) {
+        assert!(data.len() >= AES_BLOCK_SZ);
+        let mut tweak = self.initial_tweak(nonce, index);
+        let rem = data.len() % AES_BLOCK_SZ;
+        let full = data.len() / AES_BLOCK_SZ - (rem != 0) as usize;
+        for chunk in data.chunks_exact_mut(AES_BLOCK_SZ).take(full) {
+            self.encrypt_aes_block(chunk, &tweak);
+            mul_alpha(&mut tweak);
+        }
+        if rem == 0 {
+            return;
+        }
+        let (head, tail) = data[full * AES_BLOCK_SZ..].split_at_mut(AES_BLOCK_SZ);
+        self.encrypt_aes_block(head, &tweak);
+        mul_alpha(&mut tweak);
+        let mut last = [0u8; AES_BLOCK_SZ];
+        last[..rem].copy_from_slice(tail);
+        last[rem..].copy_from_slice(&head[rem..]);
+        tail.copy_from_slice(&head[..rem]);
+        self.encrypt_aes_block(&mut last, &tweak);
+        head.copy_from_slice(&last);
+    }
+    /// Decrypt data encrypted by `encrypt`
+    fn decrypt(&self, data: &mut [u8], nonce: u32, index: u32) {
+        assert!(data.len() >= AES_BLOCK_SZ);
+        let mut tweak = self.initial_tweak(nonce, index);
+        let rem = data.len() % AES_BLOCK_SZ;
+        let full = data.len() / AES_BLOCK_SZ - (rem != 0) as usize;
+        for chunk in data.chunks_exact_mut(AES_BLOCK_SZ).take(full) {
+            self.decrypt_aes_block(chunk, &tweak);
+            mul_alpha(&mut tweak);
+        }
+        if rem == 0 {
+            return;
+        }
+        // the stolen block was encrypted with the later tweak
+        let mut next_tweak = tweak;
+        mul_alpha(&mut next_tweak);
+        let (head, tail) = data[full * AES_BLOCK_SZ..].split_at_mut(AES_BLOCK_SZ);
+        self.decrypt_aes_block(head, &next_tweak);
+        let mut last = [0u8; AES_BLOCK_SZ];
+        last[..rem].copy_from_slice(tail);
+        last[rem..].copy_from_slice(&head[rem..]);
+        tail.copy_from_slice(&head[..rem]);
+        self.decrypt_aes_block(&mut last, &tweak);
+        head.copy_from_slice(&last);
+    }
+}
+
+fn xor(chunk: &mut [u8], tweak: &Block) {
+    chunk
+        .iter_mut()
+        .zip(tweak.iter())
+        .for_each(|(a, b)| *a ^= b);
+}
+
+/// Multiply a tweak by the primitive element of GF(2^128)
+fn mul_alpha(tweak: &mut Block) {
+    let mut carry = 0u8;
+    for byte in tweak.iter_mut() {
+        let next_carry = *byte >> 7;
+        *byte = (*byte << 1) | carry;
+        carry = next_carry;
+    }
+    if carry != 0 {
+        tweak[0] ^= 0x87;
+    }
+}
@@ -1,49 +1,131 @@
 use super::{
-    block_cache_sync_all, get_block_cache, Bitmap, BlockDevice, DiskInode, DiskInodeType, Inode,
-    SuperBlock,
+    block_cache_sync_all, get_block_cache, Bitmap, BlockDevice, DataRegion, DirEntry, DiskInode,
+    DiskInodeType, Inode, InodeMapEntry, Quota, SuperBlock, DIRENT_SZ, INODES_PER_BLOCK,
+    INODE_MAP_ENTRY_SZ, MAX_GROWN_REGIONS, MAX_QUOTA_UID, NAME_LENGTH_LIMIT, QUOTA_SZ, ROOT_UID,
 };
+use crate::crypt::{XtsKey, KEY_SZ};
 use crate::BLOCK_SZ;
-use alloc::sync::Arc;
-use spin::Mutex;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::{Mutex, RwLock};
 
 /// An easy fs over a block device
 pub struct EasyFileSystem {
     pub block_device: Arc<dyn BlockDevice>,
     pub inode_bitmap: Bitmap,
-    pub data_bitmap: Bitmap,
     inode_area_start_block: u32,
-    data_area_start_block: u32,
+    /// Data areas with their bitmaps, more are added as the filesystem grows
+    data_regions: Vec<DataRegion>,
+    total_blocks: u32,
+    refcount_inode: u32,
+    snapshot_inode: u32,
+    quota_inode: u32,
+    inode_map_inode: u32,
+    /// Blocks of inodes beyond the inode area, inode `inode_bitmap.maximum() + i`
+    /// is in block `inode_map[i / INODES_PER_BLOCK]`
+    inode_map: Vec<InodeMapEntry>,
+    /// Keys loaded to access encrypted inodes with the uid which loaded them, by key id
+    keys: BTreeMap<u32, (Arc<XtsKey>, u32)>,
+    /// Handles of the inodes in use, so that users of an inode share one handle
+    inode_cache: BTreeMap<u32, Weak<Inode>>,
+    /// Held for reading by each operation of an inode handle and for writing by the
+    /// operations relocating or copying the blocks of the whole filesystem
+    quiesce: Arc<RwLock<()>>,
+}
+
+/// How scattered the data blocks of the live files are
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Fragmentation {
+    /// Number of files and directories with data
+    pub files: u32,
+    /// Number of their data blocks
+    pub blocks: u32,
+    /// Number of runs of contiguous data blocks
+    pub extents: u32,
+}
+
+impl Fragmentation {
+    /// Percentage of boundaries between consecutive data blocks of a file
+    /// that are not contiguous on disk, 0 if every file is a single extent
+    pub fn score(&self) -> u32 {
+        let boundaries = self.blocks - self.files;
+        if boundaries == 0 {
+            return 0;
+        }
+        (self.extents - self.files) * 100 / boundaries
+    }
+}
+
+/// How much of the filesystem is in use
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Usage {
+    pub total_blocks: u32,
+    /// Number of blocks of the data areas
+    pub data_blocks: u32,
+    /// Number of free blocks of the data areas
+    pub free_blocks: u32,
+    /// Number of inodes of the inode area
+    pub area_inodes: u32,
+    /// Number of inodes in use, including those in blocks taken from the data areas
+    pub used_inodes: u32,
 }
 
 /// A data block of block size
 type DataBlock = [u8; BLOCK_SZ];
+/// Number of extra references to a data block, 0 for a block owned by a single inode
+type RefCount = u16;
+/// Size of a reference count entry
+const REFCOUNT_SZ: usize = core::mem::size_of::<RefCount>();
 
 impl EasyFileSystem {
+    /// Number of blocks of the inode area of a filesystem with `inode_bitmap_blocks`
+    /// blocks of inode bitmap
+    fn inode_area_blocks(inode_bitmap_blocks: u32) -> u32 {
+        let inode_num = Bitmap::new(1, inode_bitmap_blocks as usize).maximum();
+        ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32
+    }
+    /// Get the smallest number of blocks a filesystem with `inode_bitmap_blocks`
+    /// blocks of inode bitmap can be created on: the super block, the inode bitmap and area,
+    /// and a data bitmap with one data block
+    pub fn min_total_blocks(inode_bitmap_blocks: u32) -> u32 {
+        1 + inode_bitmap_blocks + Self::inode_area_blocks(inode_bitmap_blocks) + 2
+    }
     /// Create a filesystem from a block device
     pub fn create(
         block_device: Arc<dyn BlockDevice>,
         total_blocks: u32,
         inode_bitmap_blocks: u32,
     ) -> Arc<Mutex<Self>> {
+        assert!(total_blocks >= Self::min_total_blocks(inode_bitmap_blocks));
         // calculate block size of areas & create bitmaps
         let inode_bitmap = Bitmap::new(1, inode_bitmap_blocks as usize);
-        let inode_num = inode_bitmap.maximum();
-        let inode_area_blocks =
-            ((inode_num * core::mem::size_of::<DiskInode>() + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+        let inode_area_blocks = Self::inode_area_blocks(inode_bitmap_blocks);
         let inode_total_blocks = inode_bitmap_blocks + inode_area_blocks;
         let data_total_blocks = total_blocks - 1 - inode_total_blocks;
         let data_bitmap_blocks = (data_total_blocks + 4096) / 4097;
         let data_area_blocks = data_total_blocks - data_bitmap_blocks;
-        let data_bitmap = Bitmap::new(
-            (1 + inode_bitmap_blocks + inode_area_blocks) as usize,
-            data_bitmap_blocks as usize,
-        );
+        let data_region = DataRegion {
+            bitmap_start: 1 + inode_total_blocks,
+            bitmap_blocks: data_bitmap_blocks,
+            area_start: 1 + inode_total_blocks + data_bitmap_blocks,
+            area_blocks: data_area_blocks,
+        };
         let mut efs = Self {
             block_device: Arc::clone(&block_device),
             inode_bitmap,
-            data_bitmap,
             inode_area_start_block: 1 + inode_bitmap_blocks,
-            data_area_start_block: 1 + inode_total_blocks + data_bitmap_blocks,
+            data_regions: alloc::vec![data_region],
+            total_blocks,
+            refcount_inode: 0,
+            snapshot_inode: 0,
+            quota_inode: 0,
+            inode_map_inode: 0,
+            inode_map: Vec::new(),
+            keys: BTreeMap::new(),
+            inode_cache: BTreeMap::new(),
+            quiesce: Arc::new(RwLock::new(())),
         };
         // clear all blocks
         for i in 0..total_blocks {
@@ -55,6 +137,37 @@ impl EasyFileSystem {
                     }
                 });
         }
+        // write back immediately
+        // create a inode for root node "/"
+        assert_eq!(efs.alloc_inode(), Some(0));
+        let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
+        get_block_cache(root_inode_block_id as usize, Arc::clone(&block_device))
+            .lock()
+            .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
+                disk_inode.initialize(DiskInodeType::Directory);
+                // the root directory is linked as the root
+                disk_inode.nlink = 1;
+            });
+        // create a hidden inode for reference counts of data blocks
+        efs.refcount_inode = efs.alloc_inode().unwrap();
+        efs.modify_disk_inode(efs.refcount_inode, |disk_inode| {
+            disk_inode.initialize(DiskInodeType::File)
+        });
+        // create a hidden directory for snapshots
+        efs.snapshot_inode = efs.alloc_inode().unwrap();
+        efs.modify_disk_inode(efs.snapshot_inode, |disk_inode| {
+            disk_inode.initialize(DiskInodeType::Directory)
+        });
+        // create a hidden inode for quotas of uids
+        efs.quota_inode = efs.alloc_inode().unwrap();
+        efs.modify_disk_inode(efs.quota_inode, |disk_inode| {
+            disk_inode.initialize(DiskInodeType::File)
+        });
+        // create a hidden inode for the inode map
+        efs.inode_map_inode = efs.alloc_inode().unwrap();
+        efs.modify_disk_inode(efs.inode_map_inode, |disk_inode| {
+            disk_inode.initialize(DiskInodeType::File)
+        });
         // initialize SuperBlock
         get_block_cache(0, Arc::clone(&block_device)).lock().modify(
             0,
@@ -66,74 +179,328 @@ impl EasyFileSystem {
                     data_bitmap_blocks,
                     data_area_blocks,
                 );
+                super_block.refcount_inode = efs.refcount_inode;
+                super_block.snapshot_inode = efs.snapshot_inode;
+                super_block.quota_inode = efs.quota_inode;
+                super_block.inode_map_inode = efs.inode_map_inode;
             },
         );
-        // write back immediately
-        // create a inode for root node "/"
-        assert_eq!(efs.alloc_inode(), 0);
-        let (root_inode_block_id, root_inode_offset) = efs.get_disk_inode_pos(0);
-        get_block_cache(root_inode_block_id as usize, Arc::clone(&block_device))
-            .lock()
-            .modify(root_inode_offset, |disk_inode: &mut DiskInode| {
-                disk_inode.initialize(DiskInodeType::Directory);
-            });
         block_cache_sync_all();
         Arc::new(Mutex::new(efs))
     }
+    /// Whether a block device holds a filesystem, which can be opened
+    pub fn is_formatted(block_device: &Arc<dyn BlockDevice>) -> bool {
+        get_block_cache(0, Arc::clone(block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| super_block.is_valid())
+    }
     /// Open a block device as a filesystem
     pub fn open(block_device: Arc<dyn BlockDevice>) -> Arc<Mutex<Self>> {
         // read SuperBlock
-        get_block_cache(0, Arc::clone(&block_device))
-            .lock()
-            .read(0, |super_block: &SuperBlock| {
+        let mut efs = get_block_cache(0, Arc::clone(&block_device)).lock().read(
+            0,
+            |super_block: &SuperBlock| {
                 assert!(super_block.is_valid(), "Error loading EFS!");
-                let inode_total_blocks =
-                    super_block.inode_bitmap_blocks + super_block.inode_area_blocks;
-                let efs = Self {
+                Self {
                     block_device,
                     inode_bitmap: Bitmap::new(1, super_block.inode_bitmap_blocks as usize),
-                    data_bitmap: Bitmap::new(
-                        (1 + inode_total_blocks) as usize,
-                        super_block.data_bitmap_blocks as usize,
-                    ),
                     inode_area_start_block: 1 + super_block.inode_bitmap_blocks,
-                    data_area_start_block: 1 + inode_total_blocks + super_block.data_bitmap_blocks,
-                };
-                Arc::new(Mutex::new(efs))
-            })
+                    data_regions: super_block.data_regions(),
+                    total_blocks: super_block.total_blocks,
+                    refcount_inode: super_block.refcount_inode,
+                    snapshot_inode: super_block.snapshot_inode,
+                    quota_inode: super_block.quota_inode,
+                    inode_map_inode: super_block.inode_map_inode,
+                    inode_map: Vec::new(),
+                    keys: BTreeMap::new(),
+                    inode_cache: BTreeMap::new(),
+                    quiesce: Arc::new(RwLock::new(())),
+                }
+            },
+        );
+        // load the inode map
+        let entries = efs.read_disk_inode(efs.inode_map_inode, |disk_inode| disk_inode.size)
+            as usize
+            / INODE_MAP_ENTRY_SZ;
+        for index in 0..entries {
+            let mut entry = InodeMapEntry::default();
+            efs.read_table(
+                efs.inode_map_inode,
+                index * INODE_MAP_ENTRY_SZ,
+                entry.as_bytes_mut(),
+            );
+            efs.inode_map.push(entry);
+        }
+        Arc::new(Mutex::new(efs))
     }
-    /// Get the root inode of the filesystem
+    /// Get the root inode of the filesystem. The handle is not shared,
+    /// so keep a single one for as long as the filesystem is in use
     pub fn root_inode(efs: &Arc<Mutex<Self>>) -> Inode {
         let block_device = Arc::clone(&efs.lock().block_device);
+        let quiesce = Arc::clone(&efs.lock().quiesce);
         // acquire efs lock temporarily
         let (block_id, block_offset) = efs.lock().get_disk_inode_pos(0);
         // release efs lock
-        Inode::new(block_id, block_offset, Arc::clone(efs), block_device)
+        Inode::new(
+            block_id,
+            block_offset,
+            Arc::clone(efs),
+            block_device,
+            quiesce,
+        )
+    }
+    /// Check whether a handle of an inode is in use
+    pub fn is_open(&self, inode_id: u32) -> bool {
+        self.inode_cache
+            .get(&inode_id)
+            .map_or(false, |inode| inode.strong_count() > 0)
+    }
+    /// Check whether a handle of an inode other than the root directory is in use
+    pub fn has_open_inodes(&self) -> bool {
+        self.inode_cache
+            .iter()
+            .any(|(inode_id, inode)| *inode_id != 0 && inode.strong_count() > 0)
+    }
+    /// Get the handle of an inode of `efs`, which is this filesystem.
+    /// A handle in use is shared, otherwise a new one is created
+    pub fn get_inode(
+        &mut self,
+        efs: &Arc<Mutex<Self>>,
+        inode_id: u32,
+        read_only: bool,
+    ) -> Arc<Inode> {
+        if let Some(inode) = self.inode_cache.get(&inode_id).and_then(Weak::upgrade) {
+            return inode;
+        }
+        self.inode_cache.retain(|_, inode| inode.strong_count() > 0);
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        let mut inode = Inode::new(
+            block_id,
+            block_offset,
+            Arc::clone(efs),
+            Arc::clone(&self.block_device),
+            Arc::clone(&self.quiesce),
+        );
+        if read_only {
+            inode = inode.into_read_only();
+        }
+        let inode = Arc::new(inode);
+        self.inode_cache.insert(inode_id, Arc::downgrade(&inode));
+        inode
     }
     /// Get inode by id
     pub fn get_disk_inode_pos(&self, inode_id: u32) -> (u32, usize) {
         let inode_size = core::mem::size_of::<DiskInode>();
-        let inodes_per_block = (BLOCK_SZ / inode_size) as u32;
-        let block_id = self.inode_area_start_block + inode_id / inodes_per_block;
+        let area_inodes = self.inode_bitmap.maximum() as u32;
+        let block_id = if inode_id < area_inodes {
+            self.inode_area_start_block + inode_id / INODES_PER_BLOCK
+        } else {
+            self.inode_map[((inode_id - area_inodes) / INODES_PER_BLOCK) as usize].block_id
+        };
         (
             block_id,
-            (inode_id % inodes_per_block) as usize * inode_size,
+            (inode_id % INODES_PER_BLOCK) as usize * inode_size,
         )
     }
+    /// Get the id of the inode at a position
+    pub fn get_inode_id(&self, block_id: u32, block_offset: usize) -> u32 {
+        let inode_size = core::mem::size_of::<DiskInode>();
+        let area_inodes = self.inode_bitmap.maximum() as u32;
+        let block_index = if block_id < self.inode_area_start_block + area_inodes / INODES_PER_BLOCK
+        {
+            block_id - self.inode_area_start_block
+        } else {
+            let index = self
+                .inode_map
+                .iter()
+                .position(|entry| entry.block_id == block_id)
+                .unwrap() as u32;
+            area_inodes / INODES_PER_BLOCK + index
+        };
+        block_index * INODES_PER_BLOCK + (block_offset / inode_size) as u32
+    }
     /// Get data block by id
-    pub fn get_data_block_id(&self, data_block_id: u32) -> u32 {
-        self.data_area_start_block + data_block_id
+    pub fn get_data_block_id(&self, mut data_block_id: u32) -> u32 {
+        for region in self.data_regions.iter() {
+            if data_block_id < region.area_blocks {
+                return region.area_start + data_block_id;
+            }
+            data_block_id -= region.area_blocks;
+        }
+        panic!("data block out of range");
+    }
+    /// Get the number of blocks of the filesystem
+    pub fn total_blocks(&self) -> u32 {
+        self.total_blocks
+    }
+    /// Write all modified cached blocks back to their devices
+    pub fn sync(&self) {
+        block_cache_sync_all();
+    }
+    /// Read a block of the device through the block cache,
+    /// so that blocks not written back yet are read as they are
+    pub fn read_block(&self, block_id: u32, buf: &mut [u8; BLOCK_SZ]) {
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |data_block: &DataBlock| buf.copy_from_slice(data_block));
+    }
+    /// Get a copy of the super block
+    pub fn super_block(&self) -> SuperBlock {
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |super_block: &SuperBlock| *super_block)
+    }
+    /// Count the blocks and inodes in use
+    pub fn usage(&self) -> Usage {
+        let mut usage = Usage {
+            total_blocks: self.total_blocks,
+            area_inodes: self.inode_bitmap.maximum() as u32,
+            used_inodes: self.inode_bitmap.count_allocated(&self.block_device) as u32,
+            ..Usage::default()
+        };
+        for region in self.data_regions.iter() {
+            let bitmap = Bitmap::new(region.bitmap_start as usize, region.bitmap_blocks as usize);
+            usage.data_blocks += region.area_blocks;
+            usage.free_blocks +=
+                region.area_blocks - bitmap.count_allocated(&self.block_device) as u32;
+        }
+        usage.used_inodes += self
+            .inode_map
+            .iter()
+            .map(|entry| entry.used.count_ones())
+            .sum::<u32>();
+        usage
+    }
+    /// Allocate a new inode, taking a block of inodes from the data area
+    /// once the inode area is full. Fail if the data area is full as well
+    pub fn alloc_inode(&mut self) -> Option<u32> {
+        if let Some(inode_id) = self.inode_bitmap.alloc(&self.block_device) {
+            return Some(inode_id as u32);
+        }
+        let (index, new_block) = match self
+            .inode_map
+            .iter()
+            .position(|entry| entry.block_id != 0 && !entry.is_full())
+        {
+            Some(index) => (index, false),
+            None => {
+                // freed data blocks are zeroed, so are the inodes of the new block
+                let block_id = self.try_alloc_data()?;
+                let index = match self.inode_map.iter().position(|entry| entry.block_id == 0) {
+                    Some(index) => index,
+                    None => {
+                        self.inode_map.push(InodeMapEntry::default());
+                        self.inode_map.len() - 1
+                    }
+                };
+                self.inode_map[index].block_id = block_id;
+                (index, true)
+            }
+        };
+        let entry = &mut self.inode_map[index];
+        let inner_id = (!entry.used).trailing_zeros();
+        entry.used |= 1 << inner_id;
+        if self.write_inode_map_entry(index).is_none() {
+            // the map could not grow, give the inode and its new block back
+            let entry = &mut self.inode_map[index];
+            entry.used &= !(1 << inner_id);
+            if new_block {
+                let block_id = entry.block_id;
+                entry.block_id = 0;
+                self.dealloc_data(block_id);
+            }
+            return None;
+        }
+        Some(self.inode_bitmap.maximum() as u32 + index as u32 * INODES_PER_BLOCK + inner_id)
     }
-    /// Allocate a new inode
-    pub fn alloc_inode(&mut self) -> u32 {
-        self.inode_bitmap.alloc(&self.block_device).unwrap() as u32
+    /// Deallocate an inode, a block of inodes from the data area
+    /// is released with its last inode
+    pub fn dealloc_inode(&mut self, inode_id: u32) {
+        // a later inode with this id may be elsewhere
+        self.inode_cache.remove(&inode_id);
+        let area_inodes = self.inode_bitmap.maximum() as u32;
+        if inode_id < area_inodes {
+            self.inode_bitmap
+                .dealloc(&self.block_device, inode_id as usize);
+            return;
+        }
+        let index = ((inode_id - area_inodes) / INODES_PER_BLOCK) as usize;
+        let entry = &mut self.inode_map[index];
+        assert!(entry.used & (1 << (inode_id % INODES_PER_BLOCK)) != 0);
+        entry.used &= !(1 << (inode_id % INODES_PER_BLOCK));
+        if entry.used == 0 {
+            let block_id = entry.block_id;
+            entry.block_id = 0;
+            self.dealloc_data(block_id);
+        }
+        // the entry was written when the inode was allocated, so the table does not grow
+        self.write_inode_map_entry(index).unwrap();
+    }
+    /// Write an entry of the inode map back to its hidden inode,
+    /// fail if the map has to grow and the device is full
+    fn write_inode_map_entry(&mut self, index: usize) -> Option<()> {
+        let entry = self.inode_map[index];
+        self.write_table(
+            self.inode_map_inode,
+            index * INODE_MAP_ENTRY_SZ,
+            entry.as_bytes(),
+        )
     }
     /// Allocate a data block
     pub fn alloc_data(&mut self) -> u32 {
-        self.data_bitmap.alloc(&self.block_device).unwrap() as u32 + self.data_area_start_block
+        self.try_alloc_data().unwrap()
+    }
+    /// Allocate `count` data blocks, contiguous if a free run is long enough.
+    /// Nothing is allocated if there are not enough free blocks
+    pub fn alloc_data_blocks(&mut self, count: u32) -> Option<Vec<u32>> {
+        // a run of no blocks would be searched for through the whole bitmap
+        if count == 0 {
+            return Some(Vec::new());
+        }
+        if let Some(start) = self.alloc_contiguous_data(count) {
+            return Some((start..start + count).collect());
+        }
+        self.try_alloc_data_blocks(count)
     }
-    /// Deallocate a data block
+    /// Allocate `count` data blocks one by one, wherever they are free.
+    /// Nothing is allocated if there are not enough free blocks
+    pub fn try_alloc_data_blocks(&mut self, count: u32) -> Option<Vec<u32>> {
+        let mut blocks = Vec::new();
+        for _ in 0..count {
+            match self.try_alloc_data() {
+                Some(block_id) => blocks.push(block_id),
+                None => {
+                    blocks
+                        .into_iter()
+                        .for_each(|block_id| self.dealloc_data(block_id));
+                    return None;
+                }
+            }
+        }
+        Some(blocks)
+    }
+    /// Allocate a data block from the first region with a free one,
+    /// fail if the device is full
+    pub fn try_alloc_data(&mut self) -> Option<u32> {
+        self.data_regions.iter().find_map(|region| {
+            let bitmap = Bitmap::new(region.bitmap_start as usize, region.bitmap_blocks as usize);
+            let bit = bitmap.alloc(&self.block_device)?;
+            // the bitmap may cover more blocks than the area holds
+            if bit >= region.area_blocks as usize {
+                bitmap.dealloc(&self.block_device, bit);
+                return None;
+            }
+            Some(region.area_start + bit as u32)
+        })
+    }
+    /// Deallocate a data block, a shared block only loses one reference
     pub fn dealloc_data(&mut self, block_id: u32) {
+        let refs = self.data_refs(block_id);
+        if refs > 0 {
+            // the entry exists, so the table does not grow
+            self.set_data_refs(block_id, refs - 1).unwrap();
+            return;
+        }
         get_block_cache(block_id as usize, Arc::clone(&self.block_device))
             .lock()
             .modify(0, |data_block: &mut DataBlock| {
@@ -141,9 +508,748 @@ impl EasyFileSystem {
                     *p = 0;
                 })
             });
-        self.data_bitmap.dealloc(
-            &self.block_device,
-            (block_id - self.data_area_start_block) as usize,
+        let region = self
+            .data_regions
+            .iter()
+            .find(|region| region.contains(block_id))
+            .unwrap();
+        Bitmap::new(region.bitmap_start as usize, region.bitmap_blocks as usize)
+            .dealloc(&self.block_device, (block_id - region.area_start) as usize)
+    }
+    /// Grow the filesystem onto the blocks of a larger device up to `new_total_blocks`.
+    /// The last data area takes as many new blocks as its bitmap covers,
+    /// the others become a new data region with its own bitmap.
+    /// A single block left over is not used, as a region needs a bitmap block and a data
+    /// block. Fail if the device is known to hold fewer blocks
+    pub fn grow(&mut self, mut new_total_blocks: u32) -> Option<()> {
+        if self
+            .block_device
+            .num_blocks()
+            .map_or(false, |blocks| blocks < new_total_blocks as usize)
+        {
+            return None;
+        }
+        if new_total_blocks <= self.total_blocks {
+            return None;
+        }
+        let last = *self.data_regions.last().unwrap();
+        let bitmap_capacity = last.bitmap_blocks * BLOCK_SZ as u32 * 8;
+        let extended =
+            (new_total_blocks - self.total_blocks).min(bitmap_capacity - last.area_blocks);
+        let mut remaining = new_total_blocks - self.total_blocks - extended;
+        if remaining == 1 {
+            new_total_blocks -= 1;
+            remaining = 0;
+            if new_total_blocks == self.total_blocks {
+                return None;
+            }
+        }
+        // a new region needs a bitmap block and at least one data block
+        if remaining >= 2 && self.data_regions.len() > MAX_GROWN_REGIONS {
+            return None;
+        }
+        // new blocks are allocated as zeros
+        for block_id in self.total_blocks..new_total_blocks {
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .modify(0, |data_block: &mut DataBlock| data_block.fill(0));
+        }
+        self.data_regions.last_mut().unwrap().area_blocks += extended;
+        if remaining >= 2 {
+            let bitmap_blocks = (remaining + 4096) / 4097;
+            let bitmap_start = self.total_blocks + extended;
+            self.data_regions.push(DataRegion {
+                bitmap_start,
+                bitmap_blocks,
+                area_start: bitmap_start + bitmap_blocks,
+                area_blocks: remaining - bitmap_blocks,
+            });
+        }
+        self.total_blocks = new_total_blocks;
+        get_block_cache(0, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |super_block: &mut SuperBlock| {
+                super_block.set_data_regions(new_total_blocks, &self.data_regions)
+            });
+        block_cache_sync_all();
+        Some(())
+    }
+    /// Call a function over a disk inode to read it
+    pub fn read_disk_inode<V>(&self, inode_id: u32, f: impl FnOnce(&DiskInode) -> V) -> V {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(block_offset, f)
+    }
+    /// Call a function over a disk inode to modify it
+    pub fn modify_disk_inode<V>(&self, inode_id: u32, f: impl FnOnce(&mut DiskInode) -> V) -> V {
+        let (block_id, block_offset) = self.get_disk_inode_pos(inode_id);
+        get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(block_offset, f)
+    }
+    /// Read an entry of a table kept in a hidden inode,
+    /// entries beyond the end of the table read as zeros
+    fn read_table(&self, inode_id: u32, offset: usize, buf: &mut [u8]) {
+        self.read_disk_inode(inode_id, |disk_inode| {
+            disk_inode.read_at(offset, buf, &self.block_device)
+        });
+    }
+    /// Write an entry of a table kept in a hidden inode, growing the table as needed.
+    /// Fail if the device is full
+    fn write_table(&mut self, inode_id: u32, offset: usize, data: &[u8]) -> Option<()> {
+        let new_size = (offset + data.len()) as u32;
+        let blocks_needed = self.read_disk_inode(inode_id, |disk_inode| {
+            if new_size > disk_inode.size {
+                disk_inode.blocks_num_needed(new_size)
+            } else {
+                0
+            }
+        });
+        let new_blocks = self.try_alloc_data_blocks(blocks_needed)?;
+        self.modify_disk_inode(inode_id, |disk_inode| {
+            if new_size > disk_inode.size {
+                disk_inode.increase_size(new_size, new_blocks, &self.block_device);
+            }
+            disk_inode.write_at(offset, data, &self.block_device);
+        });
+        Some(())
+    }
+    /// Get the number of extra references to a data block
+    pub fn data_refs(&self, block_id: u32) -> RefCount {
+        let mut buf = [0u8; REFCOUNT_SZ];
+        // blocks beyond the table have never been shared
+        self.read_table(
+            self.refcount_inode,
+            block_id as usize * REFCOUNT_SZ,
+            &mut buf,
+        );
+        RefCount::from_le_bytes(buf)
+    }
+    /// Set the number of extra references to a data block, fail if the table
+    /// has to grow and the device is full
+    fn set_data_refs(&mut self, block_id: u32, refs: RefCount) -> Option<()> {
+        self.write_table(
+            self.refcount_inode,
+            block_id as usize * REFCOUNT_SZ,
+            &refs.to_le_bytes(),
         )
     }
+    /// Whether a data block can take another reference
+    pub fn can_share(&self, block_id: u32) -> bool {
+        self.data_refs(block_id) < RefCount::MAX
+    }
+    /// Add a reference to a data block, fail if it has as many as a count holds
+    pub fn share_data(&mut self, block_id: u32) -> Option<()> {
+        let refs = self.data_refs(block_id).checked_add(1)?;
+        self.set_data_refs(block_id, refs)
+    }
+    /// Map all data blocks of the disk inode at `src` into the empty disk inode at `dst`,
+    /// adding a reference to each of them. The compression and encryption policy is copied as well.
+    /// Fail without adding any reference if the device is full
+    /// or a block has as many references as a count holds
+    pub fn share_inode_data(&mut self, src: (u32, usize), dst: (u32, usize)) -> Option<()> {
+        let (size, policy, data_blocks) =
+            get_block_cache(src.0 as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(src.1, |disk_inode: &DiskInode| {
+                    let data_blocks: Vec<u32> = (0..disk_inode.data_blocks())
+                        .map(|inner_id| disk_inode.get_block_id(inner_id, &self.block_device))
+                        .collect();
+                    (disk_inode.size, disk_inode.data_policy(), data_blocks)
+                });
+        // check the counts and grow their table up front, so that sharing cannot fail midway
+        if !data_blocks.iter().all(|block_id| self.can_share(*block_id)) {
+            return None;
+        }
+        if let Some(last) = data_blocks.iter().max() {
+            self.set_data_refs(*last, self.data_refs(*last))?;
+        }
+        let indirect_count = DiskInode::total_blocks(size) - data_blocks.len() as u32;
+        let mut indirect_blocks = self.try_alloc_data_blocks(indirect_count)?.into_iter();
+        for block_id in data_blocks.iter() {
+            self.share_data(*block_id).unwrap();
+        }
+        let new_blocks =
+            DiskInode::build_block_list(&data_blocks, || indirect_blocks.next().unwrap());
+        get_block_cache(dst.0 as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(dst.1, |disk_inode: &mut DiskInode| {
+                disk_inode.increase_size(size, new_blocks, &self.block_device);
+                disk_inode.set_data_policy(policy);
+            });
+        Some(())
+    }
+    /// Get the quota and usage of a uid
+    pub fn quota(&self, uid: u32) -> Quota {
+        let mut quota = Quota::default();
+        // uids beyond the table have neither limits nor usage
+        if uid <= MAX_QUOTA_UID {
+            self.read_table(
+                self.quota_inode,
+                uid as usize * QUOTA_SZ,
+                quota.as_bytes_mut(),
+            );
+        }
+        quota
+    }
+    /// Update the quota record of a uid, fail if the uid has no record
+    /// or the table has to grow and the device is full
+    fn modify_quota(&mut self, uid: u32, f: impl FnOnce(&mut Quota)) -> Option<()> {
+        if uid > MAX_QUOTA_UID {
+            return None;
+        }
+        let mut quota = self.quota(uid);
+        f(&mut quota);
+        self.write_table(self.quota_inode, uid as usize * QUOTA_SZ, quota.as_bytes())
+    }
+    /// Set the block and inode limits of a uid, 0 for unlimited.
+    /// Fail if the uid is beyond `MAX_QUOTA_UID` or the device is full
+    pub fn set_quota(&mut self, uid: u32, block_limit: u32, inode_limit: u32) -> Option<()> {
+        self.modify_quota(uid, |quota| {
+            quota.block_limit = block_limit;
+            quota.inode_limit = inode_limit;
+        })?;
+        block_cache_sync_all();
+        Some(())
+    }
+    /// Charge data blocks to a uid, fail if it would exceed its block limit
+    /// or its record cannot be written
+    pub fn charge_blocks(&mut self, uid: u32, blocks: u32) -> bool {
+        // uids beyond the table are not accounted
+        if blocks == 0 || uid > MAX_QUOTA_UID {
+            return true;
+        }
+        if !self.quota(uid).allows_blocks(blocks) {
+            return false;
+        }
+        self.modify_quota(uid, |quota| quota.blocks_used += blocks)
+            .is_some()
+    }
+    /// Return data blocks charged to a uid
+    pub fn uncharge_blocks(&mut self, uid: u32, blocks: u32) {
+        if blocks == 0 {
+            return;
+        }
+        // nothing is charged to a uid without a record
+        let _ = self.modify_quota(uid, |quota| {
+            quota.blocks_used = quota.blocks_used.saturating_sub(blocks)
+        });
+    }
+    /// Charge an inode to a uid, fail if it would exceed its inode limit
+    /// or its record cannot be written
+    pub fn charge_inode(&mut self, uid: u32) -> bool {
+        // uids beyond the table are not accounted
+        if uid > MAX_QUOTA_UID {
+            return true;
+        }
+        if !self.quota(uid).allows_inode() {
+            return false;
+        }
+        self.modify_quota(uid, |quota| quota.inodes_used += 1)
+            .is_some()
+    }
+    /// Return an inode charged to a uid
+    pub fn uncharge_inode(&mut self, uid: u32) {
+        // nothing is charged to a uid without a record
+        let _ = self.modify_quota(uid, |quota| {
+            quota.inodes_used = quota.inodes_used.saturating_sub(1)
+        });
+    }
+    /// Recompute the usage of all uids from the live filesystem, limits are kept.
+    /// Snapshots are not charged to anyone
+    pub fn recount_quota(&mut self) {
+        let uids = self.read_disk_inode(self.quota_inode, |disk_inode| disk_inode.size) as usize
+            / QUOTA_SZ;
+        for uid in 0..uids as u32 {
+            // the records exist, so the table does not grow
+            self.modify_quota(uid, |quota| {
+                quota.blocks_used = 0;
+                quota.inodes_used = 0;
+            })
+            .unwrap();
+        }
+        for inode_id in self.live_inodes() {
+            let (uid, blocks) = self.read_disk_inode(inode_id, |disk_inode| {
+                (
+                    disk_inode.uid,
+                    disk_inode.allocated_blocks() + (disk_inode.xattr != 0) as u32,
+                )
+            });
+            // the root directory is not created by anyone
+            let inodes = (inode_id != 0) as u32;
+            // a uid whose record cannot be written on a full device stays unaccounted
+            let _ = self.modify_quota(uid, |quota| {
+                quota.blocks_used += blocks;
+                quota.inodes_used += inodes;
+            });
+        }
+    }
+    /// Get the ids of all inodes reachable from the root directory
+    fn live_inodes(&self) -> Vec<u32> {
+        self.reachable_inodes(0)
+    }
+    /// Get the ids of all inodes reachable from the directory `root_id`, itself included
+    fn reachable_inodes(&self, root_id: u32) -> Vec<u32> {
+        let mut visited = BTreeSet::new();
+        let mut stack = alloc::vec![root_id];
+        while let Some(inode_id) = stack.pop() {
+            if !visited.insert(inode_id) {
+                continue;
+            }
+            if self.read_disk_inode(inode_id, |disk_inode| disk_inode.is_dir()) {
+                stack.extend(
+                    self.dir_entries(inode_id)
+                        .into_iter()
+                        .map(|dirent| dirent.inode_number()),
+                );
+            }
+        }
+        visited.into_iter().collect()
+    }
+    /// Get the number of directory entries referring to an inode,
+    /// the root directory has an extra link as the root
+    pub fn link_count(&self, inode_id: u32) -> u32 {
+        self.read_disk_inode(inode_id, |disk_inode| disk_inode.nlink)
+    }
+    /// Get the data blocks of an inode in order, including reserved ones
+    fn inode_data_blocks(&self, inode_id: u32) -> Vec<u32> {
+        self.read_disk_inode(inode_id, |disk_inode| {
+            (0..disk_inode.mapped_blocks())
+                .map(|inner_id| disk_inode.get_block_id(inner_id, &self.block_device))
+                .collect()
+        })
+    }
+    /// Measure how scattered the data blocks of the live files and directories are
+    pub fn fragmentation(&self) -> Fragmentation {
+        let mut fragmentation = Fragmentation::default();
+        for inode_id in self.live_inodes() {
+            let data_blocks = self.inode_data_blocks(inode_id);
+            if data_blocks.is_empty() {
+                continue;
+            }
+            fragmentation.files += 1;
+            fragmentation.blocks += data_blocks.len() as u32;
+            fragmentation.extents += 1 + data_blocks
+                .windows(2)
+                .filter(|pair| pair[1] != pair[0] + 1)
+                .count() as u32;
+        }
+        fragmentation
+    }
+    /// Run `f` over the filesystem of `efs` once no operation of an inode handle is
+    /// in progress, none starts before it returns
+    fn quiesced<V>(efs: &Arc<Mutex<Self>>, f: impl FnOnce(&mut Self) -> V) -> V {
+        let quiesce = Arc::clone(&efs.lock().quiesce);
+        let _quiesced = quiesce.write();
+        let result = f(&mut efs.lock());
+        result
+    }
+    /// Relocate the data blocks of each live file and directory into a contiguous run,
+    /// return the number of inodes relocated. Inodes sharing blocks with clones or snapshots
+    /// are left in place, as are those for which no free run is long enough
+    /// or no blocks are left for the indirect blocks.
+    /// Operations of inodes wait until it is done
+    pub fn defragment(efs: &Arc<Mutex<Self>>) -> u32 {
+        Self::quiesced(efs, |fs| fs.defragment_live())
+    }
+    /// Relocate the data blocks of each live file and directory, see `defragment`
+    fn defragment_live(&mut self) -> u32 {
+        let mut relocated = 0;
+        for inode_id in self.live_inodes() {
+            if self.defragment_inode(inode_id).is_some() {
+                relocated += 1;
+            }
+        }
+        block_cache_sync_all();
+        relocated
+    }
+    /// Relocate the data blocks of an inode into a contiguous run.
+    /// Data is copied before the pointers are switched, and old blocks are released last
+    fn defragment_inode(&mut self, inode_id: u32) -> Option<()> {
+        let data_blocks = self.inode_data_blocks(inode_id);
+        if data_blocks.windows(2).all(|pair| pair[1] == pair[0] + 1)
+            || data_blocks
+                .iter()
+                .any(|block_id| self.data_refs(*block_id) > 0)
+        {
+            return None;
+        }
+        let start = self.alloc_contiguous_data(data_blocks.len() as u32)?;
+        let new_data_blocks: Vec<u32> = (start..start + data_blocks.len() as u32).collect();
+        // the indirect blocks are allocated up front, so that the inode is left alone
+        // if the device is full
+        let indirect_count =
+            DiskInode::build_block_list(&new_data_blocks, || 0).len() - new_data_blocks.len();
+        let mut indirect_blocks = match self.try_alloc_data_blocks(indirect_count as u32) {
+            Some(indirect_blocks) => indirect_blocks.into_iter(),
+            None => {
+                new_data_blocks
+                    .into_iter()
+                    .for_each(|block_id| self.dealloc_data(block_id));
+                return None;
+            }
+        };
+        for (old, new) in data_blocks.iter().zip(new_data_blocks.iter()) {
+            self.copy_block(*old, *new);
+        }
+        let new_blocks =
+            DiskInode::build_block_list(&new_data_blocks, || indirect_blocks.next().unwrap());
+        let old_blocks = self.modify_disk_inode(inode_id, |disk_inode| {
+            let size = disk_inode.size;
+            let mapped = disk_inode.mapped_blocks();
+            let old_blocks = disk_inode.clear_size(&self.block_device);
+            disk_inode.reserve(mapped, new_blocks, &self.block_device);
+            disk_inode.increase_size(size, Vec::new(), &self.block_device);
+            old_blocks
+        });
+        for block_id in old_blocks {
+            self.dealloc_data(block_id);
+        }
+        Some(())
+    }
+    /// Allocate `count` contiguous data blocks within a region, return the first of them
+    fn alloc_contiguous_data(&mut self, count: u32) -> Option<u32> {
+        self.data_regions.iter().find_map(|region| {
+            Bitmap::new(region.bitmap_start as usize, region.bitmap_blocks as usize)
+                .alloc_contiguous(
+                    &self.block_device,
+                    count as usize,
+                    region.area_blocks as usize,
+                )
+                .map(|bit| region.area_start + bit as u32)
+        })
+    }
+    /// Copy the content of a block into another
+    fn copy_block(&self, src: u32, dst: u32) {
+        get_block_cache(src as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, |src: &DataBlock| {
+                get_block_cache(dst as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .modify(0, |dst: &mut DataBlock| dst.copy_from_slice(src));
+            });
+    }
+    /// Load a key on behalf of `uid` to access the inodes it encrypts, return its id.
+    /// A key loaded already keeps the uid which loaded it first.
+    /// Fail if a different key with the same id is loaded
+    pub fn add_key(&mut self, key: &[u8; KEY_SZ], uid: u32) -> Option<u32> {
+        let key = XtsKey::new(key);
+        let key_id = key.id();
+        match self.keys.get(&key_id) {
+            // the ids are short, data must not be decrypted with another key
+            Some((loaded, _)) if !loaded.same_key(&key) => return None,
+            Some(_) => {}
+            None => {
+                self.keys.insert(key_id, (Arc::new(key), uid));
+            }
+        }
+        Some(key_id)
+    }
+    /// Forget a key on behalf of `uid`, the inodes it encrypts are no longer accessible.
+    /// Fail unless `uid` loaded the key or is the root user
+    pub fn remove_key(&mut self, key_id: u32, uid: u32) -> Option<()> {
+        let (_, owner) = self.keys.get(&key_id)?;
+        if uid != *owner && uid != ROOT_UID {
+            return None;
+        }
+        self.keys.remove(&key_id).map(|_| ())
+    }
+    /// Get a loaded key by id, which stays usable after the filesystem lock is released
+    pub fn key(&self, key_id: u32) -> Option<Arc<XtsKey>> {
+        self.keys.get(&key_id).map(|(key, _)| key.clone())
+    }
+    /// Get the entries of a directory, skipping unlinked ones.
+    /// Names are kept as stored, as those in encrypted directories cannot be read
+    fn dir_entries(&self, dir_id: u32) -> Vec<DirEntry> {
+        self.read_disk_inode(dir_id, |disk_inode| {
+            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+            let mut v: Vec<DirEntry> = Vec::new();
+            for i in 0..file_count {
+                let mut dirent = DirEntry::empty();
+                assert_eq!(
+                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device),
+                    DIRENT_SZ,
+                );
+                if !dirent.is_unused() {
+                    v.push(dirent);
+                }
+            }
+            v
+        })
+    }
+    /// Append an entry to a directory, adding a link to the inode it refers to
+    fn append_dirent(&mut self, dir_id: u32, dirent: &DirEntry) -> Option<()> {
+        let (file_count, blocks_needed) = self.read_disk_inode(dir_id, |disk_inode| {
+            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+            let new_size = ((file_count + 1) * DIRENT_SZ) as u32;
+            (file_count, disk_inode.blocks_num_needed(new_size))
+        });
+        let new_blocks = self.try_alloc_data_blocks(blocks_needed)?;
+        self.modify_disk_inode(dir_id, |disk_inode| {
+            let new_size = ((file_count + 1) * DIRENT_SZ) as u32;
+            disk_inode.increase_size(new_size, new_blocks, &self.block_device);
+            disk_inode.write_at(
+                file_count * DIRENT_SZ,
+                dirent.as_bytes(),
+                &self.block_device,
+            );
+        });
+        self.modify_disk_inode(dirent.inode_number(), |disk_inode| disk_inode.nlink += 1);
+        Some(())
+    }
+    /// Clear an entry of a directory, removing a link from the inode it refers to.
+    /// Return the inode id it referred to
+    fn remove_dirent(&mut self, dir_id: u32, name: &str) -> Option<u32> {
+        let inode_id = self.modify_disk_inode(dir_id, |disk_inode| {
+            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
+            let mut dirent = DirEntry::empty();
+            for i in 0..file_count {
+                disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device);
+                if dirent.name() == name {
+                    disk_inode.write_at(
+                        i * DIRENT_SZ,
+                        DirEntry::empty().as_bytes(),
+                        &self.block_device,
+                    );
+                    return Some(dirent.inode_number());
+                }
+            }
+            None
+        })?;
+        self.modify_disk_inode(inode_id, |disk_inode| disk_inode.nlink -= 1);
+        Some(inode_id)
+    }
+    /// Copy inode `src` into a newly allocated inode unless it has been copied already.
+    /// Fail if the device or the inode table is full, or a block cannot take another
+    /// reference, the inodes copied so far are left in `copied` for `discard_copies`
+    fn copy_inode(&mut self, src: u32, copied: &mut BTreeMap<u32, u32>) -> Option<u32> {
+        if let Some(dst) = copied.get(&src) {
+            return Some(*dst);
+        }
+        let dst = self.alloc_inode()?;
+        self.copy_inode_into(src, dst, copied)?;
+        Some(dst)
+    }
+    /// Copy inode `src` into the free inode `dst`, directories are copied recursively
+    /// and file data blocks are shared instead of copied
+    fn copy_inode_into(
+        &mut self,
+        src: u32,
+        dst: u32,
+        copied: &mut BTreeMap<u32, u32>,
+    ) -> Option<()> {
+        copied.insert(src, dst);
+        let (inode_type, xattr, uid, policy, device) = self.read_disk_inode(src, |disk_inode| {
+            (
+                disk_inode.inode_type(),
+                disk_inode.xattr,
+                disk_inode.uid,
+                disk_inode.data_policy(),
+                disk_inode.device(),
+            )
+        });
+        self.modify_disk_inode(dst, |disk_inode| {
+            disk_inode.initialize(inode_type);
+            disk_inode.uid = uid;
+            disk_inode.set_data_policy(policy);
+            if let Some((major, minor)) = device {
+                disk_inode.set_device(major, minor);
+            }
+        });
+        if xattr != 0 {
+            let new_xattr = self.try_alloc_data()?;
+            self.copy_block(xattr, new_xattr);
+            self.modify_disk_inode(dst, |disk_inode| disk_inode.xattr = new_xattr);
+        }
+        if inode_type == DiskInodeType::Directory {
+            for dirent in self.dir_entries(src) {
+                let new_child = self.copy_inode(dirent.inode_number(), copied)?;
+                self.append_dirent(dst, &DirEntry::from_raw(*dirent.raw_name(), new_child))?;
+            }
+        } else if inode_type == DiskInodeType::File {
+            self.share_inode_data(self.get_disk_inode_pos(src), self.get_disk_inode_pos(dst))?;
+        }
+        Some(())
+    }
+    /// Release the inodes made by a failed copy with whatever they hold so far
+    fn discard_copies(&mut self, copied: &BTreeMap<u32, u32>) {
+        for dst in copied.values() {
+            self.release_inode_data(*dst);
+            self.dealloc_inode(*dst);
+        }
+    }
+    /// Copy the tree of inodes under `src`, releasing what was copied on failure
+    fn copy_tree(&mut self, src: u32) -> Option<u32> {
+        let mut copied = BTreeMap::new();
+        let dst = self.copy_inode(src, &mut copied);
+        if dst.is_none() {
+            self.discard_copies(&copied);
+        }
+        dst
+    }
+    /// Release the data blocks and extended attributes of an inode
+    fn release_inode_data(&mut self, inode_id: u32) {
+        let (data_blocks_dealloc, xattr) = self.modify_disk_inode(inode_id, |disk_inode| {
+            let xattr = disk_inode.xattr;
+            disk_inode.xattr = 0;
+            (disk_inode.clear_size(&self.block_device), xattr)
+        });
+        for data_block in data_blocks_dealloc.into_iter() {
+            self.dealloc_data(data_block);
+        }
+        if xattr != 0 {
+            self.dealloc_data(xattr);
+        }
+    }
+    /// Whether an inode is allocated
+    fn is_inode_allocated(&self, inode_id: u32) -> bool {
+        let area_inodes = self.inode_bitmap.maximum() as u32;
+        if inode_id < area_inodes {
+            return self
+                .inode_bitmap
+                .is_allocated(&self.block_device, inode_id as usize);
+        }
+        self.inode_map
+            .get(((inode_id - area_inodes) / INODES_PER_BLOCK) as usize)
+            .map_or(false, |entry| {
+                entry.used & (1 << (inode_id % INODES_PER_BLOCK)) != 0
+            })
+    }
+    /// Release an inode of the live filesystem whose last link is gone,
+    /// with its blocks, which are uncharged from its owner.
+    /// An entry left by a crash may refer to an inode which was never allocated,
+    /// there is nothing to release then
+    pub fn release_unlinked(&mut self, inode_id: u32) {
+        if !self.is_inode_allocated(inode_id) {
+            return;
+        }
+        let (uid, blocks) = self.read_disk_inode(inode_id, |disk_inode| {
+            (
+                disk_inode.uid,
+                disk_inode.allocated_blocks() + (disk_inode.xattr != 0) as u32,
+            )
+        });
+        self.release_inode_data(inode_id);
+        self.dealloc_inode(inode_id);
+        self.uncharge_blocks(uid, blocks);
+        self.uncharge_inode(uid);
+    }
+    /// Whether a directory has no entries
+    pub fn is_empty_dir(&self, dir_id: u32) -> bool {
+        self.dir_entries(dir_id).is_empty()
+    }
+    /// Release an inode unless it has been released already,
+    /// directories are released recursively
+    fn free_inode(&mut self, inode_id: u32, freed: &mut BTreeSet<u32>) {
+        if !freed.insert(inode_id) {
+            return;
+        }
+        if self.read_disk_inode(inode_id, |disk_inode| disk_inode.is_dir()) {
+            for dirent in self.dir_entries(inode_id) {
+                self.free_inode(dirent.inode_number(), freed);
+            }
+        }
+        self.release_inode_data(inode_id);
+        self.dealloc_inode(inode_id);
+    }
+    /// Find the root directory of a snapshot by name
+    fn find_snapshot(&self, name: &str) -> Option<u32> {
+        self.dir_entries(self.snapshot_inode)
+            .into_iter()
+            .find(|dirent| dirent.name() == name)
+            .map(|dirent| dirent.inode_number())
+    }
+    /// Take a point-in-time snapshot of the whole filesystem,
+    /// all data blocks are shared with the live filesystem.
+    /// Fail, releasing what was copied, if the device or the inode table is full.
+    /// Operations of inodes wait until it is taken
+    pub fn create_snapshot(efs: &Arc<Mutex<Self>>, name: &str) -> Option<()> {
+        Self::quiesced(efs, |fs| fs.take_snapshot(name))
+    }
+    /// Take a point-in-time snapshot of the whole filesystem, see `create_snapshot`
+    fn take_snapshot(&mut self, name: &str) -> Option<()> {
+        if name.is_empty() || name.len() > NAME_LENGTH_LIMIT || self.find_snapshot(name).is_some() {
+            return None;
+        }
+        let snapshot_root = self.copy_tree(0)?;
+        if self
+            .append_dirent(self.snapshot_inode, &DirEntry::new(name, snapshot_root))
+            .is_none()
+        {
+            self.free_inode(snapshot_root, &mut BTreeSet::new());
+            return None;
+        }
+        block_cache_sync_all();
+        Some(())
+    }
+    /// List the names of snapshots
+    pub fn list_snapshots(&self) -> Vec<String> {
+        self.dir_entries(self.snapshot_inode)
+            .into_iter()
+            .map(|dirent| String::from(dirent.name()))
+            .collect()
+    }
+    /// Delete a snapshot and release the blocks only it refers to.
+    /// Fail if a file of the snapshot is open
+    pub fn delete_snapshot(&mut self, name: &str) -> Option<()> {
+        let snapshot_root = self.find_snapshot(name)?;
+        if self
+            .reachable_inodes(snapshot_root)
+            .into_iter()
+            .any(|inode_id| self.is_open(inode_id))
+        {
+            return None;
+        }
+        self.remove_dirent(self.snapshot_inode, name)?;
+        self.free_inode(snapshot_root, &mut BTreeSet::new());
+        block_cache_sync_all();
+        Some(())
+    }
+    /// Revert the live filesystem to a snapshot, the snapshot itself is kept.
+    /// Inodes of the live filesystem are released, so fail if any file other than
+    /// the root directory is open, or if the device cannot hold a copy of the snapshot
+    /// besides the live filesystem. The usage of all uids is recounted afterwards.
+    /// Operations of inodes, e.g. of the root directory, wait until it is done
+    pub fn revert_to_snapshot(efs: &Arc<Mutex<Self>>, name: &str) -> Option<()> {
+        Self::quiesced(efs, |fs| fs.revert(name))
+    }
+    /// Revert the live filesystem to a snapshot, see `revert_to_snapshot`
+    fn revert(&mut self, name: &str) -> Option<()> {
+        let snapshot_root = self.find_snapshot(name)?;
+        if self.has_open_inodes() {
+            return None;
+        }
+        // copy the snapshot first, so that the live filesystem is kept if that fails
+        let new_root = self.copy_tree(snapshot_root)?;
+        let mut freed = BTreeSet::new();
+        // the root inode must keep id 0
+        freed.insert(0);
+        for dirent in self.dir_entries(0) {
+            self.free_inode(dirent.inode_number(), &mut freed);
+        }
+        self.release_inode_data(0);
+        // the root inode takes over the blocks of the copy, whose inode is then free
+        let disk_inode = self.read_disk_inode(new_root, DiskInode::clone);
+        self.modify_disk_inode(0, |root| {
+            *root = disk_inode;
+            root.nlink = 1;
+        });
+        self.dealloc_inode(new_root);
+        self.recount_quota();
+        block_cache_sync_all();
+        Some(())
+    }
+    /// Get the root inode of a snapshot, which is mounted read-only
+    pub fn snapshot_root(efs: &Arc<Mutex<Self>>, name: &str) -> Option<Arc<Inode>> {
+        let mut fs = efs.lock();
+        let inode_id = fs.find_snapshot(name)?;
+        Some(fs.get_inode(efs, inode_id, true))
+    }
+    /// Get the hidden directory holding the roots of the snapshots, read-only
+    pub fn snapshots_dir(efs: &Arc<Mutex<Self>>) -> Arc<Inode> {
+        let mut fs = efs.lock();
+        let inode_id = fs.snapshot_inode;
+        fs.get_inode(efs, inode_id, true)
+    }
 }
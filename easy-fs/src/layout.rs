@@ -1,14 +1,17 @@
+use super::compress::{cluster_count, cluster_len, clusters_start, decompress_cluster, CLUSTER_SZ};
 use super::{get_block_cache, BlockDevice, BLOCK_SZ};
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
 
-/// Magic number for sanity check
-const EFS_MAGIC: u32 = 0x3b800001;
+/// Magic number for sanity check, changed along with the layout on disk
+/// so that images of an older layout are not opened
+const EFS_MAGIC: u32 = 0x3b800002;
 /// The max number of direct inodes
-const INODE_DIRECT_COUNT: usize = 28;
+const INODE_DIRECT_COUNT: usize = 22;
 /// The max length of inode name
-const NAME_LENGTH_LIMIT: usize = 27;
+pub const NAME_LENGTH_LIMIT: usize = 27;
 /// The max number of indirect1 inodes
 const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
 /// The max number of indirect2 inodes
@@ -18,11 +21,15 @@ const DIRECT_BOUND: usize = INODE_DIRECT_COUNT;
 /// The upper bound of indirect1 inode index
 const INDIRECT1_BOUND: usize = DIRECT_BOUND + INODE_INDIRECT1_COUNT;
 /// The upper bound of indirect2 inode index
-#[allow(unused)]
 const INDIRECT2_BOUND: usize = INDIRECT1_BOUND + INODE_INDIRECT2_COUNT;
+/// The max size of the data of an inode
+pub const MAX_FILE_SIZE: usize = INDIRECT2_BOUND * BLOCK_SZ;
+/// The max number of data regions added by growing a filesystem
+pub const MAX_GROWN_REGIONS: usize = 16;
 
 /// Super block of a filesystem
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct SuperBlock {
     magic: u32,
     pub total_blocks: u32,
@@ -30,6 +37,35 @@ pub struct SuperBlock {
     pub inode_area_blocks: u32,
     pub data_bitmap_blocks: u32,
     pub data_area_blocks: u32,
+    /// Inode holding the reference counts of shared data blocks
+    pub refcount_inode: u32,
+    /// Hidden directory holding the root directories of snapshots
+    pub snapshot_inode: u32,
+    /// Inode holding the quota of each uid
+    pub quota_inode: u32,
+    /// Inode holding the inode map, which locates inodes beyond the inode area
+    pub inode_map_inode: u32,
+    /// Number of data regions added by growing the filesystem
+    grown_regions: u32,
+    /// Data regions added by growing the filesystem, each with its own bitmap
+    regions: [DataRegion; MAX_GROWN_REGIONS],
+}
+
+/// A data area and the bitmap allocating its blocks
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DataRegion {
+    pub bitmap_start: u32,
+    pub bitmap_blocks: u32,
+    pub area_start: u32,
+    pub area_blocks: u32,
+}
+
+impl DataRegion {
+    /// Whether a block is in the data area of this region
+    pub fn contains(&self, block_id: u32) -> bool {
+        (self.area_start..self.area_start + self.area_blocks).contains(&block_id)
+    }
 }
 
 impl Debug for SuperBlock {
@@ -40,6 +76,14 @@ impl Debug for SuperBlock {
             .field("inode_area_blocks", &self.inode_area_blocks)
             .field("data_bitmap_blocks", &self.data_bitmap_blocks)
             .field("data_area_blocks", &self.data_area_blocks)
+            .field("refcount_inode", &self.refcount_inode)
+            .field("snapshot_inode", &self.snapshot_inode)
+            .field("quota_inode", &self.quota_inode)
+            .field("inode_map_inode", &self.inode_map_inode)
+            .field(
+                "grown_regions",
+                &&self.regions[..self.grown_regions as usize],
+            )
             .finish()
     }
 }
@@ -61,21 +105,75 @@ impl SuperBlock {
             inode_area_blocks,
             data_bitmap_blocks,
             data_area_blocks,
+            refcount_inode: 0,
+            snapshot_inode: 0,
+            quota_inode: 0,
+            inode_map_inode: 0,
+            grown_regions: 0,
+            regions: [DataRegion::default(); MAX_GROWN_REGIONS],
         }
     }
     /// Check if a super block is valid using efs magic
     pub fn is_valid(&self) -> bool {
         self.magic == EFS_MAGIC
     }
+    /// Get all data regions, the first one follows the inode area
+    pub fn data_regions(&self) -> Vec<DataRegion> {
+        let bitmap_start = 1 + self.inode_bitmap_blocks + self.inode_area_blocks;
+        let first = DataRegion {
+            bitmap_start,
+            bitmap_blocks: self.data_bitmap_blocks,
+            area_start: bitmap_start + self.data_bitmap_blocks,
+            area_blocks: self.data_area_blocks,
+        };
+        core::iter::once(first)
+            .chain(self.regions[..self.grown_regions as usize].iter().copied())
+            .collect()
+    }
+    /// Record all data regions after the filesystem has grown to `total_blocks`
+    pub fn set_data_regions(&mut self, total_blocks: u32, regions: &[DataRegion]) {
+        assert!(regions.len() <= 1 + MAX_GROWN_REGIONS);
+        self.total_blocks = total_blocks;
+        self.data_area_blocks = regions[0].area_blocks;
+        self.grown_regions = regions.len() as u32 - 1;
+        self.regions[..regions.len() - 1].copy_from_slice(&regions[1..]);
+    }
+}
+
+/// The uid owning the root directory and all files created without an owner
+pub const ROOT_UID: u32 = 0;
+
+/// Flag of a disk inode whose data is compressed in clusters
+pub const INODE_COMPRESSED: u32 = 1 << 0;
+/// Flag of a disk inode whose data, or names if it is a directory, are encrypted
+pub const INODE_ENCRYPTED: u32 = 1 << 1;
+
+/// How the data of a disk inode is stored, shared by inodes sharing the data
+#[derive(Clone, Copy)]
+pub struct DataPolicy {
+    flags: u32,
+    key_id: u32,
+    nonce: u32,
 }
 
 /// Type of a disk inode
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum DiskInodeType {
     File,
     Directory,
+    /// Named pipe, which has no data
+    Fifo,
+    /// Character device, which has no data but a device number
+    CharDevice,
+    /// Block device, which has no data but a device number
+    BlockDevice,
 }
 
+/// The max major number of a device
+pub const MAX_MAJOR: u32 = (1 << 12) - 1;
+/// The max minor number of a device
+pub const MAX_MINOR: u32 = (1 << 20) - 1;
+
 /// A indirect block
 type IndirectBlock = [u32; BLOCK_SZ / 4];
 /// A data block
@@ -83,11 +181,26 @@ type DataBlock = [u8; BLOCK_SZ];
 
 /// A disk inode
 #[repr(C)]
+#[derive(Clone)]
 pub struct DiskInode {
     pub size: u32,
     pub direct: [u32; INODE_DIRECT_COUNT],
     pub indirect1: u32,
     pub indirect2: u32,
+    /// Block holding the extended attributes, 0 if there is none
+    pub xattr: u32,
+    /// Owner uid, charged for the blocks and the inode itself
+    pub uid: u32,
+    /// Flags describing how the data is stored
+    pub flags: u32,
+    /// Id of the key encrypting the data, valid if INODE_ENCRYPTED is set
+    pub key_id: u32,
+    /// Nonce making the encryption of this inode differ from others with the same key
+    pub nonce: u32,
+    /// Number of directory entries referring to this inode, plus one for the root directory
+    pub nlink: u32,
+    /// Number of data blocks mapped beyond the end of the data, reserved by `reserve`
+    prealloc: u16,
     type_: DiskInodeType,
 }
 
@@ -99,17 +212,80 @@ impl DiskInode {
         self.direct.iter_mut().for_each(|v| *v = 0);
         self.indirect1 = 0;
         self.indirect2 = 0;
+        self.xattr = 0;
+        self.uid = ROOT_UID;
+        self.flags = 0;
+        self.key_id = 0;
+        self.nonce = 0;
+        self.nlink = 0;
+        self.prealloc = 0;
         self.type_ = type_;
     }
+    /// Get the type of this inode
+    pub fn inode_type(&self) -> DiskInodeType {
+        self.type_
+    }
     /// Whether this inode is a directory
     pub fn is_dir(&self) -> bool {
         self.type_ == DiskInodeType::Directory
     }
     /// Whether this inode is a file
-    #[allow(unused)]
     pub fn is_file(&self) -> bool {
         self.type_ == DiskInodeType::File
     }
+    /// Whether this inode is a file or directory, which hold data
+    pub fn has_data(&self) -> bool {
+        self.is_file() || self.is_dir()
+    }
+    /// Whether this inode is a character or block device
+    pub fn is_device(&self) -> bool {
+        matches!(
+            self.type_,
+            DiskInodeType::CharDevice | DiskInodeType::BlockDevice
+        )
+    }
+    /// Get the (major, minor) number of a device, kept in place of the first data block
+    pub fn device(&self) -> Option<(u32, u32)> {
+        self.is_device()
+            .then(|| (self.direct[0] >> 20, self.direct[0] & MAX_MINOR))
+    }
+    /// Set the (major, minor) number of a device
+    pub fn set_device(&mut self, major: u32, minor: u32) {
+        assert!(self.is_device() && major <= MAX_MAJOR && minor <= MAX_MINOR);
+        self.direct[0] = major << 20 | minor;
+    }
+    /// Whether the data of this inode is compressed
+    pub fn is_compressed(&self) -> bool {
+        self.flags & INODE_COMPRESSED != 0
+    }
+    /// Get how the data of this inode is stored
+    pub fn data_policy(&self) -> DataPolicy {
+        DataPolicy {
+            flags: self.flags,
+            key_id: self.key_id,
+            nonce: self.nonce,
+        }
+    }
+    /// Set how the data of this inode is stored
+    pub fn set_data_policy(&mut self, policy: DataPolicy) {
+        self.flags = policy.flags;
+        self.key_id = policy.key_id;
+        self.nonce = policy.nonce;
+    }
+    /// Whether the data or names of this inode are encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & INODE_ENCRYPTED != 0
+    }
+    /// Get the size of the data as read by `read_at`, which differs from
+    /// the stored size for compressed data
+    pub fn file_size(&self, block_device: &Arc<dyn BlockDevice>) -> u32 {
+        if !self.is_compressed() {
+            return self.size;
+        }
+        let mut buf = [0u8; 4];
+        self.read_raw(0, &mut buf, block_device);
+        u32::from_le_bytes(buf)
+    }
     /// Get the number of data blocks corresponding to size
     pub fn data_blocks(&self) -> u32 {
         Self::_data_blocks(self.size)
@@ -117,9 +293,22 @@ impl DiskInode {
     fn _data_blocks(size: u32) -> u32 {
         (size + BLOCK_SZ as u32 - 1) / BLOCK_SZ as u32
     }
+    /// Get the number of data blocks mapped, including those reserved beyond the size
+    pub fn mapped_blocks(&self) -> u32 {
+        self.data_blocks() + self.prealloc as u32
+    }
+    /// Get the number of blocks held, including indirect blocks and reserved data blocks
+    pub fn allocated_blocks(&self) -> u32 {
+        Self::blocks_for(self.mapped_blocks())
+    }
     /// Get the number of data blocks required for the given size of data
     pub fn total_blocks(size: u32) -> u32 {
-        let data_blocks = Self::_data_blocks(size) as usize;
+        Self::blocks_for(Self::_data_blocks(size))
+    }
+    /// Get the number of blocks needed to map the given number of data blocks,
+    /// including indirect blocks
+    pub fn blocks_for(data_blocks: u32) -> u32 {
+        let data_blocks = data_blocks as usize;
         let mut total = data_blocks as usize;
         // indirect1
         if data_blocks > INODE_DIRECT_COUNT {
@@ -134,10 +323,12 @@ impl DiskInode {
         }
         total as u32
     }
-    /// Get the number of data blocks that have to be allocated given the new size of data
+    /// Get the number of data blocks that have to be allocated given the new size of data,
+    /// reserved data blocks are used first
     pub fn blocks_num_needed(&self, new_size: u32) -> u32 {
         assert!(new_size >= self.size);
-        Self::total_blocks(new_size) - Self::total_blocks(self.size)
+        let mapped = self.mapped_blocks();
+        Self::blocks_for(Self::_data_blocks(new_size).max(mapped)) - Self::blocks_for(mapped)
     }
     /// Get id of block given inner id
     pub fn get_block_id(&self, inner_id: u32, block_device: &Arc<dyn BlockDevice>) -> u32 {
@@ -164,16 +355,90 @@ impl DiskInode {
                 })
         }
     }
-    /// Inncrease the size of current disk inode
+    /// Set id of block given inner id, the block must have been mapped
+    pub fn set_block_id(
+        &mut self,
+        inner_id: u32,
+        block_id: u32,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let inner_id = inner_id as usize;
+        if inner_id < INODE_DIRECT_COUNT {
+            self.direct[inner_id] = block_id;
+        } else if inner_id < INDIRECT1_BOUND {
+            get_block_cache(self.indirect1 as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |indirect_block: &mut IndirectBlock| {
+                    indirect_block[inner_id - INODE_DIRECT_COUNT] = block_id;
+                });
+        } else {
+            let last = inner_id - INDIRECT1_BOUND;
+            let indirect1 = get_block_cache(self.indirect2 as usize, Arc::clone(block_device))
+                .lock()
+                .read(0, |indirect2: &IndirectBlock| {
+                    indirect2[last / INODE_INDIRECT1_COUNT]
+                });
+            get_block_cache(indirect1 as usize, Arc::clone(block_device))
+                .lock()
+                .modify(0, |indirect1: &mut IndirectBlock| {
+                    indirect1[last % INODE_INDIRECT1_COUNT] = block_id;
+                });
+        }
+    }
+    /// Arrange existing data blocks together with newly allocated indirect blocks
+    /// in the order `increase_size` consumes them when growing an empty inode
+    pub fn build_block_list(data_blocks: &[u32], alloc_indirect: impl FnMut() -> u32) -> Vec<u32> {
+        Self::build_block_list_from(0, data_blocks, alloc_indirect)
+    }
+    /// Arrange data blocks mapped from inner id `first` on together with newly allocated
+    /// indirect blocks in the order `reserve` and `increase_size` consume them
+    pub fn build_block_list_from(
+        first: u32,
+        data_blocks: &[u32],
+        mut alloc_indirect: impl FnMut() -> u32,
+    ) -> Vec<u32> {
+        let mut v: Vec<u32> = Vec::new();
+        for (inner_id, block_id) in (first as usize..).zip(data_blocks.iter()) {
+            // indirect1 before the first block it maps, likewise indirect2
+            if inner_id == DIRECT_BOUND || inner_id == INDIRECT1_BOUND {
+                v.push(alloc_indirect());
+            }
+            // low-level indirect1
+            if inner_id >= INDIRECT1_BOUND
+                && (inner_id - INDIRECT1_BOUND) % INODE_INDIRECT1_COUNT == 0
+            {
+                v.push(alloc_indirect());
+            }
+            v.push(*block_id);
+        }
+        v
+    }
+    /// Inncrease the size of current disk inode, reserved data blocks are used first
     pub fn increase_size(
         &mut self,
         new_size: u32,
         new_blocks: Vec<u32>,
         block_device: &Arc<dyn BlockDevice>,
     ) {
-        let mut current_blocks = self.data_blocks();
+        let mapped = self.mapped_blocks().max(Self::_data_blocks(new_size));
+        self.reserve(mapped, new_blocks, block_device);
         self.size = new_size;
-        let mut total_blocks = self.data_blocks();
+        self.prealloc = (mapped - self.data_blocks()) as u16;
+    }
+    /// Map data blocks up to `mapped` without changing the size,
+    /// those beyond the size are kept reserved for later growth
+    pub fn reserve(
+        &mut self,
+        mapped: u32,
+        new_blocks: Vec<u32>,
+        block_device: &Arc<dyn BlockDevice>,
+    ) {
+        let mut current_blocks = self.mapped_blocks();
+        if mapped <= current_blocks {
+            return;
+        }
+        self.prealloc = (mapped - self.data_blocks()) as u16;
+        let mut total_blocks = mapped;
         let mut new_blocks = new_blocks.into_iter();
         // fill direct
         while current_blocks < total_blocks.min(INODE_DIRECT_COUNT as u32) {
@@ -241,8 +506,9 @@ impl DiskInode {
     /// and clear the block contents to zero later
     pub fn clear_size(&mut self, block_device: &Arc<dyn BlockDevice>) -> Vec<u32> {
         let mut v: Vec<u32> = Vec::new();
-        let mut data_blocks = self.data_blocks() as usize;
+        let mut data_blocks = self.mapped_blocks() as usize;
         self.size = 0;
+        self.prealloc = 0;
         let mut current_blocks = 0usize;
         // direct
         while current_blocks < data_blocks.min(INODE_DIRECT_COUNT) {
@@ -284,17 +550,13 @@ impl DiskInode {
             .lock()
             .modify(0, |indirect2: &mut IndirectBlock| {
                 // full indirect1 blocks
-                for i in 0..a1 {
-                    v.push(indirect2[i]);
-                    get_block_cache(indirect2[i] as usize, Arc::clone(block_device))
+                for indirect1_block in indirect2.iter().take(a1) {
+                    v.push(*indirect1_block);
+                    get_block_cache(*indirect1_block as usize, Arc::clone(block_device))
                         .lock()
                         .modify(0, |indirect1: &mut IndirectBlock| {
-                            for j in 0..INODE_INDIRECT1_COUNT {
-                                v.push(indirect1[j]);
-                                //indirect1[j] = 0;
-                            }
+                            v.extend_from_slice(indirect1);
                         });
-                    //indirect2[i] = 0;
                 }
                 // last indirect1 block
                 if b1 > 0 {
@@ -302,10 +564,7 @@ impl DiskInode {
                     get_block_cache(indirect2[a1] as usize, Arc::clone(block_device))
                         .lock()
                         .modify(0, |indirect1: &mut IndirectBlock| {
-                            for j in 0..b1 {
-                                v.push(indirect1[j]);
-                                //indirect1[j] = 0;
-                            }
+                            v.extend_from_slice(&indirect1[..b1]);
                         });
                     //indirect2[a1] = 0;
                 }
@@ -313,12 +572,68 @@ impl DiskInode {
         self.indirect2 = 0;
         v
     }
-    /// Read data from current disk inode
+    /// Read data from current disk inode, decompressing it if needed
     pub fn read_at(
         &self,
         offset: usize,
         buf: &mut [u8],
         block_device: &Arc<dyn BlockDevice>,
+    ) -> usize {
+        if self.is_compressed() {
+            self.read_compressed(offset, buf, block_device)
+        } else {
+            self.read_raw(offset, buf, block_device)
+        }
+    }
+    /// Read compressed data, decompressing every cluster the range overlaps.
+    /// On a damaged image the read stops short at the first cluster out of bounds
+    /// or failing to decompress
+    fn read_compressed(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
+    ) -> usize {
+        let size = self.file_size(block_device) as usize;
+        let end = (offset + buf.len()).min(size);
+        if offset >= end {
+            return 0;
+        }
+        let table_end = clusters_start(size);
+        let mut read_size = 0usize;
+        for cluster in offset / CLUSTER_SZ..cluster_count(end) {
+            // the end offset of the previous cluster is the start of this one
+            let mut bounds = [0u8; 8];
+            if cluster == 0 {
+                self.read_raw(4, &mut bounds[4..], block_device);
+            } else {
+                self.read_raw(4 * cluster, &mut bounds, block_device);
+            }
+            let start = u32::from_le_bytes([bounds[0], bounds[1], bounds[2], bounds[3]]) as usize;
+            let stop = u32::from_le_bytes([bounds[4], bounds[5], bounds[6], bounds[7]]) as usize;
+            if start > stop || table_end + stop > self.size as usize {
+                break;
+            }
+            let mut stored = alloc::vec![0u8; stop - start];
+            self.read_raw(table_end + start, &mut stored, block_device);
+            let data = match decompress_cluster(&stored, cluster_len(cluster, size)) {
+                Some(data) => data,
+                None => break,
+            };
+            let cluster_start = cluster * CLUSTER_SZ;
+            let from = offset.max(cluster_start) - cluster_start;
+            let to = end.min(cluster_start + data.len()) - cluster_start;
+            buf[read_size..read_size + to - from].copy_from_slice(&data[from..to]);
+            read_size += to - from;
+        }
+        read_size
+    }
+    /// Read data as stored in current disk inode
+    pub fn read_raw(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        block_device: &Arc<dyn BlockDevice>,
     ) -> usize {
         let mut start = offset;
         let end = (offset + buf.len()).min(self.size as usize);
@@ -353,7 +668,7 @@ impl DiskInode {
         }
         read_size
     }
-    /// Write data into current disk inode
+    /// Write data into current disk inode as is, compressed data must not be overwritten
     /// size must be adjusted properly beforehand
     pub fn write_at(
         &mut self,
@@ -394,6 +709,77 @@ impl DiskInode {
     }
 }
 
+/// Quota and usage of a uid, a zero limit means unlimited
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Quota {
+    pub block_limit: u32,
+    pub inode_limit: u32,
+    pub blocks_used: u32,
+    pub inodes_used: u32,
+}
+
+/// Size of a quota record
+pub const QUOTA_SZ: usize = core::mem::size_of::<Quota>();
+/// Largest uid with a quota record, which bounds the quota table to 64 KiB.
+/// Larger uids have neither limits nor recorded usage
+pub const MAX_QUOTA_UID: u32 = 4095;
+
+impl Quota {
+    /// Whether `blocks` more blocks stay within the limit
+    pub fn allows_blocks(&self, blocks: u32) -> bool {
+        self.block_limit == 0 || self.blocks_used + blocks <= self.block_limit
+    }
+    /// Whether one more inode stays within the limit
+    pub fn allows_inode(&self) -> bool {
+        self.inode_limit == 0 || self.inodes_used < self.inode_limit
+    }
+    /// Serialize into bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, QUOTA_SZ) }
+    }
+    /// Serialize into mutable bytes
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, QUOTA_SZ) }
+    }
+}
+
+/// Number of disk inodes in a block
+pub const INODES_PER_BLOCK: u32 = (BLOCK_SZ / core::mem::size_of::<DiskInode>()) as u32;
+
+/// Entry of the inode map, a block of inodes allocated from the data area
+/// once the inode area is full
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct InodeMapEntry {
+    /// Block holding the inodes, 0 if the entry is free
+    pub block_id: u32,
+    /// Bit mask of the inodes in use in the block
+    pub used: u32,
+}
+
+/// Size of an inode map entry
+pub const INODE_MAP_ENTRY_SZ: usize = core::mem::size_of::<InodeMapEntry>();
+
+impl InodeMapEntry {
+    /// Whether all inodes of the block are in use
+    pub fn is_full(&self) -> bool {
+        self.used == (1 << INODES_PER_BLOCK) - 1
+    }
+    /// Serialize into bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self as *const _ as usize as *const u8, INODE_MAP_ENTRY_SZ)
+        }
+    }
+    /// Serialize into mutable bytes
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(self as *mut _ as usize as *mut u8, INODE_MAP_ENTRY_SZ)
+        }
+    }
+}
+
 /// A directory entry
 #[repr(C)]
 pub struct DirEntry {
@@ -403,6 +789,8 @@ pub struct DirEntry {
 
 /// Size of a directory entry
 pub const DIRENT_SZ: usize = 32;
+/// Name of a directory entry as stored, padded with zeros or encrypted
+pub type RawName = [u8; NAME_LENGTH_LIMIT + 1];
 
 impl DirEntry {
     /// Create an empty directory entry
@@ -421,6 +809,10 @@ impl DirEntry {
             inode_number,
         }
     }
+    /// Create a directory entry from a stored name and inode number
+    pub fn from_raw(name: RawName, inode_number: u32) -> Self {
+        Self { name, inode_number }
+    }
     /// Serialize into bytes
     pub fn as_bytes(&self) -> &[u8] {
         unsafe { core::slice::from_raw_parts(self as *const _ as usize as *const u8, DIRENT_SZ) }
@@ -437,8 +829,109 @@ impl DirEntry {
             ""
         }
     }
+    /// Get name of the entry as stored
+    pub fn raw_name(&self) -> &RawName {
+        &self.name
+    }
     /// Get inode number of the entry
     pub fn inode_number(&self) -> u32 {
         self.inode_number
     }
+    /// Whether the entry is free, either never used or cleared by unlink.
+    /// Entries are told apart by name, as a used entry may refer to inode 0
+    pub fn is_unused(&self) -> bool {
+        self.name.iter().all(|byte| *byte == 0)
+    }
+}
+
+/// An extended attribute block
+type XattrBlock = [u8; BLOCK_SZ];
+/// Size of an xattr entry header: name length (u8) and value length (u16)
+const XATTR_HEADER_SZ: usize = 3;
+/// The max length of an xattr name
+pub const XATTR_NAME_LIMIT: usize = u8::MAX as usize;
+
+/// Extended attributes of an inode, kept in a single block as a sequence of
+/// `(name_len, value_len, name, value)` entries terminated by a zero name length
+pub struct Xattrs {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl Xattrs {
+    /// Create an empty attribute list
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+    /// Deserialize from an xattr block, fail if an entry runs past the block
+    /// or a name is not valid UTF-8
+    pub fn load(block: &XattrBlock) -> Option<Self> {
+        let mut entries = Vec::new();
+        let mut pos = 0usize;
+        while pos + XATTR_HEADER_SZ <= BLOCK_SZ && block[pos] != 0 {
+            let name_len = block[pos] as usize;
+            let value_len = u16::from_le_bytes([block[pos + 1], block[pos + 2]]) as usize;
+            pos += XATTR_HEADER_SZ;
+            let name = core::str::from_utf8(block.get(pos..pos + name_len)?).ok()?;
+            pos += name_len;
+            let value = block.get(pos..pos + value_len)?;
+            entries.push((String::from(name), value.to_vec()));
+            pos += value_len;
+        }
+        Some(Self { entries })
+    }
+    /// Serialize into an xattr block, the caller must check `fits` beforehand
+    pub fn store(&self, block: &mut XattrBlock) {
+        let mut pos = 0usize;
+        for (name, value) in self.entries.iter() {
+            block[pos] = name.len() as u8;
+            block[pos + 1..pos + XATTR_HEADER_SZ]
+                .copy_from_slice(&(value.len() as u16).to_le_bytes());
+            pos += XATTR_HEADER_SZ;
+            block[pos..pos + name.len()].copy_from_slice(name.as_bytes());
+            pos += name.len();
+            block[pos..pos + value.len()].copy_from_slice(value);
+            pos += value.len();
+        }
+        block[pos..].iter_mut().for_each(|b| *b = 0);
+    }
+    /// Whether the attributes fit into one block, including the terminator
+    pub fn fits(&self) -> bool {
+        let size: usize = self
+            .entries
+            .iter()
+            .map(|(name, value)| XATTR_HEADER_SZ + name.len() + value.len())
+            .sum();
+        size < BLOCK_SZ
+    }
+    /// Whether there is no attribute
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// Get the value of an attribute
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_slice())
+    }
+    /// Insert or replace an attribute
+    pub fn set(&mut self, name: &str, value: &[u8]) {
+        if let Some(entry) = self.entries.iter_mut().find(|(n, _)| n == name) {
+            entry.1 = value.to_vec();
+        } else {
+            self.entries.push((String::from(name), value.to_vec()));
+        }
+    }
+    /// Remove an attribute, return whether it existed
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(n, _)| n != name);
+        self.entries.len() != len
+    }
+    /// Names of all attributes
+    pub fn names(&self) -> Vec<String> {
+        self.entries.iter().map(|(name, _)| name.clone()).collect()
+    }
 }
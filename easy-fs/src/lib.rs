@@ -1,4 +1,4 @@
-#![no_std]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 extern crate alloc;
 extern crate log;
@@ -6,8 +6,14 @@ extern crate log;
 mod bitmap;
 mod block_cache;
 mod block_dev;
+mod compress;
+mod crypt;
 mod efs;
 mod layout;
+#[cfg(any(test, feature = "std"))]
+mod mem_dev;
+#[cfg(test)]
+mod tests;
 mod vfs;
 
 /// Use a block size of 512 bytes
@@ -15,6 +21,10 @@ pub const BLOCK_SZ: usize = 512;
 use bitmap::Bitmap;
 use block_cache::{block_cache_sync_all, get_block_cache};
 pub use block_dev::BlockDevice;
-pub use efs::EasyFileSystem;
+pub use crypt::KEY_SZ;
+pub use efs::{EasyFileSystem, Fragmentation, Usage};
 use layout::*;
-pub use vfs::Inode;
+pub use layout::{DiskInodeType, Quota, SuperBlock, MAX_FILE_SIZE, MAX_MAJOR, MAX_MINOR, ROOT_UID};
+#[cfg(any(test, feature = "std"))]
+pub use mem_dev::{Fault, FaultyBlockDevice, MemBlockDevice, WriteRecord};
+pub use vfs::{DirEntryInfo, Inode, InodeStat};
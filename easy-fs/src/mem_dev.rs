@@ -0,0 +1,168 @@
+//! Block devices kept in memory, for testing easy-fs on the host
+
+use super::{BlockDevice, BLOCK_SZ};
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// Block device whose blocks are kept in memory
+pub struct MemBlockDevice {
+    blocks: Mutex<Vec<[u8; BLOCK_SZ]>>,
+}
+
+impl MemBlockDevice {
+    /// Create a device of `blocks` zeroed blocks
+    pub fn new(blocks: usize) -> Self {
+        Self {
+            blocks: Mutex::new(vec![[0u8; BLOCK_SZ]; blocks]),
+        }
+    }
+    /// Create a device holding a copy of `data`, whose last block is padded with zeros
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let device = Self::new((data.len() + BLOCK_SZ - 1) / BLOCK_SZ);
+        for (block, chunk) in device.blocks.lock().iter_mut().zip(data.chunks(BLOCK_SZ)) {
+            block[..chunk.len()].copy_from_slice(chunk);
+        }
+        device
+    }
+    /// Get a copy of all the blocks, e.g. to open it again as another device
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.blocks.lock().concat()
+    }
+    /// Get the number of blocks
+    pub fn blocks(&self) -> usize {
+        self.blocks.lock().len()
+    }
+}
+
+impl BlockDevice for MemBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let blocks = self.blocks.lock();
+        let block = blocks
+            .get(block_id)
+            .unwrap_or_else(|| panic!("block {} is past the end of the device", block_id));
+        buf.copy_from_slice(block);
+    }
+    fn num_blocks(&self) -> Option<usize> {
+        Some(self.blocks())
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let mut blocks = self.blocks.lock();
+        let block = blocks
+            .get_mut(block_id)
+            .unwrap_or_else(|| panic!("block {} is past the end of the device", block_id));
+        block.copy_from_slice(buf);
+    }
+}
+
+/// Fault injected by a [`FaultyBlockDevice`]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Fault {
+    /// Writes are lost, as if the power was cut before they reached the disk
+    Drop,
+    /// Only the first half of the blocks written reaches the disk
+    Tear,
+    /// Reads and writes fail with an I/O error, which panics
+    Error,
+}
+
+/// A write that reached a [`FaultyBlockDevice`]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WriteRecord {
+    /// Number of operations done on the device before this write
+    pub op: usize,
+    pub block_id: usize,
+    /// Fault injected into the write, if any
+    pub fault: Option<Fault>,
+}
+
+struct FaultState {
+    /// Number of reads and writes done
+    ops: usize,
+    /// Fault injected from the given operation on
+    armed: Option<(usize, Fault)>,
+    log: Vec<WriteRecord>,
+}
+
+/// In-memory block device injecting faults after a number of operations,
+/// and keeping a log of the writes
+pub struct FaultyBlockDevice {
+    disk: MemBlockDevice,
+    state: Mutex<FaultState>,
+}
+
+impl FaultyBlockDevice {
+    /// Create a device of `blocks` zeroed blocks, without faults
+    pub fn new(blocks: usize) -> Self {
+        Self {
+            disk: MemBlockDevice::new(blocks),
+            state: Mutex::new(FaultState {
+                ops: 0,
+                armed: None,
+                log: Vec::new(),
+            }),
+        }
+    }
+    /// Inject `fault` into every operation after the next `ops` ones
+    pub fn fail_after(&self, ops: usize, fault: Fault) {
+        let mut state = self.state.lock();
+        state.armed = Some((state.ops + ops, fault));
+    }
+    /// Stop injecting faults
+    pub fn heal(&self) {
+        self.state.lock().armed = None;
+    }
+    /// Get the number of reads and writes done
+    pub fn ops(&self) -> usize {
+        self.state.lock().ops
+    }
+    /// Get the writes done, in order
+    pub fn write_log(&self) -> Vec<WriteRecord> {
+        self.state.lock().log.clone()
+    }
+    /// Get the blocks as they reached the disk
+    pub fn disk(&self) -> &MemBlockDevice {
+        &self.disk
+    }
+    /// Count an operation, return the fault to inject into it
+    fn next_op(&self) -> (usize, Option<Fault>) {
+        let mut state = self.state.lock();
+        let op = state.ops;
+        state.ops += 1;
+        match state.armed {
+            Some((from, fault)) if op >= from => (op, Some(fault)),
+            _ => (op, None),
+        }
+    }
+}
+
+impl BlockDevice for FaultyBlockDevice {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        if let (_, Some(Fault::Error)) = self.next_op() {
+            panic!("I/O error when reading block {}", block_id);
+        }
+        self.disk.read_block(block_id, buf);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let (op, fault) = self.next_op();
+        self.state.lock().log.push(WriteRecord {
+            op,
+            block_id,
+            fault,
+        });
+        match fault {
+            None => self.disk.write_block(block_id, buf),
+            Some(Fault::Drop) => {}
+            Some(Fault::Tear) => {
+                let mut block = [0u8; BLOCK_SZ];
+                self.disk.read_block(block_id, &mut block);
+                block[..BLOCK_SZ / 2].copy_from_slice(&buf[..BLOCK_SZ / 2]);
+                self.disk.write_block(block_id, &block);
+            }
+            Some(Fault::Error) => panic!("I/O error when writing block {}", block_id),
+        }
+    }
+    fn num_blocks(&self) -> Option<usize> {
+        self.disk.num_blocks()
+    }
+}
@@ -0,0 +1,527 @@
+//! Crash-consistency and error-path tests on in-memory block devices
+
+use super::{BlockDevice, EasyFileSystem, Fault, FaultyBlockDevice, MemBlockDevice, BLOCK_SZ};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// Number of blocks of the test images
+const BLOCKS: u32 = 2048;
+
+/// The block cache is shared by all the devices, tests using it run one at a time
+/// so that a device only sees the operations of its own test
+static CACHE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Data of a file spanning more blocks than the block cache holds
+fn file_data() -> Vec<u8> {
+    (0..40 * BLOCK_SZ).map(|i| (i % 251) as u8).collect()
+}
+
+/// Open the blocks that reached the disk of a device as another image
+fn reopen(device: &FaultyBlockDevice) -> Arc<Mutex<EasyFileSystem>> {
+    EasyFileSystem::open(Arc::new(MemBlockDevice::from_bytes(
+        &device.disk().to_bytes(),
+    )))
+}
+
+#[test]
+fn mem_device_round_trip() {
+    let _lock = CACHE_LOCK.lock();
+    let device = Arc::new(MemBlockDevice::new(BLOCKS as usize));
+    let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let data = file_data();
+    root_inode.create("file").unwrap().write_at(0, &data);
+    efs.lock().sync();
+    let copy = MemBlockDevice::from_bytes(&device.to_bytes());
+    assert_eq!(copy.blocks(), BLOCKS as usize);
+    let efs = EasyFileSystem::open(Arc::new(copy));
+    let mut buf = alloc::vec![0u8; data.len()];
+    let file = EasyFileSystem::root_inode(&efs).find("file").unwrap();
+    assert_eq!(file.read_at(0, &mut buf), data.len());
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn faults_of_writes() {
+    let device = FaultyBlockDevice::new(4);
+    let (old, new) = ([1u8; BLOCK_SZ], [2u8; BLOCK_SZ]);
+    device.write_block(0, &old);
+    device.write_block(1, &old);
+    device.fail_after(1, Fault::Tear);
+    device.write_block(0, &new);
+    device.write_block(1, &new);
+    device.fail_after(0, Fault::Drop);
+    device.write_block(2, &new);
+    device.heal();
+    device.write_block(3, &new);
+    let mut block = [0u8; BLOCK_SZ];
+    device.read_block(0, &mut block);
+    assert_eq!(block, new);
+    device.read_block(1, &mut block);
+    assert_eq!(block[..BLOCK_SZ / 2], new[..BLOCK_SZ / 2]);
+    assert_eq!(block[BLOCK_SZ / 2..], old[BLOCK_SZ / 2..]);
+    device.read_block(2, &mut block);
+    assert_eq!(block, [0; BLOCK_SZ]);
+    device.read_block(3, &mut block);
+    assert_eq!(block, new);
+    assert_eq!(device.ops(), 10);
+    let faults: Vec<_> = device
+        .write_log()
+        .iter()
+        .map(|record| (record.block_id, record.fault))
+        .collect();
+    assert_eq!(
+        faults,
+        [
+            (0, None),
+            (1, None),
+            (0, None),
+            (1, Some(Fault::Tear)),
+            (2, Some(Fault::Drop)),
+            (3, None)
+        ]
+    );
+    device.fail_after(0, Fault::Error);
+    let result = catch_unwind(AssertUnwindSafe(|| device.read_block(0, &mut block)));
+    assert!(result.is_err());
+    let result = catch_unwind(AssertUnwindSafe(|| device.write_block(0, &old)));
+    assert!(result.is_err());
+    device.heal();
+    device.read_block(0, &mut block);
+    assert_eq!(block, new);
+}
+
+#[test]
+fn synced_files_survive_a_crash() {
+    let _lock = CACHE_LOCK.lock();
+    let data = file_data();
+    // the power is cut after more and more operations of creating and writing a file
+    for crash_after in 0.. {
+        let device = Arc::new(FaultyBlockDevice::new(BLOCKS as usize));
+        let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        root_inode.create("old").unwrap().write_at(0, &data);
+        efs.lock().sync();
+        device.fail_after(crash_after, Fault::Drop);
+        root_inode.create("new").unwrap().write_at(0, &data);
+        efs.lock().sync();
+        let log = device.write_log();
+        let efs = reopen(&device);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let mut buf = alloc::vec![0u8; data.len()];
+        let old = root_inode.find("old").unwrap();
+        assert_eq!(old.read_at(0, &mut buf), data.len());
+        assert_eq!(
+            buf, data,
+            "old is damaged when crashing after {}",
+            crash_after
+        );
+        if log.iter().all(|record| record.fault.is_none()) {
+            // the whole new file reached the disk
+            let new = root_inode.find("new").unwrap();
+            assert_eq!(new.read_at(0, &mut buf), data.len());
+            assert_eq!(buf, data);
+            break;
+        }
+    }
+}
+
+#[test]
+fn crashed_images_stay_mountable() {
+    let _lock = CACHE_LOCK.lock();
+    let data = file_data();
+    let fill_data = alloc::vec![0xa5u8; data.len()];
+    for fault in [Fault::Drop, Fault::Tear, Fault::Error] {
+        for crash_after in 0.. {
+            let device = Arc::new(FaultyBlockDevice::new(BLOCKS as usize));
+            let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+            let root_inode = EasyFileSystem::root_inode(&efs);
+            root_inode.create("old").unwrap().write_at(0, &data);
+            efs.lock().sync();
+            let super_block = alloc::format!("{:?}", efs.lock().super_block());
+            let synced = if fault == Fault::Error {
+                // an error panics, which must not drop inode handles on the way,
+                // so it is injected into writing back the cache only
+                root_inode.create("new").unwrap().write_at(0, &data);
+                device.fail_after(crash_after, fault);
+                catch_unwind(AssertUnwindSafe(|| efs.lock().sync())).is_ok()
+            } else {
+                device.fail_after(crash_after, fault);
+                root_inode.create("new").unwrap().write_at(0, &data);
+                efs.lock().sync();
+                device
+                    .write_log()
+                    .iter()
+                    .all(|record| record.fault.is_none())
+            };
+            let efs = reopen(&device);
+            device.heal();
+            let context = alloc::format!("{:?} after {} operations", fault, crash_after);
+            assert_eq!(
+                alloc::format!("{:?}", efs.lock().super_block()),
+                super_block,
+                "{}",
+                context
+            );
+            let root_inode = EasyFileSystem::root_inode(&efs);
+            if let Some(new) = root_inode.find("new") {
+                assert!(new.stat().size as usize <= data.len(), "{}", context);
+            }
+            // an inode or a block of old missing from the bitmaps would be handed out again
+            let fill = root_inode.create("fill").unwrap();
+            let mut offset = 0;
+            for len in [fill_data.len(), BLOCK_SZ] {
+                loop {
+                    let written = fill.write_at(offset, &fill_data[..len]);
+                    if written == 0 {
+                        break;
+                    }
+                    offset += written;
+                }
+            }
+            let mut buf = alloc::vec![0u8; data.len()];
+            let old = root_inode.find("old").unwrap();
+            assert_eq!(old.read_at(0, &mut buf), data.len(), "{}", context);
+            assert_eq!(buf, data, "old is overwritten {}", context);
+            if synced {
+                let new = root_inode.find("new").unwrap();
+                assert_eq!(new.read_at(0, &mut buf), data.len());
+                assert_eq!(buf, data);
+                break;
+            }
+        }
+    }
+}
+
+#[test]
+fn read_errors_panic_and_heal() {
+    let _lock = CACHE_LOCK.lock();
+    let device = Arc::new(FaultyBlockDevice::new(BLOCKS as usize));
+    let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+    let file = EasyFileSystem::root_inode(&efs).create("file").unwrap();
+    let data = file_data();
+    file.write_at(0, &data);
+    // nothing is left to write back, so only the reads of the device fail
+    efs.lock().sync();
+    let mut buf = alloc::vec![0u8; data.len()];
+    device.fail_after(0, Fault::Error);
+    let result = catch_unwind(AssertUnwindSafe(|| file.read_at(0, &mut buf)));
+    assert!(result.is_err());
+    device.heal();
+    assert_eq!(file.read_at(0, &mut buf), data.len());
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn damaged_xattr_blocks_are_rejected() {
+    let _lock = CACHE_LOCK.lock();
+    let device = Arc::new(MemBlockDevice::new(BLOCKS as usize));
+    let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    root_inode
+        .create("file")
+        .unwrap()
+        .set_xattr("user.label", b"fixture")
+        .unwrap();
+    efs.lock().sync();
+    let image = device.to_bytes();
+    // the xattr block starts with the header of its only entry
+    let entry = [10, 7, 0];
+    let xattr_pos = image
+        .chunks(BLOCK_SZ)
+        .position(|block| block.starts_with(&entry) && &block[3..13] == b"user.label")
+        .unwrap()
+        * BLOCK_SZ;
+    let damages: [&[u8]; 2] = [
+        // the value runs past the block
+        &[10, 0xff, 0xff],
+        // the name is not UTF-8
+        &[10, 7, 0, 0xff],
+    ];
+    for damage in damages {
+        let mut damaged = image.clone();
+        damaged[xattr_pos..xattr_pos + damage.len()].copy_from_slice(damage);
+        let efs = EasyFileSystem::open(Arc::new(MemBlockDevice::from_bytes(&damaged)));
+        let file = EasyFileSystem::root_inode(&efs).find("file").unwrap();
+        assert!(file.get_xattr("user.label").is_none());
+        assert!(file.list_xattr().is_empty());
+        assert!(file.set_xattr("user.other", b"x").is_none());
+        assert!(file.remove_xattr("user.label").is_none());
+    }
+}
+
+#[test]
+fn damaged_compressed_clusters_read_short() {
+    let _lock = CACHE_LOCK.lock();
+    let device = Arc::new(MemBlockDevice::new(BLOCKS as usize));
+    let efs = EasyFileSystem::create(device.clone(), BLOCKS, 1);
+    // four clusters, the last one partial
+    let data: Vec<u8> = (0..12 * BLOCK_SZ + 100).map(|i| (i / 64) as u8).collect();
+    let file = EasyFileSystem::root_inode(&efs).create("file").unwrap();
+    file.write_at(0, &data);
+    file.compress().unwrap();
+    efs.lock().sync();
+    let image = device.to_bytes();
+    // the compressed data starts with the uncompressed size and the end of each cluster
+    let table_pos = image
+        .chunks(BLOCK_SZ)
+        .position(|block| block.starts_with(&(data.len() as u32).to_le_bytes()))
+        .unwrap()
+        * BLOCK_SZ;
+    let damages: [(usize, &[u8], usize); 3] = [
+        // the first cluster ends past the data
+        (4, &[0xff; 4], 0),
+        // the second cluster ends before it starts
+        (8, &[0; 4], 4 * BLOCK_SZ),
+        // the first cluster does not decompress
+        (20, &[0xff; 8], 0),
+    ];
+    for (pos, damage, read_size) in damages {
+        let mut damaged = image.clone();
+        damaged[table_pos + pos..table_pos + pos + damage.len()].copy_from_slice(damage);
+        let efs = EasyFileSystem::open(Arc::new(MemBlockDevice::from_bytes(&damaged)));
+        let file = EasyFileSystem::root_inode(&efs).find("file").unwrap();
+        let mut buf = alloc::vec![0u8; data.len()];
+        assert_eq!(file.read_at(0, &mut buf), read_size);
+        assert_eq!(buf[..read_size], data[..read_size]);
+    }
+}
+
+#[test]
+fn writes_fail_when_the_device_is_full() {
+    let _lock = CACHE_LOCK.lock();
+    let device = Arc::new(MemBlockDevice::new(BLOCKS as usize));
+    let efs = EasyFileSystem::create(device, BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let data = file_data();
+    let original = root_inode.create("original").unwrap();
+    original.write_at(0, &data);
+    let clone = root_inode.create("clone").unwrap();
+    super::Inode::clone_from(&clone, &original).unwrap();
+    let fill = root_inode.create("fill").unwrap();
+    let mut offset = 0;
+    // large writes first, then single blocks for the rest
+    for len in [data.len(), BLOCK_SZ] {
+        loop {
+            let written = fill.write_at(offset, &data[..len]);
+            if written == 0 {
+                break;
+            }
+            offset += written;
+        }
+    }
+    assert_eq!(efs.lock().usage().free_blocks, 0);
+    // a shared block cannot be copied on write
+    assert_eq!(clone.write_at(0, b"changed"), 0);
+    assert_eq!(original.write_at(BLOCK_SZ, b"changed"), 0);
+    assert!(clone.set_xattr("user.label", b"fixture").is_none());
+    let mut buf = alloc::vec![0u8; data.len()];
+    assert_eq!(clone.read_at(0, &mut buf), data.len());
+    assert_eq!(buf, data);
+    // space freed by the filler is used again
+    fill.clear();
+    assert_eq!(clone.write_at(0, b"changed"), 7);
+    assert_eq!(original.read_at(0, &mut buf), data.len());
+    assert_eq!(buf, data);
+}
+
+#[test]
+fn unlink_releases_the_last_link() {
+    let _lock = CACHE_LOCK.lock();
+    let device = Arc::new(MemBlockDevice::new(BLOCKS as usize));
+    let efs = EasyFileSystem::create(device, BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let used = |efs: &Arc<Mutex<EasyFileSystem>>| {
+        let usage = efs.lock().usage();
+        let quota = efs.lock().quota(7);
+        (
+            usage.free_blocks,
+            usage.used_inodes,
+            quota.blocks_used,
+            quota.inodes_used,
+        )
+    };
+    // the blocks of the entries of the root and of the quota record stay in use
+    root_inode.create("kept").unwrap();
+    efs.lock().set_quota(7, 0, 0).unwrap();
+    let baseline = used(&efs);
+    let data = file_data();
+    let (_, file) = root_inode.create_inode_as("file", 7).unwrap();
+    file.write_at(0, &data);
+    file.set_xattr("user.label", b"fixture").unwrap();
+    drop(file);
+    root_inode.link("link", "file").unwrap();
+    root_inode.unlink("file").unwrap();
+    // a link is left
+    assert_ne!(used(&efs), baseline);
+    let file = root_inode.find("link").unwrap();
+    assert_eq!(file.stat().nlink, 1);
+    root_inode.unlink("link").unwrap();
+    // the open handle keeps the inode until it is dropped
+    assert_ne!(used(&efs), baseline);
+    let mut buf = alloc::vec![0u8; data.len()];
+    assert_eq!(file.read_at(0, &mut buf), data.len());
+    assert_eq!(buf, data);
+    drop(file);
+    assert_eq!(used(&efs), baseline);
+    // a directory is removed once empty
+    let (_, dir) = root_inode.mkdir_as("dir", 7).unwrap();
+    dir.create_inode_as("file", 7).unwrap();
+    assert!(root_inode.unlink("dir").is_none());
+    dir.unlink("file").unwrap();
+    drop(dir);
+    root_inode.unlink("dir").unwrap();
+    assert_eq!(used(&efs), baseline);
+    assert_eq!(root_inode.ls(), ["kept"]);
+}
+
+#[test]
+fn grow_uses_only_the_blocks_of_the_device_it_can_place() {
+    let _lock = CACHE_LOCK.lock();
+    let device = Arc::new(MemBlockDevice::new(3 * BLOCKS as usize));
+    let efs = EasyFileSystem::create(device, BLOCKS, 1);
+    let mut fs = efs.lock();
+    assert!(fs.grow(3 * BLOCKS + 1).is_none());
+    let super_block = fs.super_block();
+    let capacity = super_block.data_bitmap_blocks * BLOCK_SZ as u32 * 8;
+    let full = BLOCKS + capacity - super_block.data_area_blocks;
+    // a single block past what the bitmap covers cannot make a region of its own
+    fs.grow(full + 1).unwrap();
+    assert_eq!(fs.total_blocks(), full);
+    assert_eq!(fs.usage().data_blocks, capacity);
+    assert!(fs.grow(full + 1).is_none());
+    // two blocks make a region of a bitmap block and a data block
+    fs.grow(full + 2).unwrap();
+    assert_eq!(fs.total_blocks(), full + 2);
+    assert_eq!(fs.usage().data_blocks, capacity + 1);
+}
+
+#[test]
+fn snapshots_taken_during_writes_stay_consistent() {
+    let _lock = CACHE_LOCK.lock();
+    let device = Arc::new(MemBlockDevice::new(BLOCKS as usize));
+    let efs = EasyFileSystem::create(device, BLOCKS, 1);
+    let root_inode = EasyFileSystem::root_inode(&efs);
+    let file = root_inode.create("file").unwrap();
+    let len = 8 * BLOCK_SZ;
+    file.write_at(0, &alloc::vec![0u8; len]);
+    let writer = std::thread::spawn(move || {
+        for round in 1..=200u8 {
+            file.write_at(0, &alloc::vec![round; len]);
+        }
+    });
+    let read_snapshot = |name: &str| {
+        let snapshot_root = EasyFileSystem::snapshot_root(&efs, name).unwrap();
+        let mut buf = alloc::vec![0u8; len];
+        assert_eq!(
+            snapshot_root.find("file").unwrap().read_at(0, &mut buf),
+            len
+        );
+        buf
+    };
+    let mut snapshots = Vec::new();
+    while !writer.is_finished() && snapshots.len() < 20 {
+        let name = alloc::format!("s{}", snapshots.len());
+        EasyFileSystem::create_snapshot(&efs, &name).unwrap();
+        let data = read_snapshot(&name);
+        snapshots.push((name, data));
+    }
+    writer.join().unwrap();
+    // each snapshot holds the data of a whole write, which later writes leave alone
+    for (name, data) in snapshots {
+        assert!(data.iter().all(|byte| *byte == data[0]));
+        assert_eq!(read_snapshot(&name), data);
+    }
+}
+
+#[test]
+fn images_of_the_old_layout_are_rejected() {
+    let _lock = CACHE_LOCK.lock();
+    let device = Arc::new(MemBlockDevice::new(BLOCKS as usize));
+    EasyFileSystem::create(device.clone(), BLOCKS, 1)
+        .lock()
+        .sync();
+    let mut image = device.to_bytes();
+    let formatted = |image: &[u8]| {
+        let device: Arc<dyn BlockDevice> = Arc::new(MemBlockDevice::from_bytes(image));
+        EasyFileSystem::is_formatted(&device)
+    };
+    assert!(formatted(&image));
+    // the magic number of the original layout
+    image[..4].copy_from_slice(&0x3b800001u32.to_le_bytes());
+    assert!(!formatted(&image));
+}
+
+#[test]
+fn snapshots_of_a_nearly_full_device_leave_nothing_behind() {
+    let _lock = CACHE_LOCK.lock();
+    let data = file_data();
+    // fewer free blocks than a snapshot needs make it fail at every step of copying
+    for spare_count in 0.. {
+        let device = Arc::new(MemBlockDevice::new(BLOCKS as usize));
+        let efs = EasyFileSystem::create(device, BLOCKS, 1);
+        let root_inode = EasyFileSystem::root_inode(&efs);
+        let dir = root_inode.mkdir("dir").unwrap();
+        for i in 0..20 {
+            let file = dir.create(&alloc::format!("file{}", i)).unwrap();
+            file.write_at(0, &data[..BLOCK_SZ]);
+            file.set_xattr("user.label", b"fixture").unwrap();
+        }
+        root_inode.create("file").unwrap().write_at(0, &data);
+        // the table of reference counts never shrinks, a first snapshot grows it
+        EasyFileSystem::create_snapshot(&efs, "first").unwrap();
+        efs.lock().delete_snapshot("first").unwrap();
+        let spares: Vec<_> = (0..spare_count)
+            .map(|i| {
+                let spare = root_inode.create(&alloc::format!("spare{}", i)).unwrap();
+                spare.write_at(0, &data[..BLOCK_SZ]);
+                spare
+            })
+            .collect();
+        let fill = root_inode.create("fill").unwrap();
+        let mut offset = 0;
+        for len in [data.len(), BLOCK_SZ] {
+            loop {
+                let written = fill.write_at(offset, &data[..len]);
+                if written == 0 {
+                    break;
+                }
+                offset += written;
+            }
+        }
+        spares.iter().for_each(|spare| spare.clear());
+        // the open filler is kept out of the snapshot, whose sharing would grow the table
+        root_inode.unlink("fill").unwrap();
+        // the filler may leave a block it could not map
+        let usage = efs.lock().usage();
+        if EasyFileSystem::create_snapshot(&efs, "snapshot").is_some() {
+            let snapshot_root = EasyFileSystem::snapshot_root(&efs, "snapshot").unwrap();
+            let mut buf = alloc::vec![0u8; data.len()];
+            assert_eq!(
+                snapshot_root.find("file").unwrap().read_at(0, &mut buf),
+                data.len()
+            );
+            assert_eq!(buf, data);
+            let file = snapshot_root.find("dir").unwrap().find("file19").unwrap();
+            assert_eq!(file.get_xattr("user.label").unwrap(), b"fixture");
+            assert!(spare_count > 0);
+            break;
+        }
+        let after = efs.lock().usage();
+        assert_eq!(
+            (after.free_blocks, after.used_inodes),
+            (usage.free_blocks, usage.used_inodes),
+            "a failed snapshot with {} free blocks leaks",
+            usage.free_blocks
+        );
+        assert!(efs.lock().list_snapshots().is_empty());
+        // no reference is left on the blocks, which are written in place
+        assert_eq!(
+            root_inode.find("file").unwrap().write_at(0, &data),
+            data.len()
+        );
+        assert_eq!(efs.lock().usage().free_blocks, usage.free_blocks);
+    }
+}
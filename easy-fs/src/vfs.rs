@@ -1,12 +1,43 @@
 use super::{
     block_cache_sync_all, get_block_cache, BlockDevice, DirEntry, DiskInode, DiskInodeType,
-    EasyFileSystem, DIRENT_SZ,
+    EasyFileSystem, RawName, Xattrs, BLOCK_SZ, DIRENT_SZ, INODE_COMPRESSED, INODE_ENCRYPTED,
+    MAX_FILE_SIZE, MAX_MAJOR, MAX_MINOR, NAME_LENGTH_LIMIT, ROOT_UID, XATTR_NAME_LIMIT,
 };
+use crate::compress::compress_clusters;
+use crate::crypt::XtsKey;
+use alloc::format;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use log::info;
-use spin::{Mutex, MutexGuard};
+use spin::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A data block of block size
+type DataBlock = [u8; BLOCK_SZ];
+
+/// An entry of a directory as returned by `Inode::readdir`
+#[derive(Clone, Debug)]
+pub struct DirEntryInfo {
+    pub name: String,
+    pub inode_id: u32,
+    pub inode_type: DiskInodeType,
+}
+
+/// Metadata of an inode as returned by `Inode::stat`
+#[derive(Clone, Debug)]
+pub struct InodeStat {
+    pub inode_id: u32,
+    pub inode_type: DiskInodeType,
+    /// Size of the data as read by `read_at`
+    pub size: u32,
+    /// Number of blocks allocated, including indirect blocks and extended attributes
+    pub blocks: u32,
+    /// Number of directory entries referring to the inode
+    pub nlink: u32,
+    /// Owner uid
+    pub uid: u32,
+    /// (major, minor) number of a device
+    pub device: Option<(u32, u32)>,
+}
 
 /// Virtual filesystem layer over easy-fs
 pub struct Inode {
@@ -14,6 +45,14 @@ pub struct Inode {
     block_offset: usize,
     fs: Arc<Mutex<EasyFileSystem>>,
     block_device: Arc<dyn BlockDevice>,
+    /// Whether modifications are rejected, e.g. inside a snapshot
+    read_only: bool,
+    /// Held for reading by lookups and reads of current inode and for writing by
+    /// its modifications. It is taken before the filesystem lock, which is only
+    /// held briefly to allocate blocks or update the tables of the filesystem
+    lock: RwLock<()>,
+    /// Quiescing lock of the filesystem, held for reading before `lock`
+    quiesce: Arc<RwLock<()>>,
 }
 
 impl Inode {
@@ -23,14 +62,43 @@ impl Inode {
         block_offset: usize,
         fs: Arc<Mutex<EasyFileSystem>>,
         block_device: Arc<dyn BlockDevice>,
+        quiesce: Arc<RwLock<()>>,
     ) -> Self {
         Self {
             block_id: block_id as usize,
             block_offset,
             fs,
             block_device,
+            read_only: false,
+            lock: RwLock::new(()),
+            quiesce,
         }
     }
+    /// Lock current inode for reading, so that the whole filesystem is not
+    /// relocated or copied meanwhile either
+    fn read_lock(&self) -> (RwLockReadGuard<'_, ()>, RwLockReadGuard<'_, ()>) {
+        let quiesced = self.quiesce.read();
+        (self.lock.read(), quiesced)
+    }
+    /// Lock current inode for writing, so that the whole filesystem is not
+    /// relocated or copied meanwhile either
+    fn write_lock(&self) -> (RwLockWriteGuard<'_, ()>, RwLockReadGuard<'_, ()>) {
+        let quiesced = self.quiesce.read();
+        (self.lock.write(), quiesced)
+    }
+    /// Turn into a read-only inode, whose descendants are read-only as well
+    pub fn into_read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+    /// Whether modifications through this inode are rejected
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    /// Get the filesystem this inode belongs to
+    pub fn fs(&self) -> Arc<Mutex<EasyFileSystem>> {
+        Arc::clone(&self.fs)
+    }
     /// Call a function over a disk inode to read it
     fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
         get_block_cache(self.block_id, Arc::clone(&self.block_device))
@@ -45,10 +113,59 @@ impl Inode {
             .modify(self.block_offset, f)
     }
 
+    /// Get the key encrypting current inode, if it is encrypted and the key is loaded.
+    /// The disk inode is released before the filesystem lock is taken
+    fn loaded_key(&self) -> Option<Arc<XtsKey>> {
+        let (encrypted, key_id) =
+            self.read_disk_inode(|disk_inode| (disk_inode.is_encrypted(), disk_inode.key_id));
+        if !encrypted {
+            return None;
+        }
+        self.fs.lock().key(key_id)
+    }
+
+    /// Get a name as stored in directory `dir` with its loaded key, encrypted if `dir` is.
+    /// Fail if the name is too long or the key of `dir` is not loaded
+    fn encode_name(name: &str, dir: &DiskInode, key: Option<&XtsKey>) -> Option<RawName> {
+        if name.len() > NAME_LENGTH_LIMIT {
+            return None;
+        }
+        let mut raw = [0u8; NAME_LENGTH_LIMIT + 1];
+        raw[..name.len()].copy_from_slice(name.as_bytes());
+        if dir.is_encrypted() {
+            key?.encrypt_name(&mut raw, dir.nonce);
+        }
+        Some(raw)
+    }
+
+    /// Get a name stored in directory `dir` with its loaded key. Without the key,
+    /// the hex digits of the beginning of the encrypted name are returned instead
+    fn decode_name(raw: &RawName, dir: &DiskInode, key: Option<&XtsKey>) -> String {
+        let mut raw = *raw;
+        if dir.is_encrypted() {
+            match key {
+                Some(key) => key.decrypt_name(&mut raw, dir.nonce),
+                None => {
+                    return raw[..NAME_LENGTH_LIMIT / 2]
+                        .iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect()
+                }
+            }
+        }
+        String::from(DirEntry::from_raw(raw, 0).name())
+    }
+
     /// Find inode under a disk inode by name
-    fn find_inode_id(&self, name: &str, disk_inode: &DiskInode) -> Option<u32> {
+    fn find_inode_id(
+        &self,
+        name: &str,
+        disk_inode: &DiskInode,
+        key: Option<&XtsKey>,
+    ) -> Option<u32> {
         // assert it is a directory
         assert!(disk_inode.is_dir());
+        let raw = Self::encode_name(name, disk_inode, key)?;
         let file_count = (disk_inode.size as usize) / DIRENT_SZ;
         let mut dirent = DirEntry::empty();
         for i in 0..file_count {
@@ -56,108 +173,132 @@ impl Inode {
                 disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
                 DIRENT_SZ,
             );
-            if dirent.name() == name {
+            if !dirent.is_unused() && dirent.raw_name() == &raw {
                 return Some(dirent.inode_number() as u32);
             }
         }
         None
     }
 
-    //只有根目录这一层，所以可以直接遍历超出硬链接
-    pub fn calc_hard_links(&self, inode: u32) -> usize {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            assert!(disk_inode.is_dir());
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            let mut dirent = DirEntry::empty();
-            let mut count = 0;
-            for i in 0..file_count {
-                assert_eq!(
-                    disk_inode.read_at(DIRENT_SZ * i, dirent.as_bytes_mut(), &self.block_device,),
-                    DIRENT_SZ,
-                );
-                if dirent.inode_number() == inode {
-                    count += 1;
-                }
-            }
-            count
-        })
-    }
-
     /// Find inode under current inode by name
     pub fn find(&self, name: &str) -> Option<Arc<Inode>> {
-        let fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode).map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                Arc::new(Self::new(
-                    block_id,
-                    block_offset,
-                    self.fs.clone(),
-                    self.block_device.clone(),
-                ))
-            })
-        })
+        self.find_node(name).map(|(_, inode)| inode)
     }
 
-    /// Find inode under current inode by name
+    /// Find inode under current inode by name, the handle is shared with other users
     pub fn find_node(&self, name: &str) -> Option<(u32, Arc<Inode>)> {
-        let fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            self.find_inode_id(name, disk_inode).map(|inode_id| {
-                let (block_id, block_offset) = fs.get_disk_inode_pos(inode_id);
-                (
-                    inode_id,
-                    Arc::new(Self::new(
-                        block_id,
-                        block_offset,
-                        self.fs.clone(),
-                        self.block_device.clone(),
-                    )),
-                )
-            })
-        })
+        let _lock = self.read_lock();
+        self.find_child(name)
     }
 
-    /// Increase the size of a disk inode
-    fn increase_size(
-        &self,
-        new_size: u32,
-        disk_inode: &mut DiskInode,
-        fs: &mut MutexGuard<EasyFileSystem>,
-    ) {
-        if new_size < disk_inode.size {
-            return;
+    /// Find inode under current inode by name, with the lock of current inode held
+    fn find_child(&self, name: &str) -> Option<(u32, Arc<Inode>)> {
+        let key = self.loaded_key();
+        let inode_id = self
+            .read_disk_inode(|disk_inode| self.find_inode_id(name, disk_inode, key.as_deref()))?;
+        let inode = self.fs.lock().get_inode(&self.fs, inode_id, self.read_only);
+        Some((inode_id, inode))
+    }
+
+    /// Increase the size of current inode, charging the new blocks to its owner.
+    /// Return false if the owner is out of quota or the device is full
+    fn increase_size(&self, new_size: u32, fs: &mut MutexGuard<EasyFileSystem>) -> bool {
+        let (blocks_needed, uid) = self.read_disk_inode(|disk_inode| {
+            if new_size < disk_inode.size {
+                (0, disk_inode.uid)
+            } else {
+                (disk_inode.blocks_num_needed(new_size), disk_inode.uid)
+            }
+        });
+        if !fs.charge_blocks(uid, blocks_needed) {
+            return false;
         }
-        let blocks_needed = disk_inode.blocks_num_needed(new_size);
-        let mut v: Vec<u32> = Vec::new();
-        for _ in 0..blocks_needed {
-            v.push(fs.alloc_data());
+        let v = match fs.try_alloc_data_blocks(blocks_needed) {
+            Some(v) => v,
+            None => {
+                fs.uncharge_blocks(uid, blocks_needed);
+                return false;
+            }
+        };
+        self.modify_disk_inode(|disk_inode| {
+            if new_size >= disk_inode.size {
+                disk_inode.increase_size(new_size, v, &self.block_device);
+            }
+        });
+        true
+    }
+
+    /// Append a directory entry to current inode, adding a link to the inode it refers to.
+    /// Fail if its owner is out of quota
+    fn append_dirent(&self, name: &str, inode_id: u32) -> Option<()> {
+        let key = self.loaded_key();
+        let (file_count, raw) = self.read_disk_inode(|root_inode| {
+            (
+                (root_inode.size as usize) / DIRENT_SZ,
+                Self::encode_name(name, root_inode, key.as_deref()),
+            )
+        });
+        let raw = raw?;
+        let new_size = (file_count + 1) * DIRENT_SZ;
+        // increase size
+        if !self.increase_size(new_size as u32, &mut self.fs.lock()) {
+            return None;
         }
-        disk_inode.increase_size(new_size, v, &self.block_device);
+        // write dirent
+        let dirent = DirEntry::from_raw(raw, inode_id);
+        self.modify_disk_inode(|root_inode| {
+            root_inode.write_at(
+                file_count * DIRENT_SZ,
+                dirent.as_bytes(),
+                &self.block_device,
+            );
+        });
+        self.fs
+            .lock()
+            .modify_disk_inode(inode_id, |disk_inode| disk_inode.nlink += 1);
+        Some(())
     }
 
-    //内容直接设置为空，不删除
+    /// Remove the entry `name` of current directory. The inode it refers to is released
+    /// with its blocks once its last link is gone and it is no longer open.
+    /// Fail if it is a directory which is not empty
     pub fn unlink(&self, name: &str) -> Option<()> {
-        let _fs = self.fs.lock();
-        self.modify_disk_inode(|disk_inode| {
+        if self.read_only {
+            return None;
+        }
+        let _lock = self.write_lock();
+        // dropping the last handle of the inode releases it, see `drop`
+        let (target_id, target) = self.find_child(name)?;
+        let _target_lock = target.write_lock();
+        if target.read_disk_inode(|disk_inode| disk_inode.is_dir())
+            && !self.fs.lock().is_empty_dir(target_id)
+        {
+            return None;
+        }
+        let key = self.loaded_key();
+        let inode_id = self.modify_disk_inode(|disk_inode| {
+            let raw = Self::encode_name(name, disk_inode, key.as_deref())?;
             let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            (0..file_count).for_each(|i| {
+            (0..file_count).find_map(|i| {
                 let mut dirent = DirEntry::empty();
                 assert_eq!(
                     disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device,),
                     DIRENT_SZ,
                 );
-                if dirent.name() == name {
-                    disk_inode.write_at(
-                        i * DIRENT_SZ,
-                        DirEntry::empty().as_bytes(),
-                        &self.block_device,
-                    );
+                if dirent.is_unused() || dirent.raw_name() != &raw {
+                    return None;
                 }
+                disk_inode.write_at(
+                    i * DIRENT_SZ,
+                    DirEntry::empty().as_bytes(),
+                    &self.block_device,
+                );
+                Some(dirent.inode_number())
             })
-        });
-
+        })?;
+        self.fs
+            .lock()
+            .modify_disk_inode(inode_id, |disk_inode| disk_inode.nlink -= 1);
         block_cache_sync_all();
 
         Some(())
@@ -165,27 +306,33 @@ impl Inode {
 
     /// Create inode under current inode by name
     pub fn link(&self, new_path: &str, old_path: &str) -> Option<Arc<Inode>> {
-        if let Some((to_inode_id, to_inode)) = self.find_node(old_path) {
-            let mut fs = self.fs.lock();
-            self.modify_disk_inode(|root_inode| {
-                // append file in the dirent
-                let file_count = (root_inode.size as usize) / DIRENT_SZ;
-                let new_size = (file_count + 1) * DIRENT_SZ;
-                // increase size
-                self.increase_size(new_size as u32, root_inode, &mut fs);
-                // write dirent
-                let dirent = DirEntry::new(new_path, to_inode_id);
-                root_inode.write_at(
-                    file_count * DIRENT_SZ,
-                    dirent.as_bytes(),
-                    &self.block_device,
-                );
-            });
+        if self.read_only {
+            return None;
+        }
+        let _lock = self.write_lock();
+        if let Some((to_inode_id, to_inode)) = self.find_child(old_path) {
+            self.append_dirent(new_path, to_inode_id)?;
             block_cache_sync_all();
-            return Some(to_inode);
+            Some(to_inode)
         } else {
+            None
+        }
+    }
+
+    /// Add an entry `name` under current inode for `inode`, which may be in any directory
+    /// of the same filesystem but must not be a directory. Fail if `name` exists
+    pub fn link_inode(&self, name: &str, inode: &Inode) -> Option<()> {
+        if self.read_only || !Arc::ptr_eq(&self.fs, &inode.fs) || inode.is_dir() {
+            return None;
+        }
+        let inode_id = inode.inode_id();
+        let _lock = self.write_lock();
+        if self.find_child(name).is_some() {
             return None;
         }
+        self.append_dirent(name, inode_id)?;
+        block_cache_sync_all();
+        Some(())
     }
 
     /// Create inode under current inode by name
@@ -199,105 +346,723 @@ impl Inode {
 
     /// Create inode under current inode by name
     pub fn create_inode(&self, name: &str) -> Option<(u32, Arc<Inode>)> {
-        let mut fs = self.fs.lock();
-        if self
-            .modify_disk_inode(|root_inode| {
-                // assert it is a directory
-                assert!(root_inode.is_dir());
-                // has the file been created?
-                self.find_inode_id(name, root_inode)
-            })
-            .is_some()
-        {
+        self.create_inode_as(name, ROOT_UID)
+    }
+
+    /// Create inode owned by `uid` under current inode by name,
+    /// fail if `uid` is out of inode quota or the directory owner out of block quota
+    pub fn create_inode_as(&self, name: &str, uid: u32) -> Option<(u32, Arc<Inode>)> {
+        self.mknod_as(name, uid, DiskInodeType::File, 0, 0)
+    }
+
+    /// Create an empty directory under current inode by name
+    pub fn mkdir(&self, name: &str) -> Option<Arc<Inode>> {
+        let (_, inode) = self.mkdir_as(name, ROOT_UID)?;
+        Some(inode)
+    }
+
+    /// Create an empty directory owned by `uid` under current inode by name,
+    /// fail if `uid` is out of inode quota or the directory owner out of block quota
+    pub fn mkdir_as(&self, name: &str, uid: u32) -> Option<(u32, Arc<Inode>)> {
+        self.create_node(name, uid, DiskInodeType::Directory, 0, 0)
+    }
+
+    /// Create an inode of any type but a directory owned by `uid` under current inode
+    /// by name, `major` and `minor` give the number of a device and are ignored otherwise.
+    /// Fail if `uid` is out of inode quota or the directory owner out of block quota
+    pub fn mknod_as(
+        &self,
+        name: &str,
+        uid: u32,
+        inode_type: DiskInodeType,
+        major: u32,
+        minor: u32,
+    ) -> Option<(u32, Arc<Inode>)> {
+        if inode_type == DiskInodeType::Directory {
             return None;
         }
-        // create a new file
-        // alloc a inode with an indirect block
-        let new_inode_id = fs.alloc_inode();
+        self.create_node(name, uid, inode_type, major, minor)
+    }
+
+    /// Create an inode of any type owned by `uid` under current inode by name
+    fn create_node(
+        &self,
+        name: &str,
+        uid: u32,
+        inode_type: DiskInodeType,
+        major: u32,
+        minor: u32,
+    ) -> Option<(u32, Arc<Inode>)> {
+        if self.read_only || major > MAX_MAJOR || minor > MAX_MINOR {
+            return None;
+        }
+        let _lock = self.write_lock();
+        let key = self.loaded_key();
+        let (existed, encrypted, key_id) = self.read_disk_inode(|root_inode| {
+            // assert it is a directory
+            assert!(root_inode.is_dir());
+            // has the file been created?
+            (
+                self.find_inode_id(name, root_inode, key.as_deref()),
+                root_inode.is_encrypted(),
+                root_inode.key_id,
+            )
+        });
+        if existed.is_some() {
+            return None;
+        }
+        // create a new file, nobody else can reach it before its entry is appended
+        let (new_inode_id, new_inode_block_id, new_inode_block_offset) = {
+            let mut fs = self.fs.lock();
+            if !fs.charge_inode(uid) {
+                return None;
+            }
+            let new_inode_id = match fs.alloc_inode() {
+                Some(inode_id) => inode_id,
+                None => {
+                    fs.uncharge_inode(uid);
+                    drop(fs);
+                    block_cache_sync_all();
+                    return None;
+                }
+            };
+            let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
+            (new_inode_id, block_id, block_offset)
+        };
 
         // initialize inode
-        let (new_inode_block_id, new_inode_block_offset) = fs.get_disk_inode_pos(new_inode_id);
         get_block_cache(new_inode_block_id as usize, Arc::clone(&self.block_device))
             .lock()
             .modify(new_inode_block_offset, |new_inode: &mut DiskInode| {
-                new_inode.initialize(DiskInodeType::File);
+                new_inode.initialize(inode_type);
+                new_inode.uid = uid;
+                if new_inode.is_device() {
+                    new_inode.set_device(major, minor);
+                }
+                // files and directories in an encrypted directory are encrypted with the same key
+                if encrypted && new_inode.has_data() {
+                    new_inode.flags |= INODE_ENCRYPTED;
+                    new_inode.key_id = key_id;
+                    new_inode.nonce = new_inode_id;
+                }
             });
-        self.modify_disk_inode(|root_inode| {
-            // append file in the dirent
-            let file_count = (root_inode.size as usize) / DIRENT_SZ;
-            let new_size = (file_count + 1) * DIRENT_SZ;
-            // increase size
-            self.increase_size(new_size as u32, root_inode, &mut fs);
-            // write dirent
-            let dirent = DirEntry::new(name, new_inode_id);
-            root_inode.write_at(
-                file_count * DIRENT_SZ,
-                dirent.as_bytes(),
-                &self.block_device,
-            );
-        });
+        // append file in the dirent
+        if self.append_dirent(name, new_inode_id).is_none() {
+            let mut fs = self.fs.lock();
+            fs.dealloc_inode(new_inode_id);
+            fs.uncharge_inode(uid);
+            drop(fs);
+            block_cache_sync_all();
+            return None;
+        }
 
-        let (block_id, block_offset) = fs.get_disk_inode_pos(new_inode_id);
         block_cache_sync_all();
         // return inode
-        Some((
-            new_inode_id,
-            Arc::new(Self::new(
-                block_id,
-                block_offset,
-                self.fs.clone(),
-                self.block_device.clone(),
-            )),
-        ))
-        // release efs lock automatically by compiler
+        let inode = self.fs.lock().get_inode(&self.fs, new_inode_id, false);
+        Some((new_inode_id, inode))
+    }
+
+    /// Get the metadata of current inode
+    pub fn stat(&self) -> InodeStat {
+        let _lock = self.read_lock();
+        let (inode_type, size, blocks, nlink, uid, device) = self.read_disk_inode(|disk_inode| {
+            (
+                disk_inode.inode_type(),
+                disk_inode.file_size(&self.block_device),
+                disk_inode.allocated_blocks() + (disk_inode.xattr != 0) as u32,
+                disk_inode.nlink,
+                disk_inode.uid,
+                disk_inode.device(),
+            )
+        });
+        InodeStat {
+            inode_id: self.inode_id(),
+            inode_type,
+            size,
+            blocks,
+            nlink,
+            uid,
+            device,
+        }
+    }
+
+    /// Get the id of current inode
+    pub fn inode_id(&self) -> u32 {
+        self.fs
+            .lock()
+            .get_inode_id(self.block_id as u32, self.block_offset)
+    }
+
+    /// Get the type of current inode
+    pub fn inode_type(&self) -> DiskInodeType {
+        let _lock = self.read_lock();
+        self.read_disk_inode(|disk_inode| disk_inode.inode_type())
+    }
+
+    /// Get the (major, minor) number of current inode if it is a device
+    pub fn device(&self) -> Option<(u32, u32)> {
+        let _lock = self.read_lock();
+        self.read_disk_inode(|disk_inode| disk_inode.device())
+    }
+
+    /// Whether current inode is a directory
+    pub fn is_dir(&self) -> bool {
+        let _lock = self.read_lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_dir())
+    }
+
+    /// Get the uid owning current inode
+    pub fn owner(&self) -> u32 {
+        let _lock = self.read_lock();
+        self.read_disk_inode(|disk_inode| disk_inode.uid)
     }
 
     /// List inodes under current inode
     pub fn ls(&self) -> Vec<String> {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| {
-            let file_count = (disk_inode.size as usize) / DIRENT_SZ;
-            let mut v: Vec<String> = Vec::new();
-            for i in 0..file_count {
-                let mut dirent = DirEntry::empty();
-                assert_eq!(
-                    disk_inode.read_at(i * DIRENT_SZ, dirent.as_bytes_mut(), &self.block_device,),
-                    DIRENT_SZ,
-                );
+        let mut v: Vec<String> = Vec::new();
+        let mut offset = 0;
+        while let Some((entry, next_offset)) = self.readdir(offset) {
+            v.push(entry.name);
+            offset = next_offset;
+        }
+        v
+    }
 
-                if 0 < dirent.inode_number() {
-                    v.push(String::from(dirent.name()));
+    /// Read the first used entry of current directory at or after byte `offset`,
+    /// return it with the offset to continue from. Nothing is read from a file
+    pub fn readdir(&self, mut offset: usize) -> Option<(DirEntryInfo, usize)> {
+        let _lock = self.read_lock();
+        let key = self.loaded_key();
+        let (name, inode_id) = self.read_disk_inode(|disk_inode| {
+            if !disk_inode.is_dir() {
+                return None;
+            }
+            let mut dirent = DirEntry::empty();
+            // skip to the start of an entry
+            offset = (offset + DIRENT_SZ - 1) / DIRENT_SZ * DIRENT_SZ;
+            while offset + DIRENT_SZ <= disk_inode.size as usize {
+                disk_inode.read_at(offset, dirent.as_bytes_mut(), &self.block_device);
+                offset += DIRENT_SZ;
+                if !dirent.is_unused() {
+                    return Some((
+                        Self::decode_name(dirent.raw_name(), disk_inode, key.as_deref()),
+                        dirent.inode_number(),
+                    ));
                 }
             }
-            v
-        })
+            None
+        })?;
+        // the entry may be in the same block as current inode, which is released by now
+        let inode_type = self
+            .fs
+            .lock()
+            .read_disk_inode(inode_id, |disk_inode| disk_inode.inode_type());
+        Some((
+            DirEntryInfo {
+                name,
+                inode_id,
+                inode_type,
+            },
+            offset,
+        ))
     }
-    /// Read data from current inode
+    /// Read data from current inode, nothing is read from an encrypted file
+    /// whose key is not loaded
     pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let _fs = self.fs.lock();
-        self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
+        let _lock = self.read_lock();
+        if !self.read_disk_inode(|disk_inode| disk_inode.is_file() && disk_inode.is_encrypted()) {
+            return self
+                .read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device));
+        }
+        match self.loaded_key() {
+            Some(key) => self
+                .read_disk_inode(|disk_inode| self.read_encrypted(disk_inode, &key, offset, buf)),
+            None => 0,
+        }
     }
-    /// Write data to current inode
+    /// Read data of an encrypted file, decrypting every block the range overlaps
+    fn read_encrypted(
+        &self,
+        disk_inode: &DiskInode,
+        key: &XtsKey,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> usize {
+        let end = (offset + buf.len()).min(disk_inode.size as usize);
+        if offset >= end {
+            return 0;
+        }
+        let mut block = [0u8; BLOCK_SZ];
+        for inner_id in offset / BLOCK_SZ..(end + BLOCK_SZ - 1) / BLOCK_SZ {
+            let block_id = disk_inode.get_block_id(inner_id as u32, &self.block_device);
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(0, |data_block: &DataBlock| {
+                    block.copy_from_slice(data_block)
+                });
+            key.decrypt_block(&mut block, disk_inode.nonce, inner_id as u32);
+            let block_start = inner_id * BLOCK_SZ;
+            let from = offset.max(block_start);
+            let to = end.min(block_start + BLOCK_SZ);
+            buf[from - offset..to - offset]
+                .copy_from_slice(&block[from - block_start..to - block_start]);
+        }
+        end - offset
+    }
+    /// Write data to an encrypted file, re-encrypting every block the range overlaps.
+    /// The bytes from `old_size` on have never been written and are zeroed,
+    /// so blocks in a gap before `offset` are re-encrypted as well
+    fn write_encrypted(&self, key: &XtsKey, offset: usize, buf: &[u8], old_size: usize) -> usize {
+        self.read_disk_inode(|disk_inode| {
+            let end = offset + buf.len();
+            for inner_id in offset.min(old_size) / BLOCK_SZ..(end + BLOCK_SZ - 1) / BLOCK_SZ {
+                let block_id = disk_inode.get_block_id(inner_id as u32, &self.block_device);
+                let block_start = inner_id * BLOCK_SZ;
+                get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                    .lock()
+                    .modify(0, |data_block: &mut DataBlock| {
+                        key.decrypt_block(data_block, disk_inode.nonce, inner_id as u32);
+                        if old_size < block_start + BLOCK_SZ {
+                            data_block[old_size.saturating_sub(block_start)..].fill(0);
+                        }
+                        let from = offset.max(block_start);
+                        let to = end.min(block_start + BLOCK_SZ);
+                        if from < to {
+                            data_block[from - block_start..to - block_start]
+                                .copy_from_slice(&buf[from - offset..to - offset]);
+                        }
+                        key.encrypt_block(data_block, disk_inode.nonce, inner_id as u32);
+                    });
+            }
+            buf.len()
+        })
+    }
+    /// Give current inode private copies of the shared data blocks
+    /// overlapping the byte range [start, end). Return false if the device is full,
+    /// the blocks copied so far stay private
+    fn unshare_blocks(
+        &self,
+        start: usize,
+        end: usize,
+        fs: &mut MutexGuard<EasyFileSystem>,
+    ) -> bool {
+        let data_blocks = self.read_disk_inode(|disk_inode| disk_inode.data_blocks()) as usize;
+        let end_block = ((end + BLOCK_SZ - 1) / BLOCK_SZ).min(data_blocks);
+        for inner_id in (start / BLOCK_SZ)..end_block {
+            let block_id = self.read_disk_inode(|disk_inode| {
+                disk_inode.get_block_id(inner_id as u32, &self.block_device)
+            });
+            if fs.data_refs(block_id) == 0 {
+                continue;
+            }
+            // copy on write
+            let new_block_id = match fs.try_alloc_data() {
+                Some(block_id) => block_id,
+                None => return false,
+            };
+            get_block_cache(block_id as usize, Arc::clone(&self.block_device))
+                .lock()
+                .read(0, |src: &DataBlock| {
+                    get_block_cache(new_block_id as usize, Arc::clone(&self.block_device))
+                        .lock()
+                        .modify(0, |dst: &mut DataBlock| dst.copy_from_slice(src));
+                });
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.set_block_id(inner_id as u32, new_block_id, &self.block_device)
+            });
+            fs.dealloc_data(block_id);
+        }
+        true
+    }
+    /// Write data to current inode, nothing is written if the owner is out of quota,
+    /// the device is full, current inode is an encrypted file whose key is not loaded
+    /// or has no data, like a FIFO or device
     pub fn write_at(&self, offset: usize, buf: &[u8]) -> usize {
-        let mut fs = self.fs.lock();
-        let size = self.modify_disk_inode(|disk_inode| {
-            self.increase_size((offset + buf.len()) as u32, disk_inode, &mut fs);
-            disk_inode.write_at(offset, buf, &self.block_device)
+        if self.read_only {
+            return 0;
+        }
+        let _lock = self.write_lock();
+        let (old_size, encrypted, has_data) = self.read_disk_inode(|disk_inode| {
+            (
+                disk_inode.size as usize,
+                disk_inode.is_file() && disk_inode.is_encrypted(),
+                disk_inode.has_data(),
+            )
         });
+        if !has_data {
+            return 0;
+        }
+        let key = if encrypted {
+            match self.loaded_key() {
+                Some(key) => Some(key),
+                None => return 0,
+            }
+        } else {
+            None
+        };
+        // the filesystem lock is only held to allocate and release blocks
+        // compressed data cannot be modified in place
+        if self.read_disk_inode(|disk_inode| disk_inode.is_compressed())
+            && !self.replace_data(&self.read_all(), 0, &mut self.fs.lock())
+        {
+            return 0;
+        }
+        // a gap before an encrypted write is written as well
+        let start = match key {
+            Some(_) => offset.min(old_size),
+            None => offset,
+        };
+        // blocks added by growing the file are private already
+        let mut fs = self.fs.lock();
+        if !self.unshare_blocks(start, offset + buf.len(), &mut fs)
+            || !self.increase_size((offset + buf.len()) as u32, &mut fs)
+        {
+            return 0;
+        }
+        drop(fs);
+        let size = match key {
+            Some(key) => self.write_encrypted(&key, offset, buf, old_size),
+            None => self.modify_disk_inode(|disk_inode| {
+                disk_inode.write_at(offset, buf, &self.block_device)
+            }),
+        };
         block_cache_sync_all();
         size
     }
+    /// Reserve data blocks for the byte range [offset, offset + len), contiguous if possible,
+    /// so that later writes to it cannot run out of space. The blocks are charged to the owner
+    /// and read as zeros. Unless `keep_size` is set, the size grows to cover the range.
+    /// Fail if the range ends past the largest file, the owner is out of quota, the device
+    /// out of blocks, or current inode is compressed, an encrypted file whose key is not
+    /// loaded or has no data
+    pub fn allocate(&self, offset: usize, len: usize, keep_size: bool) -> Option<()> {
+        let end = match offset.checked_add(len) {
+            Some(end) if !self.read_only && end <= MAX_FILE_SIZE => end,
+            _ => return None,
+        };
+        let _lock = self.write_lock();
+        let (old_size, mapped, uid, compressed, encrypted, has_data) =
+            self.read_disk_inode(|disk_inode| {
+                (
+                    disk_inode.size as usize,
+                    disk_inode.mapped_blocks(),
+                    disk_inode.uid,
+                    disk_inode.is_compressed(),
+                    disk_inode.is_file() && disk_inode.is_encrypted(),
+                    disk_inode.has_data(),
+                )
+            });
+        let key = if encrypted {
+            Some(self.loaded_key()?)
+        } else {
+            None
+        };
+        if compressed || !has_data {
+            return None;
+        }
+        let target = ((end + BLOCK_SZ - 1) / BLOCK_SZ) as u32;
+        if target > mapped {
+            let mut fs = self.fs.lock();
+            let needed = DiskInode::blocks_for(target) - DiskInode::blocks_for(mapped);
+            if !fs.charge_blocks(uid, needed) {
+                return None;
+            }
+            // data blocks are allocated as a run, indirect blocks separately
+            let data_blocks = fs.alloc_data_blocks(target - mapped);
+            let indirect_blocks = data_blocks
+                .as_ref()
+                .and_then(|_| fs.alloc_data_blocks(needed - (target - mapped)));
+            let (data_blocks, indirect_blocks) = match (data_blocks, indirect_blocks) {
+                (Some(data_blocks), Some(indirect_blocks)) => (data_blocks, indirect_blocks),
+                (data_blocks, _) => {
+                    data_blocks
+                        .into_iter()
+                        .flatten()
+                        .for_each(|block_id| fs.dealloc_data(block_id));
+                    fs.uncharge_blocks(uid, needed);
+                    return None;
+                }
+            };
+            drop(fs);
+            let mut indirect_blocks = indirect_blocks.into_iter();
+            let new_blocks = DiskInode::build_block_list_from(mapped, &data_blocks, || {
+                indirect_blocks.next().unwrap()
+            });
+            // free blocks are zeroed when released, so nothing is written to them
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.reserve(target, new_blocks, &self.block_device)
+            });
+        }
+        if !keep_size && end > old_size {
+            self.modify_disk_inode(|disk_inode| {
+                disk_inode.increase_size(end as u32, Vec::new(), &self.block_device)
+            });
+            // zeros in an encrypted file are stored encrypted
+            if let Some(key) = key {
+                self.write_encrypted(&key, end, &[], old_size);
+            }
+        }
+        block_cache_sync_all();
+        Some(())
+    }
+    /// Release all data blocks of current inode and return them to its owner
+    fn clear_blocks(&self, fs: &mut MutexGuard<EasyFileSystem>) {
+        let (data_blocks_dealloc, uid) = self.modify_disk_inode(|disk_inode| {
+            let allocated = disk_inode.allocated_blocks();
+            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
+            assert!(data_blocks_dealloc.len() == allocated as usize);
+            disk_inode.flags &= !INODE_COMPRESSED;
+            (data_blocks_dealloc, disk_inode.uid)
+        });
+        fs.uncharge_blocks(uid, data_blocks_dealloc.len() as u32);
+        // reference counts live in another inode, which may share the block of current inode
+        for data_block in data_blocks_dealloc.into_iter() {
+            fs.dealloc_data(data_block);
+        }
+    }
+    /// Read the whole data of current inode
+    fn read_all(&self) -> Vec<u8> {
+        self.read_disk_inode(|disk_inode| {
+            let mut data = alloc::vec![0u8; disk_inode.file_size(&self.block_device) as usize];
+            disk_inode.read_at(0, &mut data, &self.block_device);
+            data
+        })
+    }
+    /// Replace the data of current inode with `stored`, described by `flags`.
+    /// Fail without touching the old data if the owner is out of quota
+    /// or the free blocks do not hold `stored`
+    fn replace_data(&self, stored: &[u8], flags: u32, fs: &mut MutexGuard<EasyFileSystem>) -> bool {
+        let (uid, old_blocks) =
+            self.read_disk_inode(|disk_inode| (disk_inode.uid, disk_inode.allocated_blocks()));
+        let new_blocks = DiskInode::total_blocks(stored.len() as u32);
+        let mut quota = fs.quota(uid);
+        quota.blocks_used = quota.blocks_used.saturating_sub(old_blocks);
+        // shared old blocks may not be freed, so they are not counted
+        if !quota.allows_blocks(new_blocks) || fs.usage().free_blocks < new_blocks {
+            return false;
+        }
+        self.clear_blocks(fs);
+        assert!(self.increase_size(stored.len() as u32, fs));
+        self.modify_disk_inode(|disk_inode| {
+            disk_inode.flags = flags;
+            disk_inode.write_at(0, stored, &self.block_device);
+        });
+        true
+    }
+    /// Store the data of current file compressed, fail if it does not shrink.
+    /// Reads decompress transparently, and the first write stores the data as is again
+    pub fn compress(&self) -> Option<()> {
+        if self.read_only {
+            return None;
+        }
+        let _lock = self.write_lock();
+        if !self.read_disk_inode(|disk_inode| {
+            disk_inode.is_file() && !disk_inode.is_compressed() && !disk_inode.is_encrypted()
+        }) {
+            return None;
+        }
+        let data = self.read_all();
+        let stored = compress_clusters(&data);
+        if DiskInode::total_blocks(stored.len() as u32)
+            >= DiskInode::total_blocks(data.len() as u32)
+            || !self.replace_data(&stored, INODE_COMPRESSED, &mut self.fs.lock())
+        {
+            return None;
+        }
+        block_cache_sync_all();
+        Some(())
+    }
+    /// Encrypt the data of current file, or the names and new children of current
+    /// directory, with a loaded key. Only an empty inode can be encrypted
+    pub fn set_encryption(&self, key_id: u32) -> Option<()> {
+        if self.read_only {
+            return None;
+        }
+        let _lock = self.write_lock();
+        let nonce = {
+            let fs = self.fs.lock();
+            fs.key(key_id)?;
+            fs.get_inode_id(self.block_id as u32, self.block_offset)
+        };
+        self.modify_disk_inode(|disk_inode| {
+            if disk_inode.size != 0 || disk_inode.is_encrypted() {
+                return None;
+            }
+            disk_inode.flags |= INODE_ENCRYPTED;
+            disk_inode.key_id = key_id;
+            disk_inode.nonce = nonce;
+            Some(())
+        })?;
+        block_cache_sync_all();
+        Some(())
+    }
+    /// Get the id of the key encrypting current inode, if it is encrypted
+    pub fn encryption_key(&self) -> Option<u32> {
+        let _lock = self.read_lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_encrypted().then_some(disk_inode.key_id))
+    }
+    /// Whether the data of current inode is stored compressed
+    pub fn is_compressed(&self) -> bool {
+        let _lock = self.read_lock();
+        self.read_disk_inode(|disk_inode| disk_inode.is_compressed())
+    }
     /// Clear the data in current inode
     pub fn clear(&self) {
+        if self.read_only {
+            return;
+        }
+        let _lock = self.write_lock();
+        self.clear_blocks(&mut self.fs.lock());
+        block_cache_sync_all();
+    }
+    /// Replace the data of current file with that of `src` by sharing all its data blocks,
+    /// a shared block is only copied on the first write to either file.
+    /// The shared blocks are charged to the owner of current file as well.
+    /// Fail if a block of `src` already has as many references as a count holds.
+    /// If the device is full for the indirect blocks, current file is left empty.
+    /// Call it as `Inode::clone_from(&dst, &src)`, as `Arc<Inode>` has its own `clone_from`
+    pub fn clone_from(&self, src: &Inode) -> Option<()> {
+        if self.read_only
+            || (self.block_id == src.block_id && self.block_offset == src.block_offset)
+        {
+            return None;
+        }
+        // lock both inodes in the order of their positions
+        let (first, second) =
+            if (self.block_id, self.block_offset) < (src.block_id, src.block_offset) {
+                (self, src)
+            } else {
+                (src, self)
+            };
+        let _first = first.write_lock();
+        let _second = second.write_lock();
         let mut fs = self.fs.lock();
-        self.modify_disk_inode(|disk_inode| {
-            let size = disk_inode.size;
-            let data_blocks_dealloc = disk_inode.clear_size(&self.block_device);
-            assert!(data_blocks_dealloc.len() == DiskInode::total_blocks(size) as usize);
-            for data_block in data_blocks_dealloc.into_iter() {
-                fs.dealloc_data(data_block);
-            }
+        if !self.read_disk_inode(|disk_inode| disk_inode.is_file())
+            || !src.read_disk_inode(|disk_inode| disk_inode.is_file())
+        {
+            return None;
+        }
+        let (uid, old_blocks) =
+            self.read_disk_inode(|disk_inode| (disk_inode.uid, disk_inode.allocated_blocks()));
+        let (new_blocks, shareable) = src.read_disk_inode(|disk_inode| {
+            (
+                DiskInode::total_blocks(disk_inode.size),
+                (0..disk_inode.data_blocks()).all(|inner_id| {
+                    fs.can_share(disk_inode.get_block_id(inner_id, &self.block_device))
+                }),
+            )
         });
+        let mut quota = fs.quota(uid);
+        quota.blocks_used = quota.blocks_used.saturating_sub(old_blocks);
+        if !shareable || !quota.allows_blocks(new_blocks) {
+            return None;
+        }
+        self.clear_blocks(&mut fs);
+        fs.share_inode_data(
+            (src.block_id as u32, src.block_offset),
+            (self.block_id as u32, self.block_offset),
+        )?;
+        fs.charge_blocks(uid, new_blocks);
+        block_cache_sync_all();
+        Some(())
+    }
+    /// Load the extended attributes of current inode, fail if the xattr block is damaged
+    fn read_xattrs(&self) -> Option<Xattrs> {
+        let xattr_block = self.read_disk_inode(|disk_inode| disk_inode.xattr);
+        if xattr_block == 0 {
+            return Some(Xattrs::new());
+        }
+        get_block_cache(xattr_block as usize, Arc::clone(&self.block_device))
+            .lock()
+            .read(0, Xattrs::load)
+    }
+    /// Store the extended attributes of current inode,
+    /// allocating or releasing the xattr block as needed.
+    /// Return false if the owner is out of quota for a new xattr block
+    fn write_xattrs(&self, xattrs: &Xattrs, fs: &mut MutexGuard<EasyFileSystem>) -> bool {
+        let (mut xattr_block, uid) =
+            self.read_disk_inode(|disk_inode| (disk_inode.xattr, disk_inode.uid));
+        if xattrs.is_empty() {
+            if xattr_block != 0 {
+                self.modify_disk_inode(|disk_inode| disk_inode.xattr = 0);
+                fs.dealloc_data(xattr_block);
+                fs.uncharge_blocks(uid, 1);
+            }
+            return true;
+        }
+        if xattr_block == 0 {
+            if !fs.charge_blocks(uid, 1) {
+                return false;
+            }
+            xattr_block = match fs.try_alloc_data() {
+                Some(block_id) => block_id,
+                None => {
+                    fs.uncharge_blocks(uid, 1);
+                    return false;
+                }
+            };
+            self.modify_disk_inode(|disk_inode| disk_inode.xattr = xattr_block);
+        }
+        get_block_cache(xattr_block as usize, Arc::clone(&self.block_device))
+            .lock()
+            .modify(0, |block| xattrs.store(block));
+        true
+    }
+    /// Get the value of an extended attribute
+    pub fn get_xattr(&self, name: &str) -> Option<Vec<u8>> {
+        let _lock = self.read_lock();
+        self.read_xattrs()?.get(name).map(|value| value.to_vec())
+    }
+    /// Set an extended attribute, fail if the name is invalid
+    /// or the attributes no longer fit into one block
+    pub fn set_xattr(&self, name: &str, value: &[u8]) -> Option<()> {
+        if self.read_only || name.is_empty() || name.len() > XATTR_NAME_LIMIT {
+            return None;
+        }
+        let _lock = self.write_lock();
+        let mut xattrs = self.read_xattrs()?;
+        xattrs.set(name, value);
+        if !xattrs.fits() || !self.write_xattrs(&xattrs, &mut self.fs.lock()) {
+            return None;
+        }
+        block_cache_sync_all();
+        Some(())
+    }
+    /// List the names of extended attributes, none if the xattr block is damaged
+    pub fn list_xattr(&self) -> Vec<String> {
+        let _lock = self.read_lock();
+        self.read_xattrs()
+            .map_or_else(Vec::new, |xattrs| xattrs.names())
+    }
+    /// Remove an extended attribute, fail if it does not exist
+    pub fn remove_xattr(&self, name: &str) -> Option<()> {
+        if self.read_only {
+            return None;
+        }
+        let _lock = self.write_lock();
+        let mut xattrs = self.read_xattrs()?;
+        if !xattrs.remove(name) || !self.write_xattrs(&xattrs, &mut self.fs.lock()) {
+            return None;
+        }
+        block_cache_sync_all();
+        Some(())
+    }
+}
+
+impl Drop for Inode {
+    /// Release an unlinked inode once its last handle is gone
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+        let mut fs = self.fs.lock();
+        let inode_id = fs.get_inode_id(self.block_id as u32, self.block_offset);
+        // the root directory is never unlinked
+        if inode_id == 0 || fs.read_disk_inode(inode_id, |disk_inode| disk_inode.nlink) > 0 {
+            return;
+        }
+        fs.release_unlinked(inode_id);
+        drop(fs);
         block_cache_sync_all();
     }
 }
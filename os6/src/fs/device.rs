@@ -0,0 +1,161 @@
+//! Drivers behind the device inodes of easy-fs
+
+use alloc::sync::Arc;
+use easy_fs::{DiskInodeType, Inode};
+
+use crate::{mm::UserBuffer, sync::UPSafeCell};
+
+use super::{File, Stdin, Stdout, ROOT_INODE};
+
+/// Major number of the memory devices
+pub const MEM_MAJOR: u32 = 1;
+/// Minor number of the device discarding writes and reading nothing
+pub const NULL_MINOR: u32 = 3;
+/// Minor number of the device discarding writes and reading zeros
+pub const ZERO_MINOR: u32 = 5;
+/// Major number of the terminal devices
+pub const TTY_MAJOR: u32 = 5;
+/// Minor number of the console
+pub const CONSOLE_MINOR: u32 = 1;
+/// Major number of the virtio block devices
+pub const VIRTIO_BLK_MAJOR: u32 = 254;
+
+/// Size of a block of the block device
+const BLOCK_SZ: usize = 512;
+
+/// A memory device, /dev/null or /dev/zero
+pub struct MemDevice {
+    zero: bool,
+    inode: Arc<Inode>,
+}
+
+impl File for MemDevice {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, mut buf: UserBuffer) -> usize {
+        if !self.zero {
+            return 0;
+        }
+        buf.buffers.iter_mut().for_each(|slice| slice.fill(0));
+        buf.len()
+    }
+
+    fn write(&self, buf: UserBuffer) -> usize {
+        buf.len()
+    }
+
+    fn inode(&self) -> Option<Arc<Inode>> {
+        Some(self.inode.clone())
+    }
+}
+
+/// The console, reading from stdin and writing to stdout
+pub struct Console {
+    inode: Arc<Inode>,
+}
+
+impl File for Console {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, buf: UserBuffer) -> usize {
+        Stdin.read(buf)
+    }
+
+    fn write(&self, buf: UserBuffer) -> usize {
+        Stdout.write(buf)
+    }
+
+    fn inode(&self) -> Option<Arc<Inode>> {
+        Some(self.inode.clone())
+    }
+}
+
+/// Raw read-only access to the block device, which holds the mounted easy-fs
+/// and is only written through its block cache, so it is read through it as well
+pub struct RawBlockDevice {
+    offset: UPSafeCell<usize>,
+    inode: Arc<Inode>,
+}
+
+impl File for RawBlockDevice {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+
+    /// Read from the current offset, nothing is read beyond the end of easy-fs
+    fn read(&self, mut buf: UserBuffer) -> usize {
+        let fs = ROOT_INODE.fs();
+        let fs = fs.lock();
+        let end = fs.total_blocks() as usize * BLOCK_SZ;
+        let mut offset = self.offset.exclusive_access();
+        let mut block = [0u8; BLOCK_SZ];
+        let mut read_size = 0usize;
+        for slice in buf.buffers.iter_mut() {
+            let mut done = 0;
+            while done < slice.len() && *offset < end {
+                fs.read_block((*offset / BLOCK_SZ) as u32, &mut block);
+                let start = *offset % BLOCK_SZ;
+                let len = (BLOCK_SZ - start).min(slice.len() - done);
+                slice[done..done + len].copy_from_slice(&block[start..start + len]);
+                done += len;
+                *offset += len;
+            }
+            read_size += done;
+        }
+        read_size
+    }
+
+    fn write(&self, _: UserBuffer) -> usize {
+        0
+    }
+
+    fn inode(&self) -> Option<Arc<Inode>> {
+        Some(self.inode.clone())
+    }
+
+    fn offset(&self) -> usize {
+        *self.offset.exclusive_access()
+    }
+
+    fn seek(&self, offset: usize) {
+        *self.offset.exclusive_access() = offset;
+    }
+}
+
+/// Open the device of the device inode `inode`, fail if there is no such device
+/// or it cannot be opened for writing
+pub fn open_device(inode: Arc<Inode>, writable: bool) -> Option<Arc<dyn File + Send + Sync>> {
+    let (major, minor) = inode.device()?;
+    match (inode.inode_type(), major, minor) {
+        (DiskInodeType::CharDevice, MEM_MAJOR, NULL_MINOR) => {
+            Some(Arc::new(MemDevice { zero: false, inode }))
+        }
+        (DiskInodeType::CharDevice, MEM_MAJOR, ZERO_MINOR) => {
+            Some(Arc::new(MemDevice { zero: true, inode }))
+        }
+        (DiskInodeType::CharDevice, TTY_MAJOR, CONSOLE_MINOR) => Some(Arc::new(Console { inode })),
+        (DiskInodeType::BlockDevice, VIRTIO_BLK_MAJOR, 0) if !writable => {
+            Some(Arc::new(RawBlockDevice {
+                offset: UPSafeCell::new(0),
+                inode,
+            }))
+        }
+        _ => None,
+    }
+}
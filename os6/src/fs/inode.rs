@@ -1,10 +1,10 @@
 use alloc::{sync::Arc, vec::Vec};
-use easy_fs::{EasyFileSystem, Inode};
+use easy_fs::{DiskInodeType, EasyFileSystem, Inode};
 use lazy_static::lazy_static;
 
 use crate::{drivers::BLOCK_DEVICE, mm::UserBuffer, sync::UPSafeCell};
 
-use super::File;
+use super::{open_device, open_fifo, File, EISDIR};
 
 bitflags! {
     pub struct OpenFlags: u32 {
@@ -36,7 +36,6 @@ lazy_static! {
 }
 
 pub struct OSInode {
-    inode_id: u32,
     readable: bool,
     writable: bool,
     inner: UPSafeCell<OSINodeInner>,
@@ -63,9 +62,12 @@ impl File for OSInode {
         let mut total_write_size = 0usize;
         for slice in buf.buffers.iter() {
             let write_size = inner.inode.write_at(inner.offset, *&slice);
-            assert_eq!(write_size, slice.len());
             inner.offset += write_size;
             total_write_size += write_size;
+            // the owner is out of quota
+            if write_size < slice.len() {
+                break;
+            }
         }
         total_write_size
     }
@@ -78,8 +80,16 @@ impl File for OSInode {
         self.writable
     }
 
-    fn inode_id(&self) -> Option<u32> {
-        Some(self.inode_id)
+    fn inode(&self) -> Option<Arc<Inode>> {
+        Some(self.inner.inner.borrow().inode.clone())
+    }
+
+    fn offset(&self) -> usize {
+        self.inner.inner.borrow().offset
+    }
+
+    fn seek(&self, offset: usize) {
+        self.inner.inner.borrow_mut().offset = offset;
     }
 }
 
@@ -89,9 +99,8 @@ pub struct OSINodeInner {
 }
 
 impl OSInode {
-    pub fn new(inode_id: u32, readable: bool, writable: bool, inode: Arc<Inode>) -> Self {
+    pub fn new(readable: bool, writable: bool, inode: Arc<Inode>) -> Self {
         Self {
-            inode_id,
             readable,
             writable,
             inner: UPSafeCell::new(OSINodeInner { offset: 0, inode }),
@@ -123,24 +132,65 @@ pub fn list_apps() {
     println!("***************/")
 }
 
+/// Open a regular file, e.g. an executable to load
 pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
     let (readable, writable) = flags.read_write();
-    if flags.contains(OpenFlags::CREATE) {
-        if let Some((inode_id, inode)) = ROOT_INODE.find_node(name) {
-            inode.clear();
-            Some(Arc::new(OSInode::new(inode_id, readable, writable, inode)))
-        } else {
-            ROOT_INODE.create_inode(name).map(|(inode_id, inode)| {
-                Arc::new(OSInode::new(inode_id, readable, writable, inode))
-            })
+    ROOT_INODE
+        .find(name)
+        .filter(|inode| inode.inode_type() == DiskInodeType::File)
+        .map(|inode| Arc::new(OSInode::new(readable, writable, inode)))
+}
+
+/// Open a file, a newly created file is owned by `uid`.
+/// FIFOs and devices are handed to their pipe or driver.
+/// Return the negated error number on failure
+pub fn open_file_as(
+    name: &str,
+    flags: OpenFlags,
+    uid: u32,
+) -> Result<Arc<dyn File + Send + Sync>, isize> {
+    // the root directory itself, only to be listed
+    if name == "/" {
+        return open_file_at(&ROOT_INODE, ".", flags, uid);
+    }
+    open_file_at(&ROOT_INODE, name, flags, uid)
+}
+
+/// Open a file of the directory `dir`, e.g. of a mounted snapshot.
+/// Files of a read-only directory can only be opened for reading,
+/// directories can only be opened read-only to be listed.
+/// Return the negated error number on failure
+pub fn open_file_at(
+    dir: &Arc<Inode>,
+    name: &str,
+    flags: OpenFlags,
+    uid: u32,
+) -> Result<Arc<dyn File + Send + Sync>, isize> {
+    let (readable, writable) = flags.read_write();
+    let inode = match name {
+        "." => dir.clone(),
+        _ => match dir.find(name) {
+            Some(inode) => inode,
+            None if flags.contains(OpenFlags::CREATE) => {
+                let (_, inode) = dir.create_inode_as(name, uid).ok_or(-1isize)?;
+                return Ok(Arc::new(OSInode::new(readable, writable, inode)));
+            }
+            None => return Err(-1),
+        },
+    };
+    let modifying = writable || flags.intersects(OpenFlags::CREATE | OpenFlags::TRUNC);
+    match inode.inode_type() {
+        DiskInodeType::Directory if modifying => Err(EISDIR),
+        _ if inode.is_read_only() && (writable || flags.contains(OpenFlags::TRUNC)) => Err(-1),
+        DiskInodeType::Fifo => Ok(open_fifo(inode, readable, writable)),
+        DiskInodeType::CharDevice | DiskInodeType::BlockDevice => {
+            open_device(inode, writable).ok_or(-1)
         }
-    } else {
-        ROOT_INODE.find_node(name).map(|(inode_id, inode)| {
-            if flags.contains(OpenFlags::TRUNC) {
+        _ => {
+            if flags.intersects(OpenFlags::CREATE | OpenFlags::TRUNC) {
                 inode.clear();
             }
-
-            Arc::new(OSInode::new(inode_id, readable, writable, inode))
-        })
+            Ok(Arc::new(OSInode::new(readable, writable, inode)))
+        }
     }
 }
@@ -1,10 +1,16 @@
 use crate::mm::UserBuffer;
+use alloc::sync::Arc;
+use easy_fs::Inode;
 
+mod device;
 mod inode;
+mod pipe;
 mod stdio;
 
+pub use device::open_device;
 pub use inode::*;
 pub use inode::{OSInode, OpenFlags};
+pub use pipe::open_fifo;
 pub use stdio::{Stdin, Stdout};
 
 pub trait File: Send + Sync {
@@ -13,9 +19,18 @@ pub trait File: Send + Sync {
     fn read(&self, buf: UserBuffer) -> usize;
     fn write(&self, buf: UserBuffer) -> usize;
 
-    fn inode_id(&self) -> Option<u32> {
+    /// The easy-fs inode behind this file, if any
+    fn inode(&self) -> Option<Arc<Inode>> {
         None
     }
+
+    /// The current offset in the file
+    fn offset(&self) -> usize {
+        0
+    }
+
+    /// Move the current offset in the file
+    fn seek(&self, _offset: usize) {}
 }
 
 /// The stat of a inode
@@ -30,8 +45,14 @@ pub struct Stat {
     pub mode: StatMode,
     /// number of hard links
     pub nlink: u32,
+    /// size in bytes
+    pub size: u64,
+    /// number of 512-byte blocks allocated
+    pub blocks: u64,
+    /// device number of a device, major in bits 8..20 and minor in bits 0..8 and 20..32
+    pub rdev: u64,
     /// unused pad
-    pad: [u64; 7],
+    pad: [u64; 4],
 }
 
 bitflags! {
@@ -39,9 +60,75 @@ bitflags! {
     /// whether a directory or a file
     pub struct StatMode: u32 {
         const NULL  = 0;
+        /// named pipe
+        const FIFO  = 0o010000;
+        /// character device
+        const CHR   = 0o020000;
         /// directory
         const DIR   = 0o040000;
+        /// block device
+        const BLK   = 0o060000;
         /// ordinary regular file
         const FILE  = 0o100000;
     }
 }
+
+/// Mask of the file type bits of a mode
+pub const S_IFMT: u32 = 0o170000;
+
+/// Split a device number into its (major, minor) numbers
+pub fn decode_dev(dev: u64) -> (u32, u32) {
+    (
+        ((dev >> 8) & 0xfff) as u32,
+        ((dev & 0xff) | ((dev >> 12) & 0xfff00)) as u32,
+    )
+}
+
+/// Join (major, minor) numbers into a device number
+pub fn encode_dev((major, minor): (u32, u32)) -> u64 {
+    let (major, minor) = (major as u64, minor as u64);
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+/// Type of a directory entry returned by getdents64
+pub const DT_FIFO: u8 = 1;
+/// Type of a directory entry returned by getdents64
+pub const DT_CHR: u8 = 2;
+/// Type of a directory entry returned by getdents64
+pub const DT_DIR: u8 = 4;
+/// Type of a directory entry returned by getdents64
+pub const DT_BLK: u8 = 6;
+/// Type of a directory entry returned by getdents64
+pub const DT_REG: u8 = 8;
+
+/// fallocate mode reserving blocks without changing the size of the file
+pub const FALLOC_FL_KEEP_SIZE: u32 = 1;
+
+/// ioctl request to share all data blocks of another file
+pub const FICLONE: u32 = 0x40049409;
+/// ioctl request to encrypt an empty file or directory with a loaded key
+pub const EFS_IOC_SET_ENCRYPTION: u32 = 0x40046613;
+
+/// Negated error number of opening a directory for writing, as in Linux
+pub const EISDIR: isize = -21;
+
+/// sys_snapshot operation taking a snapshot of the whole filesystem
+pub const SNAPSHOT_CREATE: usize = 0;
+/// sys_snapshot operation deleting a snapshot
+pub const SNAPSHOT_DELETE: usize = 1;
+/// sys_snapshot operation reverting the filesystem to a snapshot
+pub const SNAPSHOT_REVERT: usize = 2;
+/// sys_snapshot operation opening the root directory of a snapshot read-only
+pub const SNAPSHOT_MOUNT: usize = 3;
+/// sys_snapshot operation opening the directory of snapshots read-only
+pub const SNAPSHOT_LIST: usize = 4;
+
+bitflags! {
+    /// Flags of setxattr
+    pub struct XattrFlags: u32 {
+        /// fail if the attribute already exists
+        const CREATE  = 1;
+        /// fail if the attribute does not exist
+        const REPLACE = 2;
+    }
+}
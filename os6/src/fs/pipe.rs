@@ -0,0 +1,187 @@
+//! Named pipes (FIFOs) of easy-fs
+
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use easy_fs::Inode;
+use lazy_static::lazy_static;
+
+use crate::{mm::UserBuffer, sync::UPSafeCell, task::suspend_current_and_run_next};
+
+use super::File;
+
+/// Capacity of the buffer of a pipe
+const RING_BUFFER_SIZE: usize = 32;
+
+/// Buffer shared by all ends of a pipe
+pub struct PipeRingBuffer {
+    arr: [u8; RING_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+    /// Number of ends open for reading
+    readers: usize,
+    /// Number of ends open for writing
+    writers: usize,
+}
+
+impl PipeRingBuffer {
+    fn new() -> Self {
+        Self {
+            arr: [0; RING_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+            readers: 0,
+            writers: 0,
+        }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let c = self.arr[self.head];
+        self.head = (self.head + 1) % RING_BUFFER_SIZE;
+        self.len -= 1;
+        c
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.arr[(self.head + self.len) % RING_BUFFER_SIZE] = byte;
+        self.len += 1;
+    }
+}
+
+/// One end of a pipe, open for reading, writing or both
+pub struct Pipe {
+    readable: bool,
+    writable: bool,
+    buffer: Arc<UPSafeCell<PipeRingBuffer>>,
+    /// The FIFO inode, kept alive so that its id is not reused while the buffer is
+    inode: Arc<Inode>,
+}
+
+impl Pipe {
+    fn new(
+        readable: bool,
+        writable: bool,
+        buffer: Arc<UPSafeCell<PipeRingBuffer>>,
+        inode: Arc<Inode>,
+    ) -> Self {
+        let mut ring = buffer.exclusive_access();
+        ring.readers += readable as usize;
+        ring.writers += writable as usize;
+        drop(ring);
+        Self {
+            readable,
+            writable,
+            buffer,
+            inode,
+        }
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        let mut ring = self.buffer.exclusive_access();
+        ring.readers -= self.readable as usize;
+        ring.writers -= self.writable as usize;
+    }
+}
+
+impl File for Pipe {
+    fn readable(&self) -> bool {
+        self.readable
+    }
+
+    fn writable(&self) -> bool {
+        self.writable
+    }
+
+    fn inode(&self) -> Option<Arc<Inode>> {
+        Some(self.inode.clone())
+    }
+
+    /// Wait until some data is available, return 0 once no writer is left
+    fn read(&self, buf: UserBuffer) -> usize {
+        let want = buf.len();
+        if want == 0 {
+            return 0;
+        }
+        let mut read_size = 0usize;
+        let mut bytes = buf.buffers.into_iter().flatten();
+        loop {
+            let mut ring = self.buffer.exclusive_access();
+            if ring.len == 0 {
+                if read_size > 0 || ring.writers == 0 {
+                    return read_size;
+                }
+                drop(ring);
+                suspend_current_and_run_next();
+                continue;
+            }
+            while ring.len > 0 {
+                *bytes.next().unwrap() = ring.read_byte();
+                read_size += 1;
+                if read_size == want {
+                    return read_size;
+                }
+            }
+        }
+    }
+
+    /// Wait until all data is written, stop early once no reader is left
+    fn write(&self, buf: UserBuffer) -> usize {
+        let want = buf.len();
+        if want == 0 {
+            return 0;
+        }
+        let mut write_size = 0usize;
+        let mut bytes = buf.buffers.iter().flat_map(|slice| slice.iter());
+        loop {
+            let mut ring = self.buffer.exclusive_access();
+            if ring.readers == 0 {
+                return write_size;
+            }
+            while ring.len < RING_BUFFER_SIZE {
+                ring.write_byte(*bytes.next().unwrap());
+                write_size += 1;
+                if write_size == want {
+                    return write_size;
+                }
+            }
+            drop(ring);
+            suspend_current_and_run_next();
+        }
+    }
+}
+
+lazy_static! {
+    /// Buffers of the FIFOs with open ends, by inode id. The ends hold their inode,
+    /// so a live buffer never belongs to a released inode whose id is reused
+    static ref FIFO_BUFFERS: UPSafeCell<BTreeMap<u32, Weak<UPSafeCell<PipeRingBuffer>>>> =
+        UPSafeCell::new(BTreeMap::new());
+}
+
+/// Open an end of the FIFO `inode` once the other end is open as well,
+/// all ends open at the same time share one buffer
+pub fn open_fifo(inode: Arc<Inode>, readable: bool, writable: bool) -> Arc<Pipe> {
+    let inode_id = inode.inode_id();
+    let mut buffers = FIFO_BUFFERS.exclusive_access();
+    buffers.retain(|_, buffer| buffer.strong_count() > 0);
+    let buffer = match buffers.get(&inode_id).and_then(|buffer| buffer.upgrade()) {
+        Some(buffer) => buffer,
+        None => {
+            let buffer = Arc::new(UPSafeCell::new(PipeRingBuffer::new()));
+            buffers.insert(inode_id, Arc::downgrade(&buffer));
+            buffer
+        }
+    };
+    let pipe = Arc::new(Pipe::new(readable, writable, buffer, inode));
+    drop(buffers);
+    // like on Linux, an end waits for the other end to be opened
+    loop {
+        let ring = pipe.buffer.exclusive_access();
+        if ring.readers > 0 && ring.writers > 0 {
+            break;
+        }
+        drop(ring);
+        suspend_current_and_run_next();
+    }
+    pipe
+}
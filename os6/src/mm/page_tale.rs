@@ -229,4 +229,27 @@ impl UserBuffer {
         }
         total
     }
+
+    /// Copy `src` into the buffer, return the number of bytes copied
+    pub fn copy_from_slice(&mut self, src: &[u8]) -> usize {
+        let mut copied = 0usize;
+        for b in self.buffers.iter_mut() {
+            if copied == src.len() {
+                break;
+            }
+            let len = b.len().min(src.len() - copied);
+            b[..len].copy_from_slice(&src[copied..copied + len]);
+            copied += len;
+        }
+        copied
+    }
+
+    /// Collect the buffer content into a vector
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(self.len());
+        for b in self.buffers.iter() {
+            v.extend_from_slice(b);
+        }
+        v
+    }
 }
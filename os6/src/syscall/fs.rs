@@ -1,10 +1,29 @@
 //! File and filesystem-related syscalls
 
 
-use crate::fs::{open_file, OpenFlags, Stat, StatMode, ROOT_INODE};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use easy_fs::{DiskInodeType, EasyFileSystem, Inode, KEY_SZ, MAX_FILE_SIZE, ROOT_UID};
+
+use crate::fs::{
+    decode_dev, encode_dev, open_file_as, open_file_at, OSInode, OpenFlags, Stat, StatMode,
+    XattrFlags, DT_BLK, DT_CHR, DT_DIR, DT_FIFO, DT_REG, EFS_IOC_SET_ENCRYPTION, EISDIR,
+    FALLOC_FL_KEEP_SIZE, FICLONE, ROOT_INODE, SNAPSHOT_CREATE, SNAPSHOT_DELETE, SNAPSHOT_LIST,
+    SNAPSHOT_MOUNT, SNAPSHOT_REVERT, S_IFMT,
+};
 use crate::mm::{translated_byte_buffer, translated_refmut, translated_str, UserBuffer};
 use crate::task::{current_task, current_user_token};
 
+/// openat directory fd standing for the current (root) directory
+const AT_FDCWD: isize = -100;
+
+// Negated error numbers of Linux, the other errors are reported as -1
+const EPERM: isize = -1;
+const ENOENT: isize = -2;
+const EBUSY: isize = -16;
+const EINVAL: isize = -22;
+const EFBIG: isize = -27;
+
 // YOUR JOB: 修改 sys_write 使之通过测试
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     let token = current_user_token();
@@ -18,6 +37,9 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
         let file = file.clone();
         drop(inner);
         drop(task);
+        if file.inode().map_or(false, |inode| inode.is_dir()) {
+            return EISDIR;
+        }
         file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
     } else {
         println!("WriteError");
@@ -46,7 +68,8 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
     }
 }
 
-pub fn sys_open(path: *const u8, flags: u32) -> isize {
+/// Open `path` in the directory `dirfd`, which is the root directory for AT_FDCWD
+pub fn sys_open(dirfd: usize, path: *const u8, flags: u32) -> isize {
     let flags = if let Some(flags) = OpenFlags::from_bits(flags) {
         flags
     } else {
@@ -56,16 +79,31 @@ pub fn sys_open(path: *const u8, flags: u32) -> isize {
 
     let token = current_user_token();
     let path = translated_str(token, path);
-    if let (Some(task), Some(inode)) = (current_task(), open_file(path.as_str(), flags)) {
-        let mut inner = task.inner.inner.borrow_mut();
-        let fd = inner.alloc_fd();
-        inner.fd_table[fd] = Some(inode);
-        fd as isize
+    let task = current_task().unwrap();
+    let uid = task.inner.inner.borrow().uid;
+    let file = if dirfd as isize == AT_FDCWD {
+        open_file_as(path.as_str(), flags, uid)
     } else {
+        let dir = match task.inner.inner.borrow().fd_table.get(dirfd) {
+            Some(Some(file)) => file.inode(),
+            _ => return -1,
+        };
+        match dir {
+            Some(dir) if dir.is_dir() => open_file_at(&dir, path.as_str(), flags, uid),
+            _ => return -1,
+        }
+    };
+    match file {
+        Ok(inode) => {
+            let mut inner = task.inner.inner.borrow_mut();
+            let fd = inner.alloc_fd();
+            inner.fd_table[fd] = Some(inode);
+            fd as isize
+        }
         //实在是神奇了.....究竟哪里搞错了。。。。。
         //去掉就报错了...
         //println!("openError:{:?}-{:?}", path, flags);
-        -1
+        Err(errno) => errno,
     }
 }
 
@@ -87,22 +125,147 @@ pub fn sys_fstat(fd: usize, st: *mut Stat) -> isize {
         return -1;
     }
 
-    return if let (Some(fd), Some(ts)) = (
-        inner.fd_table[fd].clone(),
-        translated_refmut(inner.get_user_token(), st),
-    ) {
-        ts.ino = fd.inode_id().map_or(0u64, |id| id as u64);
-        //单层结构，只能是文件
-        ts.mode = StatMode::FILE;
-        if 0 < ts.ino {
-            ts.nlink = ROOT_INODE.calc_hard_links(ts.ino as u32) as u32
-        }
+    let inode = inner.fd_table[fd].as_ref().and_then(|file| file.inode());
+    let token = inner.get_user_token();
+    drop(inner);
+
+    return if let (Some(inode), Some(ts)) = (inode, translated_refmut(token, st)) {
+        let stat = inode.stat();
+        // easy-fs is the only filesystem
+        ts.dev = 0;
+        ts.ino = stat.inode_id as u64;
+        ts.mode = match stat.inode_type {
+            DiskInodeType::Directory => StatMode::DIR,
+            DiskInodeType::File => StatMode::FILE,
+            DiskInodeType::Fifo => StatMode::FIFO,
+            DiskInodeType::CharDevice => StatMode::CHR,
+            DiskInodeType::BlockDevice => StatMode::BLK,
+        };
+        ts.nlink = stat.nlink;
+        ts.size = stat.size as u64;
+        ts.blocks = stat.blocks as u64;
+        ts.rdev = stat.device.map_or(0, encode_dev);
         0
     } else {
         -1
     };
 }
 
+pub fn sys_ioctl(fd: usize, cmd: u32, arg: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.inner.inner.borrow_mut();
+    match cmd {
+        // arg is the source fd, whose data blocks are shared with fd
+        FICLONE => {
+            if fd >= inner.fd_table.len() || arg >= inner.fd_table.len() {
+                return -1;
+            }
+            if let (Some(dst), Some(src)) = (&inner.fd_table[fd], &inner.fd_table[arg]) {
+                if !dst.writable() || !src.readable() {
+                    return -1;
+                }
+                if let (Some(dst), Some(src)) = (dst.inode(), src.inode()) {
+                    drop(inner);
+                    return Inode::clone_from(&dst, &src).map_or(-1, |_| 0);
+                }
+            }
+            -1
+        }
+        // arg is the id of a loaded key, the file must still be empty
+        EFS_IOC_SET_ENCRYPTION => {
+            if fd >= inner.fd_table.len() {
+                return -1;
+            }
+            if let Some(file) = &inner.fd_table[fd] {
+                if !file.writable() {
+                    return -1;
+                }
+                // FIFOs and devices have no data of their own
+                if let Some(inode) = file.inode().filter(|inode| {
+                    matches!(
+                        inode.inode_type(),
+                        DiskInodeType::File | DiskInodeType::Directory
+                    )
+                }) {
+                    drop(inner);
+                    return inode.set_encryption(arg as u32).map_or(-1, |_| 0);
+                }
+            }
+            -1
+        }
+        _ => -1,
+    }
+}
+
+/// Reserve blocks for the byte range [offset, offset + len) of the file `fd`,
+/// its size grows to cover the range unless `mode` is FALLOC_FL_KEEP_SIZE
+pub fn sys_fallocate(fd: usize, mode: u32, offset: usize, len: usize) -> isize {
+    // offset and len are signed in Linux
+    if mode & !FALLOC_FL_KEEP_SIZE != 0 || len == 0 || ((offset | len) as isize) < 0 {
+        return EINVAL;
+    }
+    match offset.checked_add(len) {
+        Some(end) if end <= MAX_FILE_SIZE => {}
+        _ => return EFBIG,
+    }
+    let task = current_task().unwrap();
+    let inner = task.inner.inner.borrow_mut();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) if file.writable() => file.clone(),
+        _ => return -1,
+    };
+    drop(inner);
+    match file.inode() {
+        Some(inode) if inode.inode_type() == DiskInodeType::File => inode
+            .allocate(offset, len, mode & FALLOC_FL_KEEP_SIZE != 0)
+            .map_or(-1, |_| 0),
+        _ => -1,
+    }
+}
+
+/// Fill `buf` with as many `linux_dirent64` records of the directory `fd` as fit,
+/// continuing from the previous call. Return the number of bytes filled, 0 at the end
+pub fn sys_getdents64(fd: usize, buf: *mut u8, len: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.inner.inner.borrow_mut();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => return -1,
+    };
+    drop(inner);
+    let inode = match file.inode() {
+        Some(inode) if inode.is_dir() => inode,
+        _ => return -1,
+    };
+    let mut records: Vec<u8> = Vec::new();
+    while let Some((entry, next_offset)) = inode.readdir(file.offset()) {
+        // d_ino, d_off, d_reclen, d_type and the name with its '\0', aligned to 8 bytes
+        let reclen = (19 + entry.name.len() + 1 + 7) / 8 * 8;
+        if records.len() + reclen > len {
+            if records.is_empty() {
+                return -1;
+            }
+            break;
+        }
+        let d_type = match entry.inode_type {
+            DiskInodeType::Directory => DT_DIR,
+            DiskInodeType::File => DT_REG,
+            DiskInodeType::Fifo => DT_FIFO,
+            DiskInodeType::CharDevice => DT_CHR,
+            DiskInodeType::BlockDevice => DT_BLK,
+        };
+        records.extend_from_slice(&(entry.inode_id as u64).to_ne_bytes());
+        records.extend_from_slice(&(next_offset as i64).to_ne_bytes());
+        records.extend_from_slice(&(reclen as u16).to_ne_bytes());
+        records.push(d_type);
+        records.extend_from_slice(entry.name.as_bytes());
+        records.resize(records.len() + reclen - 19 - entry.name.len(), 0);
+        file.seek(next_offset);
+    }
+    UserBuffer::new(translated_byte_buffer(current_user_token(), buf, records.len()))
+        .copy_from_slice(&records) as isize
+}
+
 pub fn sys_linkat(old_name: *const u8, new_name: *const u8) -> isize {
     let token = current_user_token();
     let old_name = translated_str(token, old_name);
@@ -119,6 +282,32 @@ pub fn sys_linkat(old_name: *const u8, new_name: *const u8) -> isize {
     }
 }
 
+/// Create a FIFO, device or regular file named `path` owned by the current user,
+/// `dev` is the number of a device. Only root may create devices
+pub fn sys_mknodat(path: *const u8, mode: u32, dev: usize) -> isize {
+    let inode_type = match mode & S_IFMT {
+        0 => DiskInodeType::File,
+        fmt if fmt == StatMode::FILE.bits() => DiskInodeType::File,
+        fmt if fmt == StatMode::FIFO.bits() => DiskInodeType::Fifo,
+        fmt if fmt == StatMode::CHR.bits() => DiskInodeType::CharDevice,
+        fmt if fmt == StatMode::BLK.bits() => DiskInodeType::BlockDevice,
+        _ => return -1,
+    };
+    let uid = current_task().unwrap().inner.inner.borrow().uid;
+    if matches!(
+        inode_type,
+        DiskInodeType::CharDevice | DiskInodeType::BlockDevice
+    ) && uid != ROOT_UID
+    {
+        return EPERM;
+    }
+    let path = translated_str(current_user_token(), path);
+    let (major, minor) = decode_dev(dev as u64);
+    ROOT_INODE
+        .mknod_as(&path, uid, inode_type, major, minor)
+        .map_or(-1, |_| 0)
+}
+
 pub fn sys_unlinkat(name: *const u8) -> isize {
     let token = current_user_token();
     let name = translated_str(token, name);
@@ -128,4 +317,171 @@ pub fn sys_unlinkat(name: *const u8) -> isize {
     } else {
         -1
     }
-}
\ No newline at end of file
+}
+
+pub fn sys_setxattr(
+    path: *const u8,
+    name: *const u8,
+    value: *const u8,
+    size: usize,
+    flags: u32,
+) -> isize {
+    let flags = if let Some(flags) = XattrFlags::from_bits(flags) {
+        flags
+    } else {
+        return -1;
+    };
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let name = translated_str(token, name);
+    let inode = if let Some(inode) = ROOT_INODE.find(&path) {
+        inode
+    } else {
+        return -1;
+    };
+
+    let existed = inode.get_xattr(&name).is_some();
+    if (flags.contains(XattrFlags::CREATE) && existed)
+        || (flags.contains(XattrFlags::REPLACE) && !existed)
+    {
+        return -1;
+    }
+    let value = UserBuffer::new(translated_byte_buffer(token, value, size)).to_vec();
+    inode.set_xattr(&name, &value).map_or(-1, |_| 0)
+}
+
+pub fn sys_getxattr(path: *const u8, name: *const u8, value: *mut u8, size: usize) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let name = translated_str(token, name);
+
+    if let Some(v) = ROOT_INODE
+        .find(&path)
+        .and_then(|inode| inode.get_xattr(&name))
+    {
+        // size 0 only queries the length of the value
+        if size == 0 {
+            return v.len() as isize;
+        }
+        if size < v.len() {
+            return -1;
+        }
+        UserBuffer::new(translated_byte_buffer(token, value, v.len())).copy_from_slice(&v) as isize
+    } else {
+        -1
+    }
+}
+
+pub fn sys_listxattr(path: *const u8, list: *mut u8, size: usize) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+
+    if let Some(inode) = ROOT_INODE.find(&path) {
+        // names are separated by '\0'
+        let mut names: Vec<u8> = Vec::new();
+        for name in inode.list_xattr() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        if size == 0 {
+            return names.len() as isize;
+        }
+        if size < names.len() {
+            return -1;
+        }
+        UserBuffer::new(translated_byte_buffer(token, list, names.len())).copy_from_slice(&names)
+            as isize
+    } else {
+        -1
+    }
+}
+
+pub fn sys_removexattr(path: *const u8, name: *const u8) -> isize {
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    let name = translated_str(token, name);
+
+    ROOT_INODE
+        .find(&path)
+        .and_then(|inode| inode.remove_xattr(&name))
+        .map_or(-1, |_| 0)
+}
+
+/// Create, delete or revert to the snapshot `name` of the whole filesystem. Reverting
+/// releases every inode of the live filesystem, so it fails while files are open.
+/// Mounting a snapshot returns a read-only fd of its root directory, listing returns one
+/// of the directory of snapshots, both to be read with getdents64 and used as the dirfd
+/// of openat. Only root may do this
+pub fn sys_snapshot(op: usize, name: *const u8) -> isize {
+    if current_task().unwrap().inner.inner.borrow().uid != ROOT_UID {
+        return EPERM;
+    }
+    let efs = ROOT_INODE.fs();
+    let dir = match op {
+        SNAPSHOT_LIST => EasyFileSystem::snapshots_dir(&efs),
+        SNAPSHOT_MOUNT => {
+            let name = translated_str(current_user_token(), name);
+            match EasyFileSystem::snapshot_root(&efs, &name) {
+                Some(snapshot_root) => snapshot_root,
+                None => return ENOENT,
+            }
+        }
+        _ => {
+            let name = translated_str(current_user_token(), name);
+            let result = match op {
+                SNAPSHOT_CREATE => EasyFileSystem::create_snapshot(&efs, &name),
+                SNAPSHOT_DELETE => efs.lock().delete_snapshot(&name),
+                // open files of the live filesystem would be left to released inodes
+                SNAPSHOT_REVERT if efs.lock().has_open_inodes() => return EBUSY,
+                SNAPSHOT_REVERT => EasyFileSystem::revert_to_snapshot(&efs, &name),
+                _ => None,
+            };
+            return result.map_or(-1, |_| 0);
+        }
+    };
+    let task = current_task().unwrap();
+    let mut inner = task.inner.inner.borrow_mut();
+    let fd = inner.alloc_fd();
+    inner.fd_table[fd] = Some(Arc::new(OSInode::new(true, false, dir)));
+    fd as isize
+}
+
+/// Set the block and inode limits of `uid`, 0 for unlimited. Only root may set quotas,
+/// and only for uids up to `MAX_QUOTA_UID` of easy-fs
+pub fn sys_setquota(uid: u32, block_limit: u32, inode_limit: u32) -> isize {
+    if current_task().unwrap().inner.inner.borrow().uid != ROOT_UID {
+        return -1;
+    }
+    ROOT_INODE
+        .fs()
+        .lock()
+        .set_quota(uid, block_limit, inode_limit)
+        .map_or(-1, |_| 0)
+}
+
+/// Load an encryption key of `KEY_SZ` bytes into the kernel and return its id,
+/// fail if a different key with the same id is loaded
+pub fn sys_add_key(key: *const u8, len: usize) -> isize {
+    if len != KEY_SZ {
+        return -1;
+    }
+    let mut raw = [0u8; KEY_SZ];
+    let mut copied = 0;
+    for chunk in translated_byte_buffer(current_user_token(), key, len) {
+        raw[copied..copied + chunk.len()].copy_from_slice(chunk);
+        copied += chunk.len();
+    }
+    let uid = current_task().unwrap().inner.inner.borrow().uid;
+    ROOT_INODE
+        .fs()
+        .lock()
+        .add_key(&raw, uid)
+        .map_or(-1, |key_id| key_id as isize)
+}
+
+/// Forget a loaded key, files encrypted with it can no longer be read or written.
+/// Only the user who loaded the key or root may remove it
+pub fn sys_remove_key(id: u32) -> isize {
+    let uid = current_task().unwrap().inner.inner.borrow().uid;
+    ROOT_INODE.fs().lock().remove_key(id, uid).map_or(-1, |_| 0)
+}
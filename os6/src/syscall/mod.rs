@@ -10,18 +10,28 @@
 //! `sys_` then the name of the syscall. You can find functions like this in
 //! submodules, and you should also implement syscalls this way.
 
+const SYSCALL_SETXATTR: usize = 5;
+const SYSCALL_GETXATTR: usize = 8;
+const SYSCALL_LISTXATTR: usize = 11;
+const SYSCALL_REMOVEXATTR: usize = 14;
+const SYSCALL_IOCTL: usize = 29;
+const SYSCALL_MKNODAT: usize = 33;
 const SYSCALL_UNLINKAT: usize = 35;
 const SYSCALL_LINKAT: usize = 37;
+const SYSCALL_FALLOCATE: usize = 47;
 const SYSCALL_OPENAT: usize = 56;
 const SYSCALL_CLOSE: usize = 57;
+const SYSCALL_GETDENTS64: usize = 61;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
 const SYSCALL_FSTAT: usize = 80;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_SET_PRIORITY: usize = 140;
+const SYSCALL_SETUID: usize = 146;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_GETPID: usize = 172;
+const SYSCALL_GETUID: usize = 174;
 const SYSCALL_FORK: usize = 220;
 const SYSCALL_EXEC: usize = 221;
 const SYSCALL_WAITPID: usize = 260;
@@ -30,6 +40,10 @@ const SYSCALL_MUNMAP: usize = 215;
 const SYSCALL_MMAP: usize = 222;
 
 const SYSCALL_TASK_INFO: usize = 410;
+const SYSCALL_SNAPSHOT: usize = 480;
+const SYSCALL_SETQUOTA: usize = 481;
+const SYSCALL_ADD_KEY: usize = 482;
+const SYSCALL_REMOVE_KEY: usize = 483;
 
 mod fs;
 mod process;
@@ -42,15 +56,34 @@ pub use process::TaskInfo;
 use crate::{fs::Stat, task::reocrd_sys_call};
 
 /// handle syscall exception with `syscall_id` and other arguments
-pub fn syscall(syscall_id: usize, args: [usize; 4]) -> isize {
+pub fn syscall(syscall_id: usize, args: [usize; 5]) -> isize {
     // LAB1: You may need to update syscall info here.
 
     reocrd_sys_call(syscall_id);
     match syscall_id {
+        SYSCALL_SETXATTR => sys_setxattr(
+            args[0] as *const u8,
+            args[1] as *const u8,
+            args[2] as *const u8,
+            args[3],
+            args[4] as u32,
+        ),
+        SYSCALL_GETXATTR => sys_getxattr(
+            args[0] as *const u8,
+            args[1] as *const u8,
+            args[2] as *mut u8,
+            args[3],
+        ),
+        SYSCALL_LISTXATTR => sys_listxattr(args[0] as *const u8, args[1] as *mut u8, args[2]),
+        SYSCALL_REMOVEXATTR => sys_removexattr(args[0] as *const u8, args[1] as *const u8),
+        SYSCALL_IOCTL => sys_ioctl(args[0], args[1] as u32, args[2]),
+        SYSCALL_MKNODAT => sys_mknodat(args[1] as *const u8, args[2] as u32, args[3]),
         SYSCALL_UNLINKAT => sys_unlinkat(args[1] as *const u8),
         SYSCALL_LINKAT => sys_linkat(args[1] as *const u8, args[3] as *const u8),
-        SYSCALL_OPENAT => sys_open(args[1] as *const u8, args[2] as u32),
+        SYSCALL_FALLOCATE => sys_fallocate(args[0], args[1] as u32, args[2], args[3]),
+        SYSCALL_OPENAT => sys_open(args[0], args[1] as *const u8, args[2] as u32),
         SYSCALL_CLOSE => sys_close(args[0]),
+        SYSCALL_GETDENTS64 => sys_getdents64(args[0], args[1] as *mut u8, args[2]),
         SYSCALL_READ => sys_read(args[0], args[1] as *const u8, args[2]),
         SYSCALL_GETPID => sys_get_pid(),
         SYSCALL_FORK => sys_fork(),
@@ -60,12 +93,18 @@ pub fn syscall(syscall_id: usize, args: [usize; 4]) -> isize {
         SYSCALL_YIELD => sys_yield(),
         SYSCALL_GET_TIME => sys_get_time(args[0] as *mut TimeVal, args[1]),
         SYSCALL_SET_PRIORITY => sys_set_priority(args[0] as isize),
+        SYSCALL_SETUID => sys_setuid(args[0] as u32),
+        SYSCALL_GETUID => sys_getuid(),
         SYSCALL_TASK_INFO => sys_task_info(args[0] as *mut TaskInfo),
         SYSCALL_MMAP => sys_mmap(args[0], args[1], args[2]),
         SYSCALL_MUNMAP => sys_munmap(args[0], args[1]),
         SYSCALL_EXEC => sys_exec(args[0] as *const u8),
         SYSCALL_SPAWN => sys_spawn(args[0] as *const u8),
         SYSCALL_WAITPID => sys_waitpid(args[0] as isize, args[1] as *mut i32),
+        SYSCALL_SNAPSHOT => sys_snapshot(args[0], args[1] as *const u8),
+        SYSCALL_SETQUOTA => sys_setquota(args[0] as u32, args[1] as u32, args[2] as u32),
+        SYSCALL_ADD_KEY => sys_add_key(args[0] as *const u8, args[1]),
+        SYSCALL_REMOVE_KEY => sys_remove_key(args[0] as u32),
         _ => panic!("Unsupported syscall_id: {}", syscall_id),
     }
 }
@@ -1,4 +1,5 @@
 use alloc::sync::Arc;
+use easy_fs::ROOT_UID;
 
 use crate::config::MAX_SYSCALL_NUM;
 use crate::fs::open_file;
@@ -148,6 +149,22 @@ pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
     }
 }
 
+/// Get the uid of the current process
+pub fn sys_getuid() -> isize {
+    current_task().unwrap().inner.inner.borrow().uid as isize
+}
+
+/// Set the uid of the current process, only root may change it
+pub fn sys_setuid(uid: u32) -> isize {
+    let task = current_task().unwrap();
+    let mut inner = task.inner.inner.borrow_mut();
+    if inner.uid != ROOT_UID && inner.uid != uid {
+        return -1;
+    }
+    inner.uid = uid;
+    0
+}
+
 pub fn sys_set_priority(prio: isize) -> isize {
     if prio <= 1 {
         return -1;
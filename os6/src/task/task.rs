@@ -9,6 +9,7 @@ use crate::sync::UPSafeCell;
 use crate::trap::{trap_handler, TrapContext};
 use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
+use easy_fs::ROOT_UID;
 
 /// Task control block structure
 ///
@@ -56,6 +57,8 @@ pub struct TaskControlBlockInner {
     pub priority: isize,
     // file descriptor table
     pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
+    // owner of the files created by the process, charged for their disk usage
+    pub uid: u32,
 }
 
 /// Simple access to its internal fields
@@ -131,6 +134,7 @@ impl TaskControlBlock {
                     Some(Arc::new(Stdout)),
                     Some(Arc::new(Stdout)),
                 ],
+                uid: ROOT_UID,
             }),
         };
         // prepare TrapContext in user space
@@ -212,6 +216,7 @@ impl TaskControlBlock {
                 pass: 0,
                 priority: 16,
                 fd_table: new_fd_table,
+                uid: parent_inner.uid,
             }),
         });
         // add child
@@ -263,6 +268,7 @@ impl TaskControlBlock {
                     Some(Arc::new(Stdout)),
                     Some(Arc::new(Stdout)),
                 ],
+                uid: parent_inner.uid,
             }),
         });
         // add child
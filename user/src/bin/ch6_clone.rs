@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+use user_lib::{close, ioctl, open, read, unlink, write, OpenFlags, FICLONE};
+
+/// 测试用 ioctl FICLONE 共享文件数据并在写时复制，输出　Test clone OK! 就算正确。
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let src_name = "clone_src\0";
+    let dst_name = "clone_dst\0";
+    let data = [b'f'; 2000];
+    let fd = open(src_name, OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    assert_eq!(write(fd as usize, &data), data.len() as isize);
+    close(fd as usize);
+    let src = open(src_name, OpenFlags::RDONLY);
+    let dst = open(dst_name, OpenFlags::CREATE | OpenFlags::RDWR);
+    assert!(src > 0 && dst > 0);
+    let (src, dst) = (src as usize, dst as usize);
+    // the destination must be writable and the command known
+    assert_eq!(ioctl(src, FICLONE, dst), -1);
+    assert_eq!(ioctl(dst, 0, src), -1);
+    assert_eq!(ioctl(dst, FICLONE, src), 0);
+    // the clone is written without changing the source
+    assert_eq!(write(dst, b"changed"), 7);
+    close(src);
+    close(dst);
+    let mut buf = [0u8; 2048];
+    let fd = open(dst_name, OpenFlags::RDONLY) as usize;
+    assert_eq!(read(fd, &mut buf), data.len() as isize);
+    assert_eq!(&buf[..7], b"changed");
+    assert_eq!(&buf[7..data.len()], &data[7..]);
+    close(fd);
+    let fd = open(src_name, OpenFlags::RDONLY) as usize;
+    assert_eq!(read(fd, &mut buf), data.len() as isize);
+    assert_eq!(&buf[..data.len()], &data[..]);
+    close(fd);
+    assert_eq!(unlink(src_name), 0);
+    assert_eq!(unlink(dst_name), 0);
+    println!("Test clone OK!");
+    0
+}
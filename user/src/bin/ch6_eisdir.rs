@@ -0,0 +1,29 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+use user_lib::{close, open, write, OpenFlags};
+
+/// 测试以写方式打开目录，输出　Test open directory OK! 就算正确。
+
+/// Negated EISDIR of Linux
+const EISDIR: isize = -21;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let root = "/\0";
+    // a directory is neither written, truncated nor created
+    assert_eq!(open(root, OpenFlags::WRONLY), EISDIR);
+    assert_eq!(open(root, OpenFlags::RDWR), EISDIR);
+    assert_eq!(open(root, OpenFlags::TRUNC), EISDIR);
+    assert_eq!(open(root, OpenFlags::CREATE), EISDIR);
+    // it is opened read-only to be listed, but not written through the fd
+    let fd = open(root, OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(write(fd, b"entry"), EISDIR);
+    close(fd);
+    println!("Test open directory OK!");
+    0
+}
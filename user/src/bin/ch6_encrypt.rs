@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+use user_lib::{
+    add_key, close, exit, fork, getuid, ioctl, open, read, remove_key, setuid, unlink, waitpid,
+    write, OpenFlags, EFS_IOC_SET_ENCRYPTION, KEY_SZ,
+};
+
+/// 测试加载密钥、加密文件与移除密钥，输出　Test encryption OK! 就算正确。
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fname = "secret0\0";
+    let key = [0x5au8; KEY_SZ];
+    assert_eq!(add_key(&key[1..]), -1);
+    let key_id = add_key(&key);
+    assert!(key_id >= 0);
+    // loading the same key again gives the same id
+    assert_eq!(add_key(&key), key_id);
+    let fd = open(fname, OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    close(fd as usize);
+    // the file must be opened for writing to be encrypted
+    let fd = open(fname, OpenFlags::RDONLY) as usize;
+    assert_eq!(ioctl(fd, EFS_IOC_SET_ENCRYPTION, key_id as usize), -1);
+    close(fd);
+    let fd = open(fname, OpenFlags::RDWR) as usize;
+    assert_eq!(ioctl(fd, EFS_IOC_SET_ENCRYPTION, key_id as usize), 0);
+    let marker = b"grading data, not for students";
+    assert_eq!(write(fd, marker), marker.len() as isize);
+    close(fd);
+    let mut buf = [0u8; 64];
+    let fd = open(fname, OpenFlags::RDONLY) as usize;
+    assert_eq!(read(fd, &mut buf), marker.len() as isize);
+    assert_eq!(&buf[..marker.len()], marker);
+    close(fd);
+    // only the user who loaded the key or root removes it
+    let pid = fork();
+    if pid == 0 {
+        assert_eq!(setuid(1001), 0);
+        assert_eq!(getuid(), 1001);
+        assert_eq!(remove_key(key_id as u32), -1);
+        exit(0);
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    assert_eq!(getuid(), 0);
+    assert_eq!(remove_key(key_id as u32), 0);
+    assert_eq!(remove_key(key_id as u32), -1);
+    // without the key the file is neither read nor written
+    let fd = open(fname, OpenFlags::RDWR) as usize;
+    assert_eq!(read(fd, &mut buf), 0);
+    assert_eq!(write(fd, b"x"), 0);
+    close(fd);
+    assert_eq!(add_key(&key), key_id);
+    let fd = open(fname, OpenFlags::RDONLY) as usize;
+    assert_eq!(read(fd, &mut buf), marker.len() as isize);
+    assert_eq!(&buf[..marker.len()], marker);
+    close(fd);
+    assert_eq!(remove_key(key_id as u32), 0);
+    assert_eq!(unlink(fname), 0);
+    println!("Test encryption OK!");
+    0
+}
@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+use user_lib::{close, fallocate, fstat, open, read, unlink, OpenFlags, Stat, FALLOC_FL_KEEP_SIZE};
+
+/// 测试用 fallocate 预留文件块，输出　Test fallocate OK! 就算正确。
+
+/// Negated error numbers of Linux
+const EINVAL: isize = -22;
+const EFBIG: isize = -27;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fname = "falloc0\0";
+    let fd = open(fname, OpenFlags::CREATE | OpenFlags::RDWR);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    // unknown modes, empty and negative ranges are invalid, ranges past the end too big
+    assert_eq!(fallocate(fd, 4, 0, 512), EINVAL);
+    assert_eq!(fallocate(fd, 0, 0, 0), EINVAL);
+    assert_eq!(fallocate(fd, 0, usize::MAX, 1), EINVAL);
+    assert_eq!(
+        fallocate(fd, 0, isize::MAX as usize, isize::MAX as usize),
+        EFBIG
+    );
+    assert_eq!(fallocate(fd, 0, 0, 4 * 512), 0);
+    let stat = Stat::new();
+    assert_eq!(fstat(fd, &stat), 0);
+    assert_eq!(stat.size, 4 * 512);
+    assert!(stat.blocks >= 4);
+    // the size is kept, the blocks are reserved anyway
+    assert_eq!(fallocate(fd, FALLOC_FL_KEEP_SIZE, 4 * 512, 4 * 512), 0);
+    assert_eq!(fstat(fd, &stat), 0);
+    assert_eq!(stat.size, 4 * 512);
+    assert!(stat.blocks >= 8);
+    // the reserved range reads as zeros
+    let mut buf = [1u8; 4 * 512];
+    assert_eq!(read(fd, &mut buf), 4 * 512);
+    assert!(buf.iter().all(|byte| *byte == 0));
+    close(fd);
+    // the fd must be writable
+    let fd = open(fname, OpenFlags::RDONLY) as usize;
+    assert_eq!(fallocate(fd, 0, 0, 512), -1);
+    close(fd);
+    assert_eq!(unlink(fname), 0);
+    println!("Test fallocate OK!");
+    0
+}
@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+use user_lib::{close, fstat, mknod, open, read, unlink, write, OpenFlags, Stat, StatMode};
+
+/// 测试命名管道的零长度读写，输出　Test fifo zero-length OK! 就算正确。
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fname = "fifo0\0";
+    assert_eq!(mknod(fname, StatMode::FIFO, 0), 0);
+    // both ends in one open, so that it does not wait for another process
+    let fd = open(fname, OpenFlags::RDWR);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    let stat = Stat::new();
+    assert_eq!(fstat(fd, &stat), 0);
+    assert_eq!(stat.mode, StatMode::FIFO);
+    let mut buf = [0u8; 4];
+    // nothing was written yet, yet a zero-length read returns at once
+    assert_eq!(read(fd, &mut buf[..0]), 0);
+    assert_eq!(write(fd, &buf[..0]), 0);
+    assert_eq!(write(fd, b"ok"), 2);
+    assert_eq!(read(fd, &mut buf[..0]), 0);
+    assert_eq!(read(fd, &mut buf), 2);
+    assert_eq!(&buf[..2], b"ok");
+    // the open FIFO outlives its last link
+    assert_eq!(unlink(fname), 0);
+    assert_eq!(fstat(fd, &stat), 0);
+    assert_eq!(stat.nlink, 0);
+    close(fd);
+    println!("Test fifo zero-length OK!");
+    0
+}
@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+use user_lib::{close, getdents64, mknod, open, unlink, OpenFlags, StatMode, DT_FIFO, DT_REG};
+
+/// 测试用 getdents64 列出目录，输出　Test getdents64 OK! 就算正确。
+
+/// Find the d_type of `name` among the linux_dirent64 records of the directory `fd`
+fn find_entry(fd: usize, name: &[u8]) -> Option<u8> {
+    let mut buf = [0u8; 256];
+    let mut found = None;
+    loop {
+        let len = getdents64(fd, &mut buf);
+        assert!(len >= 0);
+        if len == 0 {
+            return found;
+        }
+        let mut offset = 0;
+        while offset < len as usize {
+            // d_ino, d_off, d_reclen, d_type and the name ending with '\0'
+            let reclen = u16::from_ne_bytes([buf[offset + 16], buf[offset + 17]]) as usize;
+            assert!(reclen % 8 == 0 && offset + reclen <= len as usize);
+            let record = &buf[offset..offset + reclen];
+            let name_len = record[19..].iter().position(|byte| *byte == 0).unwrap();
+            if &record[19..19 + name_len] == name {
+                found = Some(record[18]);
+            }
+            offset += reclen;
+        }
+    }
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fd = open("dents_file\0", OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    close(fd as usize);
+    assert_eq!(mknod("dents_fifo\0", StatMode::FIFO, 0), 0);
+    let dir = open("/\0", OpenFlags::RDONLY);
+    assert!(dir > 0);
+    let dir = dir as usize;
+    // a buffer too small for a single record fails
+    assert_eq!(getdents64(dir, &mut [0u8; 8]), -1);
+    assert_eq!(find_entry(dir, b"dents_file"), Some(DT_REG));
+    close(dir);
+    assert_eq!(unlink("dents_file\0"), 0);
+    let dir = open("/\0", OpenFlags::RDONLY) as usize;
+    assert_eq!(find_entry(dir, b"dents_fifo"), Some(DT_FIFO));
+    close(dir);
+    let dir = open("/\0", OpenFlags::RDONLY) as usize;
+    assert_eq!(find_entry(dir, b"dents_file"), None);
+    close(dir);
+    assert_eq!(unlink("dents_fifo\0"), 0);
+    // a regular file is not listed
+    let fd = open("ch6_getdents\0", OpenFlags::RDONLY) as usize;
+    assert_eq!(getdents64(fd, &mut [0u8; 256]), -1);
+    close(fd);
+    println!("Test getdents64 OK!");
+    0
+}
@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+use user_lib::{
+    close, exit, fork, getuid, open, setquota, setuid, unlink, waitpid, write, OpenFlags,
+};
+
+/// 测试用户身份与磁盘配额，输出　Test setuid and quota OK! 就算正确。
+
+const UID: u32 = 2000;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    assert_eq!(getuid(), 0);
+    // at most 2 blocks and 2 inodes for the user
+    assert_eq!(setquota(UID, 2, 2), 0);
+    // uids beyond the quota table have no quota
+    assert_eq!(setquota(u32::MAX, 2, 2), -1);
+    let pid = fork();
+    if pid == 0 {
+        assert_eq!(setuid(UID), 0);
+        assert_eq!(getuid(), UID as isize);
+        // only root changes its uid or sets quotas
+        assert_eq!(setuid(0), -1);
+        assert_eq!(setuid(UID), 0);
+        assert_eq!(setquota(UID, 0, 0), -1);
+        let fd = open("quota0\0", OpenFlags::CREATE | OpenFlags::WRONLY);
+        assert!(fd > 0);
+        let fd = fd as usize;
+        let data = [b'q'; 3 * 512];
+        assert!(write(fd, &data) < data.len() as isize);
+        close(fd);
+        let fd = open("quota1\0", OpenFlags::CREATE | OpenFlags::WRONLY);
+        assert!(fd > 0);
+        close(fd as usize);
+        assert_eq!(open("quota2\0", OpenFlags::CREATE | OpenFlags::WRONLY), -1);
+        exit(0);
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    // removing the files gives their inodes and blocks back
+    assert_eq!(unlink("quota0\0"), 0);
+    assert_eq!(unlink("quota1\0"), 0);
+    let pid = fork();
+    if pid == 0 {
+        assert_eq!(setuid(UID), 0);
+        let fd = open("quota0\0", OpenFlags::CREATE | OpenFlags::WRONLY);
+        assert!(fd > 0);
+        assert_eq!(write(fd as usize, &[b'q'; 2 * 512]), 2 * 512);
+        close(fd as usize);
+        exit(0);
+    }
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    assert_eq!(unlink("quota0\0"), 0);
+    assert_eq!(setquota(UID, 0, 0), 0);
+    println!("Test setuid and quota OK!");
+    0
+}
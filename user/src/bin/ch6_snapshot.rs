@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+use user_lib::{
+    close, exit, fork, getdents64, open, openat, read, setuid, snapshot, unlink, waitpid, write,
+    OpenFlags, SNAPSHOT_CREATE, SNAPSHOT_DELETE, SNAPSHOT_LIST, SNAPSHOT_MOUNT, SNAPSHOT_REVERT,
+};
+
+/// 测试文件系统快照的创建、挂载、列出、回滚与删除，输出　Test snapshot OK! 就算正确。
+
+/// Negated error numbers of Linux
+const EPERM: isize = -1;
+const ENOENT: isize = -2;
+const EBUSY: isize = -16;
+
+/// Replace the content of `fname` with `data`
+fn write_file(fname: &str, data: &[u8]) {
+    let fd = open(fname, OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    assert_eq!(write(fd as usize, data), data.len() as isize);
+    close(fd as usize);
+}
+
+/// Read the first bytes of the file `fd` and close it
+fn read_fd(fd: isize, buf: &mut [u8]) -> isize {
+    assert!(fd > 0);
+    let len = read(fd as usize, buf);
+    close(fd as usize);
+    len
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fname = "snap0\0";
+    let name = "ch6_snapshot\0";
+    write_file(fname, b"before");
+    assert_eq!(snapshot(SNAPSHOT_CREATE, name), 0);
+    assert_eq!(snapshot(SNAPSHOT_CREATE, name), -1);
+    write_file(fname, b"after!");
+    write_file("snap1\0", b"new");
+    // the snapshot is mounted read-only and keeps the files as they were
+    let root = snapshot(SNAPSHOT_MOUNT, name);
+    assert!(root > 0);
+    let root = root as usize;
+    let mut buf = [0u8; 16];
+    assert_eq!(read_fd(openat(root, fname, OpenFlags::RDONLY), &mut buf), 6);
+    assert_eq!(&buf[..6], b"before");
+    assert!(openat(root, fname, OpenFlags::WRONLY) < 0);
+    assert!(openat(root, "snap1\0", OpenFlags::RDONLY) < 0);
+    assert!(openat(root, "snap2\0", OpenFlags::CREATE | OpenFlags::WRONLY) < 0);
+    assert_eq!(snapshot(SNAPSHOT_MOUNT, "missing\0"), ENOENT);
+    // the directory of snapshots lists it
+    let list = snapshot(SNAPSHOT_LIST, "\0");
+    assert!(list > 0);
+    let list = list as usize;
+    let mut dents = [0u8; 256];
+    let len = getdents64(list, &mut dents);
+    assert!(len > 0);
+    let len = len as usize;
+    assert!(dents[..len]
+        .windows(name.len())
+        .any(|window| window == name.as_bytes()));
+    // files in use keep the live filesystem from being reverted
+    assert_eq!(snapshot(SNAPSHOT_REVERT, name), EBUSY);
+    close(root);
+    close(list);
+    // only root manages snapshots
+    let pid = fork();
+    if pid == 0 {
+        assert_eq!(setuid(1000), 0);
+        assert_eq!(snapshot(SNAPSHOT_LIST, "\0"), EPERM);
+        assert_eq!(snapshot(SNAPSHOT_DELETE, name), EPERM);
+        exit(0);
+    }
+    let mut exit_code: i32 = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    assert_eq!(snapshot(SNAPSHOT_REVERT, name), 0);
+    // files opened after the revert are those of the snapshot
+    assert_eq!(read_fd(open(fname, OpenFlags::RDONLY), &mut buf), 6);
+    assert_eq!(&buf[..6], b"before");
+    assert!(open("snap1\0", OpenFlags::RDONLY) < 0);
+    assert_eq!(snapshot(SNAPSHOT_DELETE, name), 0);
+    assert_eq!(snapshot(SNAPSHOT_DELETE, name), -1);
+    assert_eq!(snapshot(SNAPSHOT_MOUNT, name), ENOENT);
+    assert_eq!(unlink(fname), 0);
+    println!("Test snapshot OK!");
+    0
+}
@@ -27,6 +27,15 @@ static TESTS: &[&str] = &[
     "ch6_file1\0",
     "ch6_file2\0",
     "ch6_file3\0",
+    "ch6_fifo0\0",
+    "ch6_eisdir\0",
+    "ch6_xattr\0",
+    "ch6_clone\0",
+    "ch6_encrypt\0",
+    "ch6_fallocate\0",
+    "ch6_getdents\0",
+    "ch6_quota\0",
+    "ch6_snapshot\0",
 ];
 
 use user_lib::{spawn, waitpid};
@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+use user_lib::{
+    close, getxattr, listxattr, open, removexattr, setxattr, unlink, OpenFlags, XattrFlags,
+};
+
+/// 测试扩展属性的设置、读取、列出与删除，输出　Test xattr OK! 就算正确。
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let fname = "xattr0\0";
+    let fd = open(fname, OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    close(fd as usize);
+    let mut value = [0u8; 16];
+    assert_eq!(getxattr(fname, "user.checksum\0", &mut value), -1);
+    assert_eq!(
+        setxattr(fname, "user.checksum\0", b"1234", XattrFlags::empty()),
+        0
+    );
+    assert_eq!(
+        setxattr(fname, "user.label\0", b"fixture", XattrFlags::CREATE),
+        0
+    );
+    // CREATE fails on an existing attribute and REPLACE on a missing one
+    assert_eq!(
+        setxattr(fname, "user.label\0", b"again", XattrFlags::CREATE),
+        -1
+    );
+    assert_eq!(
+        setxattr(fname, "user.other\0", b"none", XattrFlags::REPLACE),
+        -1
+    );
+    assert_eq!(
+        setxattr(fname, "user.checksum\0", b"5678", XattrFlags::REPLACE),
+        0
+    );
+    // a zero-length buffer queries the length, a short one fails
+    assert_eq!(getxattr(fname, "user.checksum\0", &mut value[..0]), 4);
+    assert_eq!(getxattr(fname, "user.checksum\0", &mut value[..3]), -1);
+    assert_eq!(getxattr(fname, "user.checksum\0", &mut value), 4);
+    assert_eq!(&value[..4], b"5678");
+    // names are separated by '\0'
+    let mut list = [0u8; 64];
+    let expected = b"user.checksum\0user.label\0";
+    assert_eq!(listxattr(fname, &mut list[..0]), expected.len() as isize);
+    assert_eq!(listxattr(fname, &mut list), expected.len() as isize);
+    assert_eq!(&list[..expected.len()], expected);
+    assert_eq!(removexattr(fname, "user.checksum\0"), 0);
+    assert_eq!(removexattr(fname, "user.checksum\0"), -1);
+    assert_eq!(listxattr(fname, &mut list), 11);
+    assert_eq!(&list[..11], b"user.label\0");
+    assert_eq!(getxattr("missing\0", "user.label\0", &mut value), -1);
+    assert_eq!(unlink(fname), 0);
+    println!("Test xattr OK!");
+    0
+}
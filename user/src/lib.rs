@@ -142,8 +142,14 @@ pub struct Stat {
     pub mode: StatMode,
     /// number of hard links
     pub nlink: u32,
+    /// size in bytes
+    pub size: u64,
+    /// number of 512-byte blocks allocated
+    pub blocks: u64,
+    /// device number of a device, major in bits 8..20 and minor in bits 0..8 and 20..32
+    pub rdev: u64,
     /// unused pad
-    pad: [u64; 7],
+    pad: [u64; 4],
 }
 
 impl Stat {
@@ -153,7 +159,10 @@ impl Stat {
             ino: 0,
             mode: StatMode::NULL,
             nlink: 0,
-            pad: [0; 7],
+            size: 0,
+            blocks: 0,
+            rdev: 0,
+            pad: [0; 4],
         }
     }
 }
@@ -167,19 +176,64 @@ impl Default for Stat {
 bitflags! {
     pub struct StatMode: u32 {
         const NULL  = 0;
+        /// named pipe
+        const FIFO  = 0o010000;
+        /// character device
+        const CHR   = 0o020000;
         /// directory
         const DIR   = 0o040000;
+        /// block device
+        const BLK   = 0o060000;
         /// ordinary regular file
         const FILE  = 0o100000;
     }
 }
 
-const AT_FDCWD: isize = -100;
+bitflags! {
+    pub struct XattrFlags: u32 {
+        /// fail if the attribute already exists
+        const CREATE  = 1;
+        /// fail if the attribute does not exist
+        const REPLACE = 2;
+    }
+}
+
+/// ioctl sharing the data blocks of the source fd `arg` with the fd
+pub const FICLONE: u32 = 0x40049409;
+/// ioctl encrypting the still empty file of the fd with the loaded key `arg`
+pub const EFS_IOC_SET_ENCRYPTION: u32 = 0x40046613;
+/// fallocate mode reserving blocks without growing the file
+pub const FALLOC_FL_KEEP_SIZE: u32 = 1;
+
+/// d_type of getdents64 records
+pub const DT_FIFO: u8 = 1;
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_BLK: u8 = 6;
+pub const DT_REG: u8 = 8;
+
+/// Operations of snapshot
+pub const SNAPSHOT_CREATE: usize = 0;
+pub const SNAPSHOT_DELETE: usize = 1;
+pub const SNAPSHOT_REVERT: usize = 2;
+/// returns a read-only fd of the root directory of the snapshot
+pub const SNAPSHOT_MOUNT: usize = 3;
+/// returns a read-only fd of the directory of snapshots
+pub const SNAPSHOT_LIST: usize = 4;
+
+/// Size of an encryption key in bytes
+pub const KEY_SZ: usize = 64;
+
+pub const AT_FDCWD: isize = -100;
 
 pub fn open(path: &str, flags: OpenFlags) -> isize {
     sys_openat(AT_FDCWD as usize, path, flags.bits, OpenFlags::RDWR.bits)
 }
 
+pub fn openat(dirfd: usize, path: &str, flags: OpenFlags) -> isize {
+    sys_openat(dirfd, path, flags.bits, OpenFlags::RDWR.bits)
+}
+
 pub fn close(fd: usize) -> isize {
     if fd == STDOUT {
         console::flush();
@@ -203,10 +257,58 @@ pub fn unlink(path: &str) -> isize {
     sys_unlinkat(AT_FDCWD as usize, path, 0)
 }
 
+pub fn mknod(path: &str, mode: StatMode, dev: usize) -> isize {
+    sys_mknodat(AT_FDCWD as usize, path, mode.bits, dev)
+}
+
 pub fn fstat(fd: usize, st: &Stat) -> isize {
     sys_fstat(fd, st)
 }
 
+pub fn setxattr(path: &str, name: &str, value: &[u8], flags: XattrFlags) -> isize {
+    sys_setxattr(path, name, value, flags.bits)
+}
+
+pub fn getxattr(path: &str, name: &str, value: &mut [u8]) -> isize {
+    sys_getxattr(path, name, value)
+}
+
+pub fn listxattr(path: &str, list: &mut [u8]) -> isize {
+    sys_listxattr(path, list)
+}
+
+pub fn removexattr(path: &str, name: &str) -> isize {
+    sys_removexattr(path, name)
+}
+
+pub fn ioctl(fd: usize, cmd: u32, arg: usize) -> isize {
+    sys_ioctl(fd, cmd, arg)
+}
+
+pub fn fallocate(fd: usize, mode: u32, offset: usize, len: usize) -> isize {
+    sys_fallocate(fd, mode, offset, len)
+}
+
+pub fn getdents64(fd: usize, buf: &mut [u8]) -> isize {
+    sys_getdents64(fd, buf)
+}
+
+pub fn snapshot(op: usize, name: &str) -> isize {
+    sys_snapshot(op, name)
+}
+
+pub fn setquota(uid: u32, block_limit: u32, inode_limit: u32) -> isize {
+    sys_setquota(uid, block_limit, inode_limit)
+}
+
+pub fn add_key(key: &[u8]) -> isize {
+    sys_add_key(key)
+}
+
+pub fn remove_key(key_id: u32) -> isize {
+    sys_remove_key(key_id)
+}
+
 pub fn mail_read(buf: &mut [u8]) -> isize {
     sys_mail_read(buf)
 }
@@ -236,6 +338,14 @@ pub fn getpid() -> isize {
     sys_getpid()
 }
 
+pub fn setuid(uid: u32) -> isize {
+    sys_setuid(uid)
+}
+
+pub fn getuid() -> isize {
+    sys_getuid()
+}
+
 pub fn fork() -> isize {
     sys_fork()
 }
@@ -2,10 +2,18 @@ use crate::TaskInfo;
 
 use super::{Stat, TimeVal};
 
+pub const SYSCALL_SETXATTR: usize = 5;
+pub const SYSCALL_GETXATTR: usize = 8;
+pub const SYSCALL_LISTXATTR: usize = 11;
+pub const SYSCALL_REMOVEXATTR: usize = 14;
+pub const SYSCALL_IOCTL: usize = 29;
+pub const SYSCALL_FALLOCATE: usize = 47;
 pub const SYSCALL_OPENAT: usize = 56;
 pub const SYSCALL_CLOSE: usize = 57;
+pub const SYSCALL_GETDENTS64: usize = 61;
 pub const SYSCALL_READ: usize = 63;
 pub const SYSCALL_WRITE: usize = 64;
+pub const SYSCALL_MKNODAT: usize = 33;
 pub const SYSCALL_UNLINKAT: usize = 35;
 pub const SYSCALL_LINKAT: usize = 37;
 pub const SYSCALL_FSTAT: usize = 80;
@@ -14,6 +22,8 @@ pub const SYSCALL_SLEEP: usize = 101;
 pub const SYSCALL_YIELD: usize = 124;
 pub const SYSCALL_GETTIMEOFDAY: usize = 169;
 pub const SYSCALL_GETPID: usize = 172;
+pub const SYSCALL_SETUID: usize = 146;
+pub const SYSCALL_GETUID: usize = 174;
 pub const SYSCALL_GETTID: usize = 178;
 pub const SYSCALL_FORK: usize = 220;
 pub const SYSCALL_EXEC: usize = 221;
@@ -39,6 +49,10 @@ pub const SYSCALL_SEMAPHORE_DOWN: usize = 470;
 pub const SYSCALL_CONDVAR_CREATE: usize = 471;
 pub const SYSCALL_CONDVAR_SIGNAL: usize = 472;
 pub const SYSCALL_CONDVAR_WAIT: usize = 473;
+pub const SYSCALL_SNAPSHOT: usize = 480;
+pub const SYSCALL_SETQUOTA: usize = 481;
+pub const SYSCALL_ADD_KEY: usize = 482;
+pub const SYSCALL_REMOVE_KEY: usize = 483;
 
 pub fn syscall(id: usize, args: [usize; 3]) -> isize {
     let mut ret: isize;
@@ -123,10 +137,78 @@ pub fn sys_unlinkat(dirfd: usize, path: &str, flags: usize) -> isize {
     syscall(SYSCALL_UNLINKAT, [dirfd, path.as_ptr() as usize, flags])
 }
 
+pub fn sys_mknodat(dirfd: usize, path: &str, mode: u32, dev: usize) -> isize {
+    syscall6(
+        SYSCALL_MKNODAT,
+        [dirfd, path.as_ptr() as usize, mode as usize, dev, 0, 0],
+    )
+}
+
 pub fn sys_fstat(fd: usize, st: &Stat) -> isize {
     syscall(SYSCALL_FSTAT, [fd, st as *const _ as usize, 0])
 }
 
+pub fn sys_setxattr(path: &str, name: &str, value: &[u8], flags: u32) -> isize {
+    syscall6(
+        SYSCALL_SETXATTR,
+        [
+            path.as_ptr() as usize,
+            name.as_ptr() as usize,
+            value.as_ptr() as usize,
+            value.len(),
+            flags as usize,
+            0,
+        ],
+    )
+}
+
+pub fn sys_getxattr(path: &str, name: &str, value: &mut [u8]) -> isize {
+    syscall6(
+        SYSCALL_GETXATTR,
+        [
+            path.as_ptr() as usize,
+            name.as_ptr() as usize,
+            value.as_mut_ptr() as usize,
+            value.len(),
+            0,
+            0,
+        ],
+    )
+}
+
+pub fn sys_listxattr(path: &str, list: &mut [u8]) -> isize {
+    syscall(
+        SYSCALL_LISTXATTR,
+        [
+            path.as_ptr() as usize,
+            list.as_mut_ptr() as usize,
+            list.len(),
+        ],
+    )
+}
+
+pub fn sys_removexattr(path: &str, name: &str) -> isize {
+    syscall(
+        SYSCALL_REMOVEXATTR,
+        [path.as_ptr() as usize, name.as_ptr() as usize, 0],
+    )
+}
+
+pub fn sys_ioctl(fd: usize, cmd: u32, arg: usize) -> isize {
+    syscall(SYSCALL_IOCTL, [fd, cmd as usize, arg])
+}
+
+pub fn sys_fallocate(fd: usize, mode: u32, offset: usize, len: usize) -> isize {
+    syscall6(SYSCALL_FALLOCATE, [fd, mode as usize, offset, len, 0, 0])
+}
+
+pub fn sys_getdents64(fd: usize, buffer: &mut [u8]) -> isize {
+    syscall(
+        SYSCALL_GETDENTS64,
+        [fd, buffer.as_mut_ptr() as usize, buffer.len()],
+    )
+}
+
 pub fn sys_mail_read(buffer: &mut [u8]) -> isize {
     syscall(
         SYSCALL_MAIL_READ,
@@ -162,6 +244,14 @@ pub fn sys_getpid() -> isize {
     syscall(SYSCALL_GETPID, [0, 0, 0])
 }
 
+pub fn sys_setuid(uid: u32) -> isize {
+    syscall(SYSCALL_SETUID, [uid as usize, 0, 0])
+}
+
+pub fn sys_getuid() -> isize {
+    syscall(SYSCALL_GETUID, [0, 0, 0])
+}
+
 pub fn sys_fork() -> isize {
     syscall(SYSCALL_FORK, [0, 0, 0])
 }
@@ -256,3 +346,22 @@ pub fn sys_condvar_signal(condvar_id: usize) -> isize {
 pub fn sys_condvar_wait(condvar_id: usize, mutex_id: usize) -> isize {
     syscall(SYSCALL_CONDVAR_WAIT, [condvar_id, mutex_id, 0])
 }
+
+pub fn sys_snapshot(op: usize, name: &str) -> isize {
+    syscall(SYSCALL_SNAPSHOT, [op, name.as_ptr() as usize, 0])
+}
+
+pub fn sys_setquota(uid: u32, block_limit: u32, inode_limit: u32) -> isize {
+    syscall(
+        SYSCALL_SETQUOTA,
+        [uid as usize, block_limit as usize, inode_limit as usize],
+    )
+}
+
+pub fn sys_add_key(key: &[u8]) -> isize {
+    syscall(SYSCALL_ADD_KEY, [key.as_ptr() as usize, key.len(), 0])
+}
+
+pub fn sys_remove_key(key_id: u32) -> isize {
+    syscall(SYSCALL_REMOVE_KEY, [key_id as usize, 0, 0])
+}